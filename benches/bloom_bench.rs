@@ -1,5 +1,5 @@
 use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, SamplingMode};
-use lsm_tree::bloom::{Bloom, RocksDBLocalBloom, SpeedDbDynamicBloom};
+use lsm_tree::bloom::{BinaryFuseFilter, Bloom, BloomParams, RocksDBLocalBloom, SpeedDbDynamicBloom};
 use fastbloom::BloomFilter;
 use rand::{rngs::StdRng, Rng, SeedableRng};
 use xxhash_rust::xxh3::xxh3_128;
@@ -42,6 +42,13 @@ fn bench_bloom_filters(c: &mut Criterion) {
         let rocks_bloom = RocksDBLocalBloom::new(total_bits as u32, 6);
         let fast_bloom = BloomFilter::with_num_bits(total_bits)
             .expected_items(size);
+        // Fingerprints for the rate a Bloom filter gets from the same bits
+        let bloom_rate = BloomParams::from_bits_per_key(bits_per_key as f64).false_positive_rate();
+        let fuse_bits = BinaryFuseFilter::fingerprint_bits_for(bloom_rate);
+        let keys: Vec<i64> = items
+            .iter()
+            .map(|item| item.parse::<u32>().unwrap() as i64)
+            .collect();
 
         // Benchmark insertions
         group.bench_function(BenchmarkId::new("speeddb_insert", size), |b| {
@@ -78,7 +85,13 @@ fn bench_bloom_filters(c: &mut Criterion) {
             })
         });
 
+        // The fuse filter is static, so it's built from the whole key set
+        group.bench_function(BenchmarkId::new("fuse_build", size), |b| {
+            b.iter(|| BinaryFuseFilter::build(&keys, fuse_bits))
+        });
+
         // Prepare populated filters for lookups
+        let fuse = BinaryFuseFilter::build(&keys, fuse_bits);
         let mut populated_fast_bloom = fast_bloom.clone();
         for item in &items {
             populated_fast_bloom.insert(item);
@@ -114,6 +127,14 @@ fn bench_bloom_filters(c: &mut Criterion) {
             })
         });
 
+        group.bench_function(BenchmarkId::new("fuse_lookup", size), |b| {
+            b.iter(|| {
+                for item in &lookup_items {
+                    let _ = fuse.may_contain(item.parse::<u32>().unwrap() as i64);
+                }
+            })
+        });
+
         group.bench_function(BenchmarkId::new("fastbloom_lookup", size), |b| {
             b.iter(|| {
                 for item in &lookup_items {
@@ -165,6 +186,18 @@ fn bench_bloom_filters(c: &mut Criterion) {
             })
         });
 
+        group.bench_function(BenchmarkId::new("fuse_fp", size), |b| {
+            b.iter(|| {
+                let mut fps = 0;
+                for item in &fp_items {
+                    if fuse.may_contain(item.parse::<u32>().unwrap() as i64) {
+                        fps += 1;
+                    }
+                }
+                fps
+            })
+        });
+
         group.bench_function(BenchmarkId::new("fastbloom_fp", size), |b| {
             b.iter(|| {
                 let mut fps = 0;
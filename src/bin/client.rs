@@ -1,5 +1,8 @@
+use std::fs::File;
 use std::io::{self, BufRead, BufReader, Read, Write};
 use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 fn send_command(stream: &mut TcpStream, command: &str) -> io::Result<()> {
     // Send the command
@@ -38,33 +41,90 @@ fn receive_response(stream: &mut TcpStream) -> io::Result<String> {
     }
 }
 
-fn main() -> io::Result<()> {
-    let mut stream = TcpStream::connect("127.0.0.1:8080")?;
-    println!("Connected to server at 127.0.0.1:8080");
+const USAGE: &str = "\
+Usage: client [OPTIONS]
+
+Options:
+  -H <host>  Server host (default: 127.0.0.1)
+  -p <port>  Port number (default: 8080)
+  -f <file>  Send the commands in a workload file, one per line, instead of
+             reading them from the terminal, and report their latencies
+  -q         Quiet mode: no prompt or connection messages, and only the
+             summary of a replay
+  -h         Print this help message";
+
+/// What the command line asks the client to do.
+struct Options {
+    host: String,
+    port: u16,
+    /// Workload file to replay; `None` reads commands interactively
+    script: Option<PathBuf>,
+    quiet: bool,
+    help: bool,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options {
+        host: "127.0.0.1".to_string(),
+        port: lsm_tree::DEFAULT_PORT,
+        script: None,
+        quiet: false,
+        help: false,
+    };
+
+    while let Some(flag) = args.next() {
+        let mut value = || args.next().ok_or(format!("Missing value for {}", flag));
+        match flag.as_str() {
+            "-f" => options.script = Some(PathBuf::from(value()?)),
+            "-h" => options.help = true,
+            "-H" => options.host = value()?,
+            "-p" => {
+                options.port = match value()?.parse() {
+                    Ok(port) if port > 0 => port,
+                    _ => return Err("Port must be a number between 1 and 65535".to_string()),
+                }
+            }
+            "-q" => options.quiet = true,
+            other => return Err(format!("Unknown option: {}", other)),
+        }
+    }
+    Ok(options)
+}
 
+/// Sends `command` and waits for its response.
+fn round_trip(stream: &mut TcpStream, command: &str) -> io::Result<String> {
+    send_command(stream, command)?;
+    receive_response(stream)
+}
+
+fn interactive(stream: &mut TcpStream, quiet: bool) -> io::Result<()> {
     let stdin = io::stdin();
     let mut reader = BufReader::new(stdin);
     let mut buffer = String::new();
 
     loop {
-        print!("db_client > ");
-        io::stdout().flush()?;
+        if !quiet {
+            print!("db_client > ");
+            io::stdout().flush()?;
+        }
 
         buffer.clear();
-        reader.read_line(&mut buffer)?;
+        if reader.read_line(&mut buffer)? == 0 {
+            break;
+        }
 
         let command = buffer.trim();
         if command.is_empty() {
             continue;
         }
 
-        send_command(&mut stream, command)?;
-
-        match receive_response(&mut stream) {
+        match round_trip(stream, command) {
             Ok(response) => {
                 println!("{}", response);
                 if response.contains("Shutting down the server") {
-                    println!("Server is shutting down. Exiting client...");
+                    if !quiet {
+                        println!("Server is shutting down. Exiting client...");
+                    }
                     break;
                 }
             }
@@ -81,3 +141,118 @@ fn main() -> io::Result<()> {
 
     Ok(())
 }
+
+/// Latencies of the commands sent by a replay.
+#[derive(Default)]
+struct Latencies {
+    samples: Vec<Duration>,
+    elapsed: Duration,
+}
+
+impl Latencies {
+    fn percentile(sorted: &[Duration], p: f64) -> Duration {
+        let rank = ((sorted.len() as f64 * p).ceil() as usize).clamp(1, sorted.len());
+        sorted[rank - 1]
+    }
+}
+
+impl std::fmt::Display for Latencies {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let count = self.samples.len();
+        let seconds = self.elapsed.as_secs_f64();
+        write!(f, "Sent {} commands in {:.3} s", count, seconds)?;
+        if count == 0 {
+            return Ok(());
+        }
+
+        let mut sorted = self.samples.clone();
+        sorted.sort();
+        let mean = sorted.iter().sum::<Duration>() / count as u32;
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        write!(
+            f,
+            " ({:.0} commands/s)\nLatency: mean {:.3} ms, p50 {:.3} ms, p99 {:.3} ms, max {:.3} ms",
+            count as f64 / seconds.max(f64::EPSILON),
+            ms(mean),
+            ms(Self::percentile(&sorted, 0.5)),
+            ms(Self::percentile(&sorted, 0.99)),
+            ms(sorted[count - 1]),
+        )
+    }
+}
+
+/// Sends every command in `path` in order, timing each round trip.
+///
+/// Stops early at a `q` or when the server goes away; the summary covers the
+/// commands sent until then.
+fn replay(stream: &mut TcpStream, path: &Path, quiet: bool) -> io::Result<Latencies> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut latencies = Latencies::default();
+    let start = Instant::now();
+
+    for line in reader.lines() {
+        let line = line?;
+        let command = line.trim();
+        if command.is_empty() {
+            continue;
+        }
+
+        let sent = Instant::now();
+        let response = match round_trip(stream, command) {
+            Ok(response) => response,
+            Err(e) => {
+                eprintln!("Error receiving response to {}: {}", command, e);
+                break;
+            }
+        };
+        let latency = sent.elapsed();
+        latencies.samples.push(latency);
+        if !quiet {
+            println!(
+                "{} -> {} [{:.3} ms]",
+                command,
+                response,
+                latency.as_secs_f64() * 1000.0
+            );
+        }
+
+        if command == "q" || response.contains("Shutting down the server") {
+            break;
+        }
+    }
+
+    latencies.elapsed = start.elapsed();
+    Ok(latencies)
+}
+
+fn main() -> io::Result<()> {
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            std::process::exit(1);
+        }
+    };
+    if options.help {
+        println!("{}", USAGE);
+        return Ok(());
+    }
+
+    let addr = format!("{}:{}", options.host, options.port);
+    let mut stream = TcpStream::connect(&addr)?;
+    // Commands are small and wait for their response, so don't let them sit
+    // in the send buffer
+    stream.set_nodelay(true)?;
+    if !options.quiet {
+        println!("Connected to server at {}", addr);
+    }
+
+    match &options.script {
+        Some(path) => {
+            let latencies = replay(&mut stream, path, options.quiet)?;
+            println!("{}", latencies);
+            Ok(())
+        }
+        None => interactive(&mut stream, options.quiet),
+    }
+}
@@ -1,12 +1,22 @@
+use lsm_tree::bloom::{FilterAllocation, FilterKind, RangeFilterParams};
 use lsm_tree::command::Command;
-use lsm_tree::lsm_tree::LSMTree;
+use lsm_tree::compaction;
+use lsm_tree::config::LSMConfig;
+use lsm_tree::lsm_tree::{FilterCounts, LSMTree};
 use std::io;
 use std::io::BufRead;
-use std::io::{BufReader, Write};
+use std::io::{BufReader, ErrorKind, Write};
 use std::net::TcpListener;
 use std::net::TcpStream;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, RwLock};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// How often blocked reads wake up to check whether the server is stopping
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
 
 fn send_response(stream: &mut TcpStream, response: &str) -> io::Result<()> {
     // Send response followed by END_OF_MESSAGE marker
@@ -16,45 +26,61 @@ fn send_response(stream: &mut TcpStream, response: &str) -> io::Result<()> {
     Ok(())
 }
 
-fn handle_client(
-    mut stream: TcpStream,
-    termination_flag: Arc<AtomicBool>,
-    lsm_tree: Arc<RwLock<LSMTree>>,
-) {
+fn handle_client(mut stream: TcpStream, termination_flag: Arc<AtomicBool>, lsm_tree: Arc<LSMTree>) {
+    // Responses are written in pieces and clients wait for each one
+    if let Err(e) = stream.set_nodelay(true) {
+        eprintln!("Failed to disable Nagle's algorithm: {}", e);
+    }
+    // Idle clients mustn't keep the server from shutting down
+    if let Err(e) = stream.set_read_timeout(Some(POLL_INTERVAL)) {
+        eprintln!("Failed to set the read timeout: {}", e);
+    }
     let mut reader = BufReader::new(stream.try_clone().unwrap());
     println!("Started handling client");
 
+    // A line cut short by the timeout is finished by the next read
+    let mut buffer = String::new();
     while !termination_flag.load(Ordering::SeqCst) {
-        let mut buffer = String::new();
-
         match reader.read_line(&mut buffer) {
             Ok(0) => {
                 println!("Client disconnected");
                 break;
             }
             Ok(_) => {
+                let buffer = std::mem::take(&mut buffer);
                 println!("Received command: {}", buffer.trim());
                 let response = match Command::parse(buffer.trim()) {
                     Some(Command::Put(key, value)) => {
                         println!("Processing Put({}, {})", key, value);
-                        let mut tree = lsm_tree.write().unwrap();
-                        match tree.put(key, value) {
+                        match lsm_tree.put(key, value) {
                             Ok(_) => "OK".to_string(),
                             Err(e) => format!("Error: {:?}", e),
                         }
                     }
                     Some(Command::Get(key)) => {
                         println!("Processing Get({})", key);
-                        let tree = lsm_tree.read().unwrap();
-                        match tree.get(key) {
+                        match lsm_tree.get(key) {
                             Some(value) => value.to_string(),
                             None => "".to_string(),
                         }
                     }
+                    Some(Command::MultiGet(keys)) => {
+                        println!("Processing MultiGet of {} keys", keys.len());
+                        let values = lsm_tree.multi_get(&keys);
+                        // Missing keys keep their place with an empty value
+                        keys.iter()
+                            .zip(values)
+                            .map(|(k, v)| match v {
+                                Some(v) => format!("{}:{}", k, v),
+                                None => format!("{}:", k),
+                            })
+                            .collect::<Vec<_>>()
+                            .join(" ")
+                    }
                     Some(Command::Range(start, end)) => {
                         println!("Processing Range({}, {})", start, end);
-                        let tree = lsm_tree.read().unwrap();
-                        tree.range(start, end)
+                        lsm_tree
+                            .range(start, end)
                             .into_iter()
                             .map(|(k, v)| format!("{}:{}", k, v))
                             .collect::<Vec<_>>()
@@ -62,8 +88,7 @@ fn handle_client(
                     }
                     Some(Command::Delete(key)) => {
                         println!("Processing Delete({})", key);
-                        let mut tree = lsm_tree.write().unwrap();
-                        match tree.delete(key) {
+                        match lsm_tree.delete(key) {
                             Ok(_) => "OK".to_string(),
                             Err(e) => format!("Error: {:?}", e),
                         }
@@ -73,13 +98,20 @@ fn handle_client(
                         termination_flag.store(true, Ordering::SeqCst);
                         "Shutting down the server".to_string()
                     }
-                    Some(Command::Load(_)) => {
-                        eprintln!("Load command is not implemented");
-                        "Error: Load command not implemented".to_string()
+                    Some(Command::Load(path)) => {
+                        println!("Processing Load({})", path);
+                        match lsm_tree.load_file(Path::new(&path)) {
+                            Ok(summary) => summary.to_string(),
+                            Err(e) => format!("Error: {:?}", e),
+                        }
                     }
                     Some(Command::PrintStats) => {
-                        eprintln!("PrintStats command is not implemented");
-                        "Error: PrintStats command not implemented".to_string()
+                        println!("Processing PrintStats");
+                        lsm_tree.tree_stats(true).to_string()
+                    }
+                    Some(Command::PrintSummary) => {
+                        println!("Processing PrintSummary");
+                        lsm_tree.tree_stats(false).to_string()
                     }
                     None => {
                         eprintln!("Invalid command received");
@@ -92,10 +124,7 @@ fn handle_client(
                     break;
                 }
             }
-            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                std::thread::sleep(std::time::Duration::from_millis(100));
-                continue;
-            }
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => continue,
             Err(e) => {
                 eprintln!("Error reading from client: {}", e);
                 break;
@@ -106,12 +135,216 @@ fn handle_client(
     println!("Stopped handling client");
 }
 
+const CONSOLE_HELP: &str = "\
+Server commands:
+  bloom    Print the filters of every level and run
+  stats    Print the number of live keys in each level
+  flush    Write the memtables out to runs
+  compact  Merge every run into one in the last level
+  quit     Flush the memtables and shut down the server
+  help     Print this help message";
+
+/// Reads admin commands from the server's terminal until `quit`, the end of
+/// input or the server stopping.
+fn run_console(termination_flag: Arc<AtomicBool>, lsm_tree: Arc<LSMTree>) {
+    // Reading stdin can't be interrupted, so it happens on a thread of its
+    // own that holds nothing the shutdown waits for
+    let (sender, lines) = mpsc::channel();
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    while !termination_flag.load(Ordering::SeqCst) {
+        let line = match lines.recv_timeout(POLL_INTERVAL) {
+            Ok(line) => line,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        match line.trim() {
+            "" => continue,
+            "bloom" => print_bloom_summary(&lsm_tree),
+            "stats" => println!("{}", lsm_tree.tree_stats(false)),
+            "flush" => match lsm_tree.flush() {
+                Ok(()) => println!("Flushed the memtables"),
+                Err(e) => eprintln!("Flush failed: {}", e),
+            },
+            "compact" => match lsm_tree.compact() {
+                Ok(()) => println!("Compacted the tree"),
+                Err(e) => eprintln!("Compaction failed: {}", e),
+            },
+            "quit" => {
+                termination_flag.store(true, Ordering::SeqCst);
+                break;
+            }
+            "help" => println!("{}", CONSOLE_HELP),
+            other => println!("Unknown command: {}\n{}", other, CONSOLE_HELP),
+        }
+    }
+}
+
+fn print_bloom_summary(lsm_tree: &LSMTree) {
+    let bloom = lsm_tree.bloom_params();
+    println!("Bloom filters ({}): {}", bloom.kind, bloom);
+
+    for (index, level) in lsm_tree.level_stats().iter().enumerate() {
+        if level.runs == 0 {
+            continue;
+        }
+        println!(
+            "Level {}: {} runs, {} entries, {} ({:.4} expected false positives per lookup){}",
+            index + 1,
+            level.runs,
+            level.entries,
+            level.bloom,
+            level.expected_false_positives,
+            observed(level.filter),
+        );
+        for run in &level.run_stats {
+            let name = run
+                .id
+                .map_or("in memory".to_string(), |id| format!("file {}", id));
+            println!(
+                "  Run ({}, {}): {} keys, {} bytes ({:.2} bits per key), {} probes, FPR {:.4}{}",
+                name,
+                run.filter_kind,
+                run.filter_entries,
+                run.filter_bytes,
+                (run.filter_bytes * 8) as f64 / run.filter_entries.max(1) as f64,
+                run.filter_probes,
+                run.expected_false_positive_rate,
+                observed(run.filter),
+            );
+        }
+    }
+}
+
+/// The false-positive rate measured on lookups so far, if there were any.
+fn observed(counts: FilterCounts) -> String {
+    match counts.false_positive_rate() {
+        Some(rate) => format!(
+            ", observed {:.4} over {} lookups",
+            rate,
+            counts.true_negatives + counts.false_positives
+        ),
+        None => String::new(),
+    }
+}
+
+const USAGE: &str = "\
+Usage: server [OPTIONS]
+
+Options:
+  -e <error_rate>    Bloom filter error rate (default: 0.01)
+  -n <num_pages>     Size of the buffer by number of disk pages (default: 1024)
+  -f <fanout>        LSM tree fanout (default: 2)
+  -l <level_policy>  Compaction policy: tiered, leveled, lazy_leveled, partial
+                     or hybrid:K:Z (default: leveled)
+  -a <allocation>    Filter memory allocation: uniform or monkey (default: uniform)
+  -k <filter_kind>   Filter implementation: bloom, rocksdb, speeddb or fuse
+                     (default: bloom)
+  -r <bits_per_key>  Build range filters with this much memory per key
+  -d <data_dir>      Keep the tree's files in this directory (default: in memory)
+  -p <port>          Port number (default: $SERVER_PORT, or 8080)
+  -h                 Print this help message";
+
+/// What the command line asks the server to do.
+struct Options {
+    config: LSMConfig,
+    /// `-p`, overriding `SERVER_PORT`
+    port: Option<u16>,
+    /// `-h` was given
+    help: bool,
+}
+
+/// Builds the tree configuration from the command line options.
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options {
+        config: LSMConfig::default(),
+        port: None,
+        help: false,
+    };
+    let config = &mut options.config;
+
+    while let Some(flag) = args.next() {
+        let mut value = || args.next().ok_or(format!("Missing value for {}", flag));
+        match flag.as_str() {
+            "-a" => {
+                let name = value()?;
+                config.bloom_allocation = FilterAllocation::from_name(&name)
+                    .ok_or(format!("Unsupported filter allocation: {}", name))?;
+            }
+            "-d" => config.data_dir = Some(PathBuf::from(value()?)),
+            "-e" => {
+                config.bloom_error_rate = value()?
+                    .parse()
+                    .map_err(|_| "Error rate must be a number".to_string())?
+            }
+            "-f" => {
+                config.fanout = value()?
+                    .parse()
+                    .map_err(|_| "Fanout must be an integer".to_string())?
+            }
+            "-h" => options.help = true,
+            "-k" => {
+                let name = value()?;
+                config.filter_kind = FilterKind::from_name(&name)
+                    .ok_or(format!("Unsupported filter kind: {}", name))?;
+            }
+            "-l" => {
+                let name = value()?;
+                config.compaction = compaction::policy_from_name(&name)
+                    .ok_or(format!("Unsupported compaction policy: {}", name))?;
+            }
+            "-n" => {
+                config.buffer_pages = value()?
+                    .parse()
+                    .map_err(|_| "Buffer size must be a number of pages".to_string())?
+            }
+            "-p" => {
+                options.port = match value()?.parse() {
+                    Ok(port) if port > 0 => Some(port),
+                    _ => return Err("Port must be a number between 1 and 65535".to_string()),
+                }
+            }
+            "-r" => {
+                let bits_per_key = value()?
+                    .parse()
+                    .map_err(|_| "Range filter bits per key must be a number".to_string())?;
+                config.range_filter = Some(RangeFilterParams::new(bits_per_key));
+            }
+            other => return Err(format!("Unknown option: {}", other)),
+        }
+    }
+    options.config.validate().map_err(|e| e.to_string())?;
+    Ok(options)
+}
+
 fn main() -> io::Result<()> {
-    // Get port from environment or use default
-    let port = std::env::var("SERVER_PORT")
-        .ok()
-        .and_then(|p| p.parse().ok())
-        .unwrap_or(8080);
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            std::process::exit(1);
+        }
+    };
+    if options.help {
+        println!("{}", USAGE);
+        return Ok(());
+    }
+    let config = options.config;
+
+    // An explicit -p wins over the environment
+    let port = options
+        .port
+        .or_else(|| std::env::var("SERVER_PORT").ok()?.parse().ok())
+        .unwrap_or(lsm_tree::DEFAULT_PORT);
 
     let addr = format!("127.0.0.1:{}", port);
     let listener = TcpListener::bind(&addr)?;
@@ -119,26 +352,66 @@ fn main() -> io::Result<()> {
     println!("Server listening on {}", addr);
 
     let termination_flag = Arc::new(AtomicBool::new(false));
-    let lsm_tree = Arc::new(RwLock::new(LSMTree::new(128))); // Initialize LSMTree with buffer size 128
+    let error_rate = config.bloom_error_rate;
+    let lsm_tree = match LSMTree::with_config(config) {
+        Ok(tree) => Arc::new(tree),
+        Err(e) => {
+            eprintln!("Failed to open the tree: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let bloom = lsm_tree.bloom_params();
+    println!(
+        "Bloom filters ({}): {} for an error rate of {}",
+        bloom.kind, bloom, error_rate
+    );
+    if let Some(range) = bloom.range {
+        println!("Range filters: {}", range);
+    }
+
+    let console = {
+        let termination_flag = Arc::clone(&termination_flag);
+        let lsm_tree = Arc::clone(&lsm_tree);
+        thread::spawn(move || run_console(termination_flag, lsm_tree))
+    };
 
+    let mut clients: Vec<JoinHandle<()>> = Vec::new();
     while !termination_flag.load(Ordering::SeqCst) {
         match listener.accept() {
             Ok((stream, _)) => {
                 println!("New client connected");
                 let termination_flag = Arc::clone(&termination_flag);
                 let lsm_tree = Arc::clone(&lsm_tree);
-                std::thread::spawn(move || {
+                clients.retain(|client| !client.is_finished());
+                clients.push(thread::spawn(move || {
                     handle_client(stream, termination_flag, lsm_tree);
-                });
+                }));
             }
-            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                std::thread::sleep(std::time::Duration::from_millis(100));
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(POLL_INTERVAL);
                 continue;
             }
             Err(e) => eprintln!("Error accepting connection: {}", e),
         }
     }
 
+    // Nothing else may touch the tree once it is flushed
+    let _ = console.join();
+    for client in clients {
+        let _ = client.join();
+    }
+
+    // Leave everything on disk, with no merge half written
+    println!("Flushing the memtables...");
+    if let Err(e) = lsm_tree
+        .flush()
+        .and_then(|()| lsm_tree.wait_for_compactions())
+    {
+        eprintln!("Failed to flush the tree: {}", e);
+    }
+    // The last reference: dropping it stops the background threads and
+    // syncs and closes the log
+    drop(lsm_tree);
     println!("Server shut down.");
     Ok(())
 }
@@ -0,0 +1,169 @@
+use super::BloomParams;
+use crate::compaction::LevelState;
+
+/// How filter memory is shared between the levels of a tree.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FilterAllocation {
+    /// Every run gets the same bits per key
+    #[default]
+    Uniform,
+    /// Spend the same total memory as [`Uniform`](Self::Uniform), but shift it
+    /// towards the small upper levels, as in Monkey (Dayan et al., SIGMOD
+    /// 2017). A lookup probes a run in every level, so a bit saved on the
+    /// largest level buys a much lower false-positive rate higher up.
+    Monkey,
+}
+
+impl FilterAllocation {
+    /// Looks up an allocation by the name used on the command line.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "uniform" => Some(Self::Uniform),
+            "monkey" => Some(Self::Monkey),
+            _ => None,
+        }
+    }
+
+    /// Filter parameters for each level of a tree shaped like `levels`, given
+    /// an average budget of `bits_per_key` over all entries.
+    pub fn allocate(&self, levels: &[LevelState], bits_per_key: f64) -> Vec<BloomParams> {
+        let uniform = vec![BloomParams::from_bits_per_key(bits_per_key); levels.len()];
+        let total_entries: usize = levels.iter().map(LevelState::len).sum();
+        match self {
+            Self::Uniform => uniform,
+            Self::Monkey if total_entries == 0 => uniform,
+            Self::Monkey => monkey(levels, bits_per_key * total_entries as f64),
+        }
+    }
+}
+
+/// Minimizes the expected number of runs a lookup for an absent key reads,
+/// `sum(runs_i * p_i)`, for a total of `budget` filter bits.
+///
+/// The optimum makes each level's false-positive rate proportional to the
+/// size of its runs, `p_i = min(1, lambda * entries_i / runs_i)`; `lambda` is
+/// found by bisection so that the filters use up the budget.
+fn monkey(levels: &[LevelState], budget: f64) -> Vec<BloomParams> {
+    let ln2_squared = std::f64::consts::LN_2 * std::f64::consts::LN_2;
+    let run_size = |level: &LevelState| level.len() as f64 / level.runs.len().max(1) as f64;
+    let rates = |ln_lambda: f64| {
+        levels
+            .iter()
+            .map(move |level| (ln_lambda + run_size(level).max(1.0).ln()).min(0.0))
+    };
+    let bits_used = |ln_lambda: f64| -> f64 {
+        let ln_rates = rates(ln_lambda);
+        levels
+            .iter()
+            .zip(ln_rates)
+            .map(|(level, ln_rate)| level.len() as f64 * -ln_rate / ln2_squared)
+            .sum()
+    };
+
+    // Runs hold at least one entry, so at lambda = 1 every rate is 1 and no
+    // memory is used
+    let mut high = 0.0;
+    let mut low = -200.0;
+    for _ in 0..100 {
+        let mid = (low + high) / 2.0;
+        if bits_used(mid) > budget {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+
+    rates(high)
+        .map(|ln_rate| BloomParams::from_bits_per_key(-ln_rate / ln2_squared))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compaction::RunState;
+
+    fn levels(runs: &[&[usize]]) -> Vec<LevelState> {
+        let run = |len| RunState {
+            len,
+            min_key: 0,
+            max_key: 0,
+        };
+        runs.iter()
+            .map(|runs| LevelState {
+                runs: runs.iter().copied().map(run).collect(),
+                compacting: false,
+                cursor: None,
+            })
+            .collect()
+    }
+
+    fn memory(levels: &[LevelState], params: &[BloomParams]) -> f64 {
+        levels
+            .iter()
+            .zip(params)
+            .map(|(level, params)| level.len() as f64 * params.bits_per_key)
+            .sum()
+    }
+
+    fn lookup_cost(levels: &[LevelState], params: &[BloomParams]) -> f64 {
+        levels
+            .iter()
+            .zip(params)
+            .map(|(level, params)| level.runs.len() as f64 * params.false_positive_rate())
+            .sum()
+    }
+
+    #[test]
+    fn test_from_name() {
+        assert_eq!(
+            FilterAllocation::from_name("uniform"),
+            Some(FilterAllocation::Uniform)
+        );
+        assert_eq!(
+            FilterAllocation::from_name("monkey"),
+            Some(FilterAllocation::Monkey)
+        );
+        assert_eq!(FilterAllocation::from_name("optimal"), None);
+    }
+
+    #[test]
+    fn test_uniform() {
+        let levels = levels(&[&[100], &[1000]]);
+        let params = FilterAllocation::Uniform.allocate(&levels, 8.0);
+        assert_eq!(params, vec![BloomParams::from_bits_per_key(8.0); 2]);
+    }
+
+    #[test]
+    fn test_monkey_shifts_memory_upwards() {
+        let leveled = levels(&[&[1_000], &[10_000], &[100_000], &[1_000_000]]);
+        let tiered = levels(&[&[1_000; 3], &[3_000; 3], &[9_000; 3]]);
+        for levels in [leveled, tiered] {
+            let uniform = FilterAllocation::Uniform.allocate(&levels, 10.0);
+            let monkey = FilterAllocation::Monkey.allocate(&levels, 10.0);
+
+            // Upper levels get more bits per key, for the same total memory
+            assert!(monkey
+                .windows(2)
+                .all(|pair| pair[0].bits_per_key > pair[1].bits_per_key));
+            let budget = memory(&levels, &uniform);
+            assert!((memory(&levels, &monkey) - budget).abs() < budget * 0.01);
+
+            // And lookups for absent keys read fewer runs
+            assert!(lookup_cost(&levels, &monkey) < lookup_cost(&levels, &uniform));
+        }
+    }
+
+    #[test]
+    fn test_monkey_edge_cases() {
+        // Nothing to allocate yet
+        let empty = levels(&[&[], &[]]);
+        assert_eq!(FilterAllocation::Monkey.allocate(&empty, 10.0).len(), 2);
+
+        // A tiny budget leaves the largest level with the minimal filter
+        let levels = levels(&[&[10], &[1_000_000]]);
+        let params = FilterAllocation::Monkey.allocate(&levels, 0.5);
+        assert_eq!(params[1], BloomParams::from_bits_per_key(1.0));
+        assert!(params[0].bits_per_key > 1.0);
+    }
+}
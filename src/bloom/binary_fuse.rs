@@ -0,0 +1,447 @@
+use crate::run::Error;
+use crate::run::{FilterStrategy, Result};
+use crate::types::Key;
+use xxhash_rust::xxh3::xxh3_64;
+
+/// Widest fingerprint a [`BinaryFuseFilter`] stores.
+const MAX_FINGERPRINT_BITS: u32 = 16;
+
+/// Bytes past the last fingerprint so every lookup can read a whole `u32`.
+const PADDING: usize = 3;
+
+/// Serialized header: num_entries, seed, segment_length, segment_count_length
+/// and fingerprint_bits.
+const HEADER_SIZE: usize = 28;
+
+/// A static 3-wise binary fuse filter (Graf & Lemire, 2022).
+///
+/// Every key maps to three slots in consecutive segments of an array of
+/// fingerprints, and construction picks the slot values so that the three
+/// XOR to the key's own fingerprint. That takes the whole key set up front,
+/// which suits runs as they never change once written, and in return the
+/// filter needs about `1.13 * f` bits per key for a false-positive rate of
+/// `2^-f`, where a Bloom filter needs `1.44 * f` or more. Fingerprints are
+/// bit-packed so any width from 1 to 16 bits can be used.
+pub struct BinaryFuseFilter {
+    seed: u64,
+    segment_length: u32,
+    segment_count_length: u32,
+    fingerprint_bits: u32,
+    fingerprints: Box<[u8]>,
+    num_entries: u64,
+}
+
+impl BinaryFuseFilter {
+    /// Builds a filter over `keys` with fingerprints of `fingerprint_bits`
+    /// bits, clamped to 1..=16. Duplicate keys are allowed.
+    pub fn build(keys: &[Key], fingerprint_bits: u32) -> Self {
+        let fingerprint_bits = fingerprint_bits.clamp(1, MAX_FINGERPRINT_BITS);
+        let mut hashes: Vec<u64> = keys.iter().map(key_hash).collect();
+        hashes.sort_unstable();
+        hashes.dedup();
+
+        let size = hashes.len();
+        let (segment_length, mut segment_count) = layout(size);
+        let mut seed = 0x726b_2b9d_438b_9d4d;
+        let mut attempts = 0;
+        loop {
+            let mut filter = Self {
+                seed: splitmix64(&mut seed),
+                segment_length,
+                segment_count_length: segment_count * segment_length,
+                fingerprint_bits,
+                fingerprints: Box::default(),
+                num_entries: size as u64,
+            };
+            if let Some(slots) = filter.solve(&hashes) {
+                filter.fingerprints = filter.pack(&slots);
+                return filter;
+            }
+
+            // Every seed fails with small probability; if several in a row
+            // do, the array is too tight for these keys, so give it room
+            attempts += 1;
+            if attempts % 8 == 0 {
+                segment_count += 1;
+            }
+        }
+    }
+
+    /// Fingerprint width that reaches `error_rate`, `ceil(-log2(error_rate))`.
+    pub fn fingerprint_bits_for(error_rate: f64) -> u32 {
+        let bits = (-error_rate.log2()).ceil();
+        if bits.is_nan() {
+            return MAX_FINGERPRINT_BITS;
+        }
+        bits.clamp(1.0, MAX_FINGERPRINT_BITS as f64) as u32
+    }
+
+    pub fn may_contain(&self, key: Key) -> bool {
+        if self.segment_count_length == 0 {
+            return false;
+        }
+        let hash = mix(key_hash(&key), self.seed);
+        let [h0, h1, h2] = self.positions(hash);
+        self.fingerprint(hash) == self.slot(h0) ^ self.slot(h1) ^ self.slot(h2)
+    }
+
+    pub fn num_entries(&self) -> u64 {
+        self.num_entries
+    }
+
+    pub fn fingerprint_bits(&self) -> u32 {
+        self.fingerprint_bits
+    }
+
+    /// Filter memory per distinct key.
+    pub fn bits_per_key(&self) -> f64 {
+        let bits = self.array_length() as f64 * self.fingerprint_bits as f64;
+        bits / self.num_entries.max(1) as f64
+    }
+
+    /// Chance that an absent key matches: its fingerprint has to equal the XOR
+    /// of three slots, which for a key that wasn't added is a uniformly random
+    /// `fingerprint_bits` value.
+    pub fn theoretical_fp_rate(&self) -> f64 {
+        if self.num_entries == 0 {
+            return 0.0;
+        }
+        (-(self.fingerprint_bits as f64)).exp2()
+    }
+
+    fn array_length(&self) -> usize {
+        array_length(self.segment_length, self.segment_count_length)
+    }
+
+    fn mask(&self) -> u16 {
+        (u32::MAX >> (32 - self.fingerprint_bits)) as u16
+    }
+
+    fn fingerprint(&self, hash: u64) -> u16 {
+        (hash ^ (hash >> 32)) as u16 & self.mask()
+    }
+
+    /// The three slots for `hash`, one in each of three consecutive segments
+    /// starting at a segment picked by the high bits of the hash.
+    fn positions(&self, hash: u64) -> [usize; 3] {
+        let h0 = ((hash as u128 * self.segment_count_length as u128) >> 64) as u64;
+        let mask = self.segment_length as u64 - 1;
+        let h1 = (h0 + self.segment_length as u64) ^ ((hash >> 18) & mask);
+        let h2 = (h0 + 2 * self.segment_length as u64) ^ (hash & mask);
+        [h0 as usize, h1 as usize, h2 as usize]
+    }
+
+    fn slot(&self, index: usize) -> u16 {
+        let bit = index * self.fingerprint_bits as usize;
+        let word = u32::from_le_bytes(self.fingerprints[bit / 8..bit / 8 + 4].try_into().unwrap());
+        (word >> (bit % 8)) as u16 & self.mask()
+    }
+
+    /// Assigns slot values for `hashes` with the current seed, or `None` if
+    /// the keys can't be peeled off one slot at a time.
+    fn solve(&self, hashes: &[u64]) -> Option<Vec<u16>> {
+        let length = self.array_length();
+        // Each slot counts its keys in the upper six bits and XORs which of
+        // the three positions it is for them in the lower two, so once only
+        // one key is left both the key's hash and its position are known
+        let mut counts = vec![0u8; length];
+        let mut xors = vec![0u64; length];
+        for &hash in hashes {
+            let hash = mix(hash, self.seed);
+            for (position, index) in self.positions(hash).into_iter().enumerate() {
+                counts[index] = counts[index].wrapping_add(4) ^ position as u8;
+                xors[index] ^= hash;
+                if counts[index] < 4 {
+                    return None;
+                }
+            }
+        }
+
+        // Repeatedly take a key out of a slot that holds only it, which
+        // leaves the slot free to be set for that key alone
+        let mut queue: Vec<usize> = (0..length).filter(|&i| counts[i] >> 2 == 1).collect();
+        let mut order = Vec::with_capacity(hashes.len());
+        while let Some(index) = queue.pop() {
+            if counts[index] >> 2 != 1 {
+                continue;
+            }
+            let hash = xors[index];
+            let found = counts[index] & 3;
+            order.push((hash, found));
+
+            let positions = self.positions(hash);
+            for offset in 1..3 {
+                let position = (found + offset) % 3;
+                let other = positions[position as usize];
+                counts[other] = counts[other].wrapping_sub(4) ^ position;
+                xors[other] ^= hash;
+                if counts[other] >> 2 == 1 {
+                    queue.push(other);
+                }
+            }
+        }
+        if order.len() != hashes.len() {
+            return None;
+        }
+
+        // Set slots in reverse peeling order: each key's free slot is filled
+        // after the other two have their final values
+        let mut slots = vec![0u16; length];
+        for &(hash, found) in order.iter().rev() {
+            let positions = self.positions(hash);
+            let [free, a, b] = [0, 1, 2].map(|offset| positions[(found as usize + offset) % 3]);
+            slots[free] = self.fingerprint(hash) ^ slots[a] ^ slots[b];
+        }
+        Some(slots)
+    }
+
+    fn pack(&self, slots: &[u16]) -> Box<[u8]> {
+        let width = self.fingerprint_bits as usize;
+        let mut bytes = vec![0u8; packed_len(slots.len(), self.fingerprint_bits)];
+        for (index, &slot) in slots.iter().enumerate() {
+            let bit = index * width;
+            let shifted = (slot as u32) << (bit % 8);
+            for (byte, value) in bytes[bit / 8..bit / 8 + 3]
+                .iter_mut()
+                .zip(shifted.to_le_bytes())
+            {
+                *byte |= value;
+            }
+        }
+        bytes.into_boxed_slice()
+    }
+}
+
+/// Segment length and count for `size` keys, as tuned in the binary fuse
+/// paper: segments grow with the key set, and smaller sets need relatively
+/// more slots to be solvable.
+fn layout(size: usize) -> (u32, u32) {
+    if size == 0 {
+        return (1, 0);
+    }
+    let n = size as f64;
+    let segment_length = 1u32 << ((n.ln() / 3.33f64.ln() + 2.25).floor() as u32).min(18);
+    let size_factor = if size <= 1 {
+        0.0
+    } else {
+        (0.875 + 0.25 * 1e6f64.ln() / n.ln()).max(1.125)
+    };
+    let capacity = (n * size_factor).round() as u64;
+    let segments = capacity.div_ceil(segment_length as u64).saturating_sub(2);
+    (segment_length, segments.max(1) as u32)
+}
+
+fn array_length(segment_length: u32, segment_count_length: u32) -> usize {
+    if segment_count_length == 0 {
+        return 0;
+    }
+    segment_count_length as usize + 2 * segment_length as usize
+}
+
+fn packed_len(slots: usize, fingerprint_bits: u32) -> usize {
+    (slots * fingerprint_bits as usize).div_ceil(8) + PADDING
+}
+
+fn key_hash(key: &Key) -> u64 {
+    xxh3_64(&key.to_le_bytes())
+}
+
+/// Rehashes a key's hash for one construction attempt.
+fn mix(hash: u64, seed: u64) -> u64 {
+    let mut h = hash.wrapping_add(seed);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    h ^ (h >> 33)
+}
+
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+impl FilterStrategy for BinaryFuseFilter {
+    /// An empty filter; binary fuse filters are built with [`Self::build`].
+    fn new(_expected_entries: usize) -> Self {
+        Self::build(&[], 8)
+    }
+
+    fn add(&mut self, _key: &Key) -> Result<()> {
+        Err(Error::Filter(
+            "Binary fuse filters are built from the full key set and can't be added to".to_string(),
+        ))
+    }
+
+    fn may_contain(&self, key: &Key) -> bool {
+        self.may_contain(*key)
+    }
+
+    fn false_positive_rate(&self) -> f64 {
+        self.theoretical_fp_rate()
+    }
+
+    fn num_entries(&self) -> usize {
+        Self::num_entries(self) as usize
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.fingerprints.len()
+    }
+
+    fn num_probes(&self) -> u32 {
+        3
+    }
+
+    fn serialize(&self) -> Result<Vec<u8>> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + self.fingerprints.len());
+        bytes.extend_from_slice(&self.num_entries.to_le_bytes());
+        bytes.extend_from_slice(&self.seed.to_le_bytes());
+        bytes.extend_from_slice(&self.segment_length.to_le_bytes());
+        bytes.extend_from_slice(&self.segment_count_length.to_le_bytes());
+        bytes.extend_from_slice(&self.fingerprint_bits.to_le_bytes());
+        bytes.extend_from_slice(&self.fingerprints);
+        Ok(bytes)
+    }
+
+    fn deserialize(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(Error::Serialization(
+                "Invalid buffer size for binary fuse filter deserialization".to_string(),
+            ));
+        }
+
+        let num_entries = u64::from_le_bytes(bytes[0..8].try_into().unwrap());
+        let seed = u64::from_le_bytes(bytes[8..16].try_into().unwrap());
+        let segment_length = u32::from_le_bytes(bytes[16..20].try_into().unwrap());
+        let segment_count_length = u32::from_le_bytes(bytes[20..24].try_into().unwrap());
+        let fingerprint_bits = u32::from_le_bytes(bytes[24..28].try_into().unwrap());
+        let fingerprints = &bytes[HEADER_SIZE..];
+
+        if !segment_length.is_power_of_two()
+            || segment_count_length % segment_length != 0
+            || !(1..=MAX_FINGERPRINT_BITS).contains(&fingerprint_bits)
+            || fingerprints.len()
+                != packed_len(
+                    array_length(segment_length, segment_count_length),
+                    fingerprint_bits,
+                )
+        {
+            return Err(Error::Serialization(format!(
+                "Binary fuse filter with segments of {} over {} slots and {} bit fingerprints \
+                 doesn't match its {} byte buffer",
+                segment_length,
+                segment_count_length,
+                fingerprint_bits,
+                bytes.len()
+            )));
+        }
+
+        Ok(Self {
+            seed,
+            segment_length,
+            segment_count_length,
+            fingerprint_bits,
+            fingerprints: fingerprints.into(),
+            num_entries,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bloom::{check_filter, Bloom};
+
+    #[test]
+    fn test_no_false_negatives() {
+        for size in [0, 1, 2, 3, 10, 100, 1_000, 50_000] {
+            let keys: Vec<Key> = (0..size).map(|i| i * 7 - 300).collect();
+            let filter = BinaryFuseFilter::build(&keys, 8);
+            assert_eq!(filter.num_entries(), size as u64);
+            assert!(
+                keys.iter().all(|&key| filter.may_contain(key)),
+                "size {}",
+                size
+            );
+        }
+        assert!(!BinaryFuseFilter::build(&[], 8).may_contain(0));
+    }
+
+    #[test]
+    fn test_duplicate_keys() {
+        let keys: Vec<Key> = (0..1_000).map(|i| i % 10).collect();
+        let filter = BinaryFuseFilter::build(&keys, 8);
+        assert_eq!(filter.num_entries(), 10);
+        assert!((0..10).all(|key| filter.may_contain(key)));
+    }
+
+    #[test]
+    fn test_false_positive_rate() {
+        let keys: Vec<Key> = (0..100_000).collect();
+        for bits in [4, 7, 10] {
+            let filter = BinaryFuseFilter::build(&keys, bits);
+            let false_positives = (100_000..1_100_000)
+                .filter(|&key| filter.may_contain(key))
+                .count();
+            let measured = false_positives as f64 / 1_000_000.0;
+            let expected = filter.theoretical_fp_rate();
+            assert!(
+                (measured - expected).abs() < expected * 0.15,
+                "{} bits: measured {} expected {}",
+                bits,
+                measured,
+                expected
+            );
+            assert!(filter.bits_per_key() < bits as f64 * 1.25);
+        }
+    }
+
+    #[test]
+    fn test_smaller_than_bloom() {
+        // At the same false-positive rate a fuse filter needs well under the
+        // memory of a Bloom filter
+        let keys: Vec<Key> = (0..100_000).collect();
+        let fuse = BinaryFuseFilter::build(&keys, 7);
+        let bloom = Bloom::new(100_000 * 10, 7);
+        for &key in &keys {
+            bloom.add_hash(xxh3_64(&key.to_le_bytes()) as u32);
+        }
+        assert!(fuse.theoretical_fp_rate() < bloom.theoretical_fp_rate(100_000));
+        let fuse_size = fuse.serialize().unwrap().len();
+        let bloom_size = FilterStrategy::serialize(&bloom).unwrap().len();
+        assert!(
+            (fuse_size as f64) < bloom_size as f64 * 0.85,
+            "fuse {} bytes, bloom {} bytes",
+            fuse_size,
+            bloom_size
+        );
+    }
+
+    #[test]
+    fn test_fingerprint_bits_for() {
+        assert_eq!(BinaryFuseFilter::fingerprint_bits_for(0.5), 1);
+        assert_eq!(BinaryFuseFilter::fingerprint_bits_for(0.01), 7);
+        assert_eq!(BinaryFuseFilter::fingerprint_bits_for(1.0 / 256.0), 8);
+        assert_eq!(BinaryFuseFilter::fingerprint_bits_for(1e-9), 16);
+        assert_eq!(BinaryFuseFilter::fingerprint_bits_for(1.0), 1);
+    }
+
+    #[test]
+    fn test_filter_strategy() {
+        let num_keys = 10_000;
+        let keys: Vec<Key> = (0..num_keys).collect();
+        let mut filter = BinaryFuseFilter::build(&keys, 8);
+        assert!(FilterStrategy::add(&mut filter, &num_keys).is_err());
+        check_filter(&filter, num_keys, 0.15);
+
+        // An empty filter lets nothing through, even after a round trip
+        let empty = BinaryFuseFilter::new(100);
+        let restored = BinaryFuseFilter::deserialize(&empty.serialize().unwrap()).unwrap();
+        assert!(!FilterStrategy::may_contain(&restored, &0));
+        assert_eq!(FilterStrategy::false_positive_rate(&restored), 0.0);
+    }
+}
@@ -1,14 +1,207 @@
+mod allocation;
+mod binary_fuse;
+mod range;
 mod rocks_db;
 mod speed_db;
 
+pub use self::allocation::FilterAllocation;
+pub use self::binary_fuse::BinaryFuseFilter;
+pub use self::range::{RangeFilter, RangeFilterParams, DEFAULT_RANGE_FILTER_LEVELS};
 pub use self::rocks_db::RocksDBLocalBloom;
 pub use self::speed_db::SpeedDbDynamicBloom;
 
 use crate::run::Error;
-use crate::run::{FilterStrategy, Result};
+use crate::run::{key_hash, FilterStrategy, Result};
 use crate::types::Key;
 use std::sync::atomic::{AtomicU64, Ordering};
-use xxhash_rust::xxh3::xxh3_128;
+
+/// Most probes any of the filters supports.
+const MAX_PROBES: u32 = 10;
+
+/// Most probes a [`Bloom`] filter makes use of: its 32-bit hash only has room
+/// for three independent double probes, and later ones reuse their bits.
+const MAX_BLOOM_PROBES: u32 = 6;
+
+/// How large a run's Bloom filter is, how many probes it makes per key,
+/// which implementation builds it and whether the run gets a range filter
+/// too.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BloomParams {
+    pub bits_per_key: f64,
+    pub num_probes: u32,
+    pub kind: FilterKind,
+    /// Range filter built alongside the point filter, if any
+    pub range: Option<RangeFilterParams>,
+}
+
+impl BloomParams {
+    /// Sizes a filter for a target false-positive rate.
+    ///
+    /// An optimal Bloom filter needs `-ln(p) / ln(2)^2` bits per key to reach
+    /// rate `p`.
+    pub fn from_error_rate(error_rate: f64) -> Self {
+        let ln2 = std::f64::consts::LN_2;
+        Self::from_bits_per_key(-error_rate.ln() / (ln2 * ln2))
+    }
+
+    /// Picks the probe count that minimizes the false-positive rate for the
+    /// given memory, `bits_per_key * ln(2)`, capped at what the default
+    /// filter supports. Every filter gets at least one bit per key.
+    pub fn from_bits_per_key(bits_per_key: f64) -> Self {
+        let bits_per_key = bits_per_key.max(1.0);
+        let kind = FilterKind::default();
+        Self {
+            bits_per_key,
+            num_probes: kind.optimal_probes(bits_per_key),
+            kind,
+            range: None,
+        }
+    }
+
+    /// The same sizing, built by the `kind` filter, with the probe count
+    /// capped at what that filter supports instead.
+    pub fn with_kind(self, kind: FilterKind) -> Self {
+        Self {
+            kind,
+            num_probes: kind.optimal_probes(self.bits_per_key),
+            ..self
+        }
+    }
+
+    /// The same sizing, with a range filter built by `range` if given.
+    pub fn with_range_filter(self, range: Option<RangeFilterParams>) -> Self {
+        Self { range, ..self }
+    }
+
+    /// Expected false-positive rate, `(1 - e^(-k / b))^k` for `k` probes and
+    /// `b` bits per key.
+    pub fn false_positive_rate(&self) -> f64 {
+        let k = self.num_probes as f64;
+        (1.0 - (-k / self.bits_per_key).exp()).powf(k)
+    }
+
+    /// Number of bits to allocate for `num_entries` keys.
+    pub fn total_bits(&self, num_entries: usize) -> u32 {
+        (num_entries as f64 * self.bits_per_key).ceil() as u32
+    }
+}
+
+impl Default for BloomParams {
+    fn default() -> Self {
+        Self::from_error_rate(crate::config::DEFAULT_BLOOM_ERROR_RATE)
+    }
+}
+
+impl std::fmt::Display for BloomParams {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:.2} bits per key, {} probes",
+            self.bits_per_key, self.num_probes
+        )
+    }
+}
+
+/// Which of the crate's filter implementations a run uses.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FilterKind {
+    /// [`Bloom`]
+    #[default]
+    Bloom,
+    /// [`RocksDBLocalBloom`]
+    RocksDbLocal,
+    /// [`SpeedDbDynamicBloom`]
+    SpeedDbDynamic,
+    /// [`BinaryFuseFilter`], with fingerprints wide enough for the
+    /// false-positive rate the parameters give a Bloom filter
+    BinaryFuse,
+}
+
+impl FilterKind {
+    pub(crate) const ALL: [Self; 4] = [
+        Self::Bloom,
+        Self::RocksDbLocal,
+        Self::SpeedDbDynamic,
+        Self::BinaryFuse,
+    ];
+
+    /// Looks up a filter by the name used on the command line.
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.name() == name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Bloom => "bloom",
+            Self::RocksDbLocal => "rocksdb",
+            Self::SpeedDbDynamic => "speeddb",
+            Self::BinaryFuse => "fuse",
+        }
+    }
+
+    /// Tag recording the kind in run files.
+    pub(crate) fn id(&self) -> u32 {
+        match self {
+            Self::Bloom => 0,
+            Self::RocksDbLocal => 1,
+            Self::SpeedDbDynamic => 2,
+            Self::BinaryFuse => 3,
+        }
+    }
+
+    pub(crate) fn from_id(id: u32) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.id() == id)
+    }
+
+    /// Probe count minimizing the false-positive rate at `bits_per_key`,
+    /// capped at what this filter supports.
+    fn optimal_probes(&self, bits_per_key: f64) -> u32 {
+        let max_probes = match self {
+            Self::Bloom => MAX_BLOOM_PROBES,
+            _ => MAX_PROBES,
+        };
+        (bits_per_key * std::f64::consts::LN_2)
+            .round()
+            .clamp(1.0, max_probes as f64) as u32
+    }
+
+    /// A filter over `keys` sized by `params`.
+    pub(crate) fn build(&self, params: &BloomParams, keys: &[Key]) -> Box<dyn FilterStrategy> {
+        let total_bits = params.total_bits(keys.len());
+        let mut filter: Box<dyn FilterStrategy> = match self {
+            Self::Bloom => Box::new(Bloom::new(total_bits, params.num_probes)),
+            Self::RocksDbLocal => Box::new(RocksDBLocalBloom::new(total_bits, params.num_probes)),
+            Self::SpeedDbDynamic => {
+                Box::new(SpeedDbDynamicBloom::new(total_bits, params.num_probes))
+            }
+            Self::BinaryFuse => {
+                let fingerprint_bits =
+                    BinaryFuseFilter::fingerprint_bits_for(params.false_positive_rate());
+                return Box::new(BinaryFuseFilter::build(keys, fingerprint_bits));
+            }
+        };
+        for key in keys {
+            filter.add(key).unwrap();
+        }
+        filter
+    }
+
+    /// Reads back a filter of this kind written by [`FilterStrategy::serialize`].
+    pub(crate) fn deserialize(&self, bytes: &[u8]) -> Result<Box<dyn FilterStrategy>> {
+        Ok(match self {
+            Self::Bloom => Box::new(Bloom::deserialize(bytes)?),
+            Self::RocksDbLocal => Box::new(RocksDBLocalBloom::deserialize(bytes)?),
+            Self::SpeedDbDynamic => Box::new(SpeedDbDynamicBloom::deserialize(bytes)?),
+            Self::BinaryFuse => Box::new(BinaryFuseFilter::deserialize(bytes)?),
+        })
+    }
+}
+
+impl std::fmt::Display for FilterKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
 
 /// A cache-efficient Bloom filter implementation with optimized probe patterns.
 ///
@@ -22,6 +215,7 @@ pub struct Bloom {
     len: u32,               // Length in 64-bit words
     num_double_probes: u32, // Each probe sets two bits, so this is (num_probes + 1) / 2
     data: Box<[AtomicU64]>, // The underlying bit array stored as atomic words
+    num_entries: AtomicU64, // Number of hashes added so far
 }
 
 impl Bloom {
@@ -41,13 +235,13 @@ impl Bloom {
         // A block is 512 bits (8 x 64-bit words) to match common cache line sizes
         let block_bits = 512;
         let min_bits = std::cmp::max(block_bits, total_bits);
-        let min_blocks = (min_bits + block_bits - 1) / block_bits;
+        let min_blocks = min_bits.div_ceil(block_bits);
         // Round blocks to next power of 2 for efficient indexing
         let blocks = round_up_pow2(min_blocks);
         let len = blocks * (block_bits / 64);
 
         // Calculate number of double probes - each probe sets two bits
-        let num_double_probes = (num_probes + 1) / 2;
+        let num_double_probes = num_probes.div_ceil(2).max(1);
         let mut data = Vec::with_capacity(len as usize);
         data.extend((0..len).map(|_| AtomicU64::new(0)));
 
@@ -55,6 +249,7 @@ impl Bloom {
             len: len as u32,
             num_double_probes,
             data: data.into_boxed_slice(),
+            num_entries: AtomicU64::new(0),
         }
     }
 
@@ -117,7 +312,7 @@ impl Bloom {
     /// * `h32` - The original 32-bit hash value
     /// * `base_offset` - Starting offset in the bit array (already prepared via prepare_hash)
     /// * `or_func` - Closure that performs the actual bit setting operation, allowing different
-    ///               atomic strategies for concurrent vs single-threaded access
+    ///   atomic strategies for concurrent vs single-threaded access
     ///
     /// # Implementation Notes
     /// - Uses the same probe sequence as double_probe() for consistency
@@ -135,6 +330,7 @@ impl Bloom {
         let mut h2 = h32.wrapping_mul(0x9e3779b9);
         let len_mask = (self.len - 1) as usize;
         let mut offset = base_offset;
+        self.num_entries.fetch_add(1, Ordering::Relaxed);
 
         for _ in 0..self.num_double_probes {
             // Extract bit positions using bottom 6 bits of each hash
@@ -230,10 +426,27 @@ impl Bloom {
     #[cfg(not(target_arch = "x86_64"))]
     pub fn prefetch(&self, _h32: u32) {}
 
-    /// Calculate theoretical false positive rate for the current configuration.
+    /// Number of hashes added to the filter, duplicates included.
+    pub fn num_entries(&self) -> usize {
+        self.num_entries.load(Ordering::Relaxed) as usize
+    }
+
+    /// Calculate theoretical false positive rate once `num_entries` hashes
+    /// have been added.
+    ///
+    /// The standard blocked Bloom filter estimate with a word as the block:
+    /// the number of hashes writing to a word is Poisson distributed, and
+    /// each of them sets two of its bits. Every hash steps through the words
+    /// at the same stride, so a lookup's words share one load. A first
+    /// probe's second bit is derived from its first, so it counts as one bit
+    /// that each write sets with chance 1/64.
     pub fn theoretical_fp_rate(&self, num_entries: usize) -> f64 {
-        let bits_per_key = (self.len * 64) as f64 / num_entries as f64;
-        (1.0 - std::f64::consts::E.powf(-bits_per_key * 0.7)).powi(6)
+        let probes = self.num_double_probes as i32;
+        let load = num_entries as f64 * probes as f64 / self.len as f64;
+        let unset = 63.0f64 / 64.0;
+        expected_pass_rate(load, |writes| {
+            (1.0 - unset.powi(writes)) * (1.0 - unset.powi(2 * writes)).powi(2 * probes - 2)
+        })
     }
 
     /// Get the current memory usage in bytes.
@@ -242,6 +455,21 @@ impl Bloom {
     }
 }
 
+/// `E[pass(w)]` for a Poisson distributed number of writes `w` with mean
+/// `load`, for a `pass` that reaches 1 once a word is full.
+fn expected_pass_rate(load: f64, pass: impl Fn(i32) -> f64) -> f64 {
+    let mut ln_pmf = -load;
+    let (mut rate, mut mass) = (0.0, 0.0);
+    // A few thousand writes set every bit of a word
+    for writes in 0..4096 {
+        let pmf = ln_pmf.exp();
+        rate += pmf * pass(writes);
+        mass += pmf;
+        ln_pmf += (load / (writes + 1) as f64).ln();
+    }
+    rate + (1.0 - mass).max(0.0)
+}
+
 /// Rounds up to the next power of 2.
 ///
 /// Used to ensure the total size is a power of 2 for efficient indexing.
@@ -256,6 +484,37 @@ fn round_up_pow2(mut x: u32) -> u32 {
     x + 1
 }
 
+/// Checks a filter holding the keys `0..num_keys`: it finds all of them, its
+/// estimated false-positive rate is within `tolerance` of the rate lookups of
+/// absent keys see, and it survives serialization.
+#[cfg(test)]
+fn check_filter<F: FilterStrategy>(filter: &F, num_keys: Key, tolerance: f64) {
+    assert_eq!(filter.num_entries(), num_keys as usize);
+    assert!((0..num_keys).all(|key| filter.may_contain(&key)));
+
+    let lookups = 200_000;
+    let false_positives = (num_keys..num_keys + lookups)
+        .filter(|key| filter.may_contain(key))
+        .count();
+    let measured = false_positives as f64 / lookups as f64;
+    let expected = filter.false_positive_rate();
+    assert!(
+        (measured - expected).abs() < expected * tolerance,
+        "measured {}, expected {}",
+        measured,
+        expected
+    );
+
+    // Serialization keeps the bits and the entry count
+    let bytes = filter.serialize().unwrap();
+    let restored = F::deserialize(&bytes).unwrap();
+    assert_eq!(restored.num_entries(), num_keys as usize);
+    assert!((0..num_keys).all(|key| restored.may_contain(&key)));
+    assert_eq!(restored.false_positive_rate(), expected);
+    assert!(F::deserialize(&bytes[..bytes.len() - 1]).is_err());
+    assert!(F::deserialize(&bytes[..12]).is_err());
+}
+
 impl FilterStrategy for Bloom {
     fn new(expected_entries: usize) -> Self {
         // Use 10 bits per entry and 6 hash functions as reasonable defaults
@@ -264,40 +523,49 @@ impl FilterStrategy for Bloom {
     }
 
     fn add(&mut self, key: &Key) -> Result<()> {
-        // Convert key to bytes and hash
-        let bytes = key.to_le_bytes();
-        let hash = xxh3_128(&bytes) as u32;
-        self.add_hash(hash);
+        self.add_hash(key_hash(key) as u32);
         Ok(())
     }
 
     fn may_contain(&self, key: &Key) -> bool {
-        let bytes = key.to_le_bytes();
-        let hash = xxh3_128(&bytes) as u32;
-        self.may_contain(hash)
+        self.may_contain(key_hash(key) as u32)
+    }
+
+    fn may_contain_hash(&self, _key: &Key, hash: u128) -> bool {
+        self.may_contain(hash as u32)
+    }
+
+    fn prefetch(&self, hash: u128) {
+        self.prefetch(hash as u32)
     }
 
     fn false_positive_rate(&self) -> f64 {
-        // Calculate using the direct theoretical rate
-        // For a Bloom filter with m bits and k hash functions for n items:
-        // fp_rate = (1 - e^(-kn/m))^k
-        // Where k = num_double_probes * 2 (since each probe sets 2 bits)
-        let k = self.num_double_probes * 2;
-        let n = 1; // We inserted 2 keys in test
-        let m = self.len as f64 * 64.0; // Convert words to bits
+        self.theoretical_fp_rate(self.num_entries())
+    }
+
+    fn num_entries(&self) -> usize {
+        Self::num_entries(self)
+    }
 
-        (1.0 - (-((k as f64 * n as f64) / m)).exp()).powi(k as i32)
+    fn memory_usage(&self) -> usize {
+        Self::memory_usage(self)
+    }
+
+    fn num_probes(&self) -> u32 {
+        // Each double probe checks two bits of a word
+        self.num_double_probes * 2
     }
 
     fn serialize(&self) -> Result<Vec<u8>> {
         // Calculate size needed for serialization
-        let header_size = 8; // 2 u32s: len and num_double_probes
+        let header_size = 16; // 2 u32s and a u64: len, num_double_probes and num_entries
         let data_size = self.data.len() * std::mem::size_of::<u64>();
         let mut bytes = Vec::with_capacity(header_size + data_size);
 
         // Write header
         bytes.extend_from_slice(&self.len.to_le_bytes());
         bytes.extend_from_slice(&self.num_double_probes.to_le_bytes());
+        bytes.extend_from_slice(&self.num_entries.load(Ordering::Relaxed).to_le_bytes());
 
         // Write data array
         for atomic in self.data.iter() {
@@ -311,7 +579,7 @@ impl FilterStrategy for Bloom {
     where
         Self: Sized,
     {
-        if bytes.len() < 8 {
+        if bytes.len() < 16 {
             return Err(Error::Serialization(
                 "Invalid buffer size for Bloom filter deserialization".to_string(),
             ));
@@ -320,12 +588,26 @@ impl FilterStrategy for Bloom {
         // Read header
         let len = u32::from_le_bytes(bytes[0..4].try_into().unwrap());
         let num_double_probes = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+        let num_entries = u64::from_le_bytes(bytes[8..16].try_into().unwrap());
+        if !(1..=MAX_PROBES.div_ceil(2)).contains(&num_double_probes) {
+            return Err(Error::Serialization(format!(
+                "Bloom filter with {} double probes is out of range",
+                num_double_probes
+            )));
+        }
 
         // Read data array
-        let data_size = (bytes.len() - 8) / std::mem::size_of::<u64>();
+        let data_size = (bytes.len() - 16) / std::mem::size_of::<u64>();
+        if bytes.len() - 16 != len as usize * 8 || !len.is_power_of_two() {
+            return Err(Error::Serialization(format!(
+                "Bloom filter of {} words doesn't match its {} byte buffer",
+                len,
+                bytes.len()
+            )));
+        }
         let mut data = Vec::with_capacity(data_size);
 
-        let mut offset = 8;
+        let mut offset = 16;
         for _ in 0..data_size {
             let value = u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
             data.push(AtomicU64::new(value));
@@ -336,6 +618,7 @@ impl FilterStrategy for Bloom {
             len,
             num_double_probes,
             data: data.into_boxed_slice(),
+            num_entries: AtomicU64::new(num_entries),
         })
     }
 }
@@ -353,6 +636,77 @@ mod tests {
         (product >> 32) as u32
     }
 
+    #[test]
+    fn test_params_from_error_rate() {
+        let params = BloomParams::from_error_rate(0.01);
+        assert!((params.bits_per_key - 9.585).abs() < 0.01);
+        assert_eq!(params.num_probes, 6);
+        assert_eq!(params.total_bits(1000), 9586);
+        assert_eq!(params.to_string(), "9.59 bits per key, 6 probes");
+
+        // Filters that can use more probes get the optimal count
+        assert_eq!(params.with_kind(FilterKind::RocksDbLocal).num_probes, 7);
+        assert_eq!(params.with_kind(FilterKind::Bloom), params);
+
+        // Tighter rates cost more memory, up to the probe limit
+        let tight = BloomParams::from_error_rate(0.0001);
+        assert!(tight.bits_per_key > 19.0);
+        assert_eq!(tight.num_probes, MAX_BLOOM_PROBES);
+        let tight = tight.with_kind(FilterKind::SpeedDbDynamic);
+        assert_eq!(tight.num_probes, MAX_PROBES);
+
+        // Loose rates still get a usable filter
+        let loose = BloomParams::from_error_rate(0.9);
+        assert_eq!(loose.bits_per_key, 1.0);
+        assert_eq!(loose.num_probes, 1);
+    }
+
+    #[test]
+    fn test_filter_strategy() {
+        // The closed form leaves out how neighbouring probes overlap
+        for (bits_per_key, num_probes) in [(4, 2), (6, 4), (10, 6)] {
+            let num_keys = 10_000;
+            let mut bloom = Bloom::new(bits_per_key * num_keys as u32, num_probes);
+            for key in 0..num_keys {
+                FilterStrategy::add(&mut bloom, &key).unwrap();
+            }
+            check_filter(&bloom, num_keys, 0.25);
+        }
+
+        // An empty filter lets nothing through
+        assert_eq!(Bloom::new(1024, 6).theoretical_fp_rate(0), 0.0);
+    }
+
+    #[test]
+    fn test_probe_count_bounds() {
+        // A filter always probes at least one word
+        assert_eq!(FilterStrategy::num_probes(&Bloom::new(1024, 0)), 2);
+
+        // Headers with more double probes than the filter supports are
+        // rejected
+        let mut bytes = Bloom::new(1024, 6).serialize().unwrap();
+        bytes[4..8].copy_from_slice(&16u32.to_le_bytes());
+        assert!(Bloom::deserialize(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_filter_kinds() {
+        for kind in FilterKind::ALL {
+            assert_eq!(FilterKind::from_name(kind.name()), Some(kind));
+            assert_eq!(FilterKind::from_id(kind.id()), Some(kind));
+
+            // Every kind builds a filter that survives a round trip
+            let params = BloomParams::default().with_kind(kind);
+            let keys: Vec<Key> = (0..100).collect();
+            let filter = kind.build(&params, &keys);
+            let restored = kind.deserialize(&filter.serialize().unwrap()).unwrap();
+            assert!((0..100).all(|key| restored.may_contain(&key)));
+            assert_eq!(restored.false_positive_rate(), filter.false_positive_rate());
+        }
+        assert_eq!(FilterKind::from_name("cuckoo"), None);
+        assert_eq!(FilterKind::from_id(4), None);
+    }
+
     #[test]
     fn test_empty_filter() {
         let bloom = Bloom::new(100, 2);
@@ -446,7 +800,7 @@ mod tests {
                 let bit2 = (h >> 6) & 63;
                 bit_counts[bit1 as usize] += 1;
                 bit_counts[bit2 as usize] += 1;
-                h = (h >> 12) | (h << 52);
+                h = h.rotate_right(12);
             }
         }
 
@@ -670,7 +1024,7 @@ mod tests {
     #[test]
     fn test_memory_efficiency() {
         for bits_per_key in [10, 20, 40] {
-            let num_entries = 100_000;
+            let num_entries: i32 = 100_000;
             let total_bits = num_entries * bits_per_key;
 
             // Create filter with same parameters as SpeedDB test
@@ -678,17 +1032,17 @@ mod tests {
 
             // Add entries using SpeedDB's key pattern
             for i in 0..num_entries {
-                let key_bytes = (i as i32).to_le_bytes();
+                let key_bytes = i.to_le_bytes();
                 let hash = xxh3_128(&key_bytes);
                 bloom.add_hash(hash as u32); // Use lower 32 bits
             }
 
             // Test FP rate using SpeedDB's exact test pattern - 10000 keys offset by 1000000000
             let mut false_positives = 0;
-            let test_entries = 10_000; // Exact number SpeedDB uses
+            let test_entries: i32 = 10_000; // Exact number SpeedDB uses
 
             for i in 0..test_entries {
-                let key_bytes = (i as i32 + 1_000_000_000).to_le_bytes();
+                let key_bytes = (i + 1_000_000_000).to_le_bytes();
                 let hash = xxh3_128(&key_bytes);
                 if bloom.may_contain(hash as u32) {
                     false_positives += 1;
@@ -716,14 +1070,14 @@ mod tests {
 
             // Add test items
             for i in 0..size / 10 {
-                bloom.add_hash(i as u32);
+                bloom.add_hash(i);
             }
 
             // Measure FP rate
             let mut fps = 0;
             let trials = 10000;
             for i in size..size + trials {
-                if bloom.may_contain(i as u32) {
+                if bloom.may_contain(i) {
                     fps += 1;
                 }
             }
@@ -743,14 +1097,14 @@ mod tests {
             // Measure insert time
             let start = Instant::now();
             for i in 0..size / 10 {
-                bloom.add_hash(i as u32);
+                bloom.add_hash(i);
             }
             let insert_time = start.elapsed();
 
             // Measure lookup time
             let start = Instant::now();
             for i in 0..size / 10 {
-                bloom.may_contain(i as u32);
+                bloom.may_contain(i);
             }
             let lookup_time = start.elapsed();
 
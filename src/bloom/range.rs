@@ -0,0 +1,230 @@
+use super::{BloomParams, FilterKind, RocksDBLocalBloom};
+use crate::run::Error;
+use crate::run::{FilterStrategy, Result};
+use crate::types::Key;
+use xxhash_rust::xxh3::xxh3_64_with_seed;
+
+/// Default number of prefix levels above the keys themselves, so aligned
+/// ranges of up to 16 keys are answered with a single probe.
+pub const DEFAULT_RANGE_FILTER_LEVELS: u32 = 4;
+
+/// Queries spanning more than this many of the widest stored ranges are
+/// let through rather than probed piece by piece.
+const MAX_TOP_LEVEL_RANGES: u64 = 8;
+
+/// How large a run's range filter is and how long the ranges it answers are.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RangeFilterParams {
+    /// Filter memory per key, shared by all prefix levels; each level needs
+    /// about as much as a point filter would for the same accuracy
+    pub bits_per_key: f64,
+    /// Key prefixes `0..=levels` bits shorter than the keys are stored, so
+    /// ranges up to about `2^(levels + 3)` keys wide can be ruled out
+    pub levels: u32,
+}
+
+impl RangeFilterParams {
+    pub fn new(bits_per_key: f64) -> Self {
+        Self {
+            bits_per_key,
+            levels: DEFAULT_RANGE_FILTER_LEVELS,
+        }
+    }
+}
+
+impl std::fmt::Display for RangeFilterParams {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:.2} bits per key over {} prefix levels",
+            self.bits_per_key, self.levels
+        )
+    }
+}
+
+/// A prefix Bloom filter for range queries, after Rosetta (Luo et al., 2020).
+///
+/// Every key is added along with its prefixes one to `levels` bits shorter,
+/// each standing for the aligned range of keys that share it. A query is
+/// split into the fewest such ranges; any whose prefix is present is
+/// followed down through its halves until a whole key is found, which keeps
+/// false positives at higher levels from adding up.
+pub struct RangeFilter {
+    levels: u32,
+    bloom: RocksDBLocalBloom,
+}
+
+impl RangeFilter {
+    /// Builds a filter over `keys`, which must be sorted.
+    pub fn build(keys: &[Key], params: &RangeFilterParams) -> Self {
+        let levels = params.levels.min(63);
+        let mut hashes = Vec::with_capacity(keys.len() * (levels as usize + 1));
+        for level in 0..=levels {
+            let mut previous = None;
+            for &key in keys {
+                // Sorted keys share each prefix with their neighbours
+                let prefix = ordered(key) >> level;
+                if previous != Some(prefix) {
+                    hashes.push(prefix_hash(prefix, level));
+                    previous = Some(prefix);
+                }
+            }
+        }
+
+        let total_bits = (keys.len() as f64 * params.bits_per_key).ceil();
+        let num_probes = BloomParams::from_bits_per_key(total_bits / hashes.len().max(1) as f64)
+            .with_kind(FilterKind::RocksDbLocal)
+            .num_probes;
+        let bloom = RocksDBLocalBloom::new((total_bits as u32).max(1), num_probes);
+        for (h1, h2) in hashes {
+            bloom.add_hash(h1, h2);
+        }
+        Self { levels, bloom }
+    }
+
+    /// Whether any key in `[start, end)` may have been added.
+    pub fn may_contain_range(&self, start: Key, end: Key) -> bool {
+        if start >= end {
+            return false;
+        }
+        let (mut low, high) = (ordered(start), ordered(end - 1));
+        if (high - low) >> self.levels >= MAX_TOP_LEVEL_RANGES {
+            return true;
+        }
+
+        loop {
+            // The widest aligned range starting at `low` that stays in bounds
+            let mut level = low.trailing_zeros().min(self.levels);
+            while level > 0 && high - low < (1 << level) - 1 {
+                level -= 1;
+            }
+            if self.probe(low >> level, level) {
+                return true;
+            }
+            let last = low + ((1 << level) - 1);
+            if last >= high {
+                return false;
+            }
+            low = last + 1;
+        }
+    }
+
+    fn probe(&self, prefix: u64, level: u32) -> bool {
+        let (h1, h2) = prefix_hash(prefix, level);
+        if !self.bloom.may_contain(h1, h2) {
+            return false;
+        }
+        level == 0 || self.probe(prefix << 1, level - 1) || self.probe((prefix << 1) | 1, level - 1)
+    }
+
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        let mut bytes = self.levels.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&self.bloom.serialize()?);
+        Ok(bytes)
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < 4 {
+            return Err(Error::Serialization(
+                "Invalid buffer size for range filter deserialization".to_string(),
+            ));
+        }
+        let levels = u32::from_le_bytes(bytes[0..4].try_into().unwrap());
+        if levels > 63 {
+            return Err(Error::Serialization(format!(
+                "Range filter with {} prefix levels",
+                levels
+            )));
+        }
+        Ok(Self {
+            levels,
+            bloom: RocksDBLocalBloom::deserialize(&bytes[4..])?,
+        })
+    }
+}
+
+/// Maps keys to unsigned integers in the same order, so prefixes of the
+/// result cover contiguous key ranges.
+fn ordered(key: Key) -> u64 {
+    (key as u64) ^ (1 << 63)
+}
+
+fn prefix_hash(prefix: u64, level: u32) -> (u32, u32) {
+    let hash = xxh3_64_with_seed(&prefix.to_le_bytes(), level as u64);
+    (hash as u32, (hash >> 32) as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build(keys: &[Key]) -> RangeFilter {
+        RangeFilter::build(keys, &RangeFilterParams::new(32.0))
+    }
+
+    #[test]
+    fn test_no_false_negatives() {
+        let keys: Vec<Key> = (-500..500).map(|i| i * 1_000 + 17).collect();
+        let filter = build(&keys);
+        for &key in &keys {
+            assert!(filter.may_contain_range(key, key + 1));
+            assert!(filter.may_contain_range(key - 5, key + 5));
+            assert!(filter.may_contain_range(key - 300, key + 1));
+        }
+        // Ranges too wide to split up are let through
+        assert!(filter.may_contain_range(i64::MIN, i64::MAX));
+        assert!(!filter.may_contain_range(5, 5));
+    }
+
+    #[test]
+    fn test_short_empty_ranges() {
+        let keys: Vec<Key> = (0..10_000).map(|i| i * 1_000).collect();
+        let filter = build(&keys);
+
+        // Gaps between keys, at various offsets and widths
+        let queries = (0..10_000).flat_map(|i| {
+            let start = i * 1_000 + 1 + (i * 37) % 400;
+            [
+                (start, start + 1),
+                (start, start + 16),
+                (start, start + 100),
+            ]
+        });
+        let (total, passed) = queries.fold((0, 0), |(total, passed), (start, end)| {
+            (
+                total + 1,
+                passed + filter.may_contain_range(start, end) as usize,
+            )
+        });
+        let rate = passed as f64 / total as f64;
+        assert!(rate < 0.1, "{} of {} empty ranges passed", passed, total);
+    }
+
+    #[test]
+    fn test_extreme_keys() {
+        let keys = [i64::MIN, -1, 0, i64::MAX];
+        let filter = build(&keys);
+        assert!(filter.may_contain_range(i64::MIN, i64::MIN + 1));
+        // Ranges are half-open, so the largest key is never inside one
+        assert!(!filter.may_contain_range(i64::MAX - 10, i64::MAX));
+        assert!(filter.may_contain_range(-3, 2));
+        assert!(!filter.may_contain_range(1, 40));
+        assert!(!build(&[]).may_contain_range(0, 10));
+    }
+
+    #[test]
+    fn test_serialization() {
+        let keys: Vec<Key> = (0..1_000).map(|i| i * 50).collect();
+        let filter = build(&keys);
+        let bytes = filter.serialize().unwrap();
+        let restored = RangeFilter::deserialize(&bytes).unwrap();
+        for start in (0..50_000).step_by(7) {
+            assert_eq!(
+                restored.may_contain_range(start, start + 10),
+                filter.may_contain_range(start, start + 10)
+            );
+        }
+        assert!(RangeFilter::deserialize(&bytes[..2]).is_err());
+        assert!(RangeFilter::deserialize(&bytes[..bytes.len() - 8]).is_err());
+    }
+}
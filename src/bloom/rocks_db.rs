@@ -1,3 +1,6 @@
+use super::{BloomParams, FilterKind};
+use crate::run::{key_hash, Error, FilterStrategy, Result};
+use crate::types::Key;
 use std::sync::atomic::{AtomicU64, Ordering};
 
 /// Direct port of RocksDB's FastLocalBloomImpl to Rust
@@ -5,6 +8,7 @@ pub struct RocksDBLocalBloom {
     len: u32,               // Length in 64-bit words
     num_probes: u32,        // Number of probes per key
     data: Box<[AtomicU64]>, // The bit array backing the filter
+    num_entries: AtomicU64, // Number of hashes added so far
 }
 
 impl RocksDBLocalBloom {
@@ -15,9 +19,9 @@ impl RocksDBLocalBloom {
         const CACHE_LINE_BITS: u32 = 512;
         const BITS_PER_WORD: u32 = 64;
 
-        // Round up total_bits to cache line size
+        // Round up total_bits to cache line size, keeping at least one line
         let block_bits = CACHE_LINE_BITS;
-        let blocks = (total_bits + block_bits - 1) / block_bits;
+        let blocks = total_bits.div_ceil(block_bits).max(1);
         let len = blocks * (block_bits / BITS_PER_WORD);
 
         let mut data = Vec::with_capacity(len as usize);
@@ -27,6 +31,7 @@ impl RocksDBLocalBloom {
             len,
             num_probes,
             data: data.into_boxed_slice(),
+            num_entries: AtomicU64::new(0),
         }
     }
 
@@ -44,6 +49,7 @@ impl RocksDBLocalBloom {
 
     #[inline]
     fn add_hash_prepared(&self, mut h2: u32, byte_offset: usize) {
+        self.num_entries.fetch_add(1, Ordering::Relaxed);
         for _ in 0..self.num_probes {
             // Extract bit position from top 9 bits (as per RocksDB)
             let bitpos = h2 >> (32 - 9);
@@ -104,19 +110,147 @@ impl RocksDBLocalBloom {
     #[cfg(not(target_arch = "x86_64"))]
     pub fn prefetch(&self, _h1: u32) {}
 
+    /// Number of hashes added to the filter, duplicates included.
+    pub fn num_entries(&self) -> usize {
+        self.num_entries.load(Ordering::Relaxed) as usize
+    }
+
+    /// Theoretical false positive rate once `num_entries` hashes have been
+    /// added.
+    ///
+    /// Each hash sets all its probes within one 512-bit cache line, so the
+    /// number of hashes sharing a line is Poisson distributed; a lookup passes
+    /// if all of its probes land on set bits of its line.
+    pub fn theoretical_fp_rate(&self, num_entries: usize) -> f64 {
+        let load = num_entries as f64 / (self.len / 8) as f64;
+        let probes = self.num_probes as i32;
+
+        // Inclusion-exclusion over the probed bits left unset, with
+        // E[x^j] = e^(load * (x - 1)) for the Poisson distributed count j
+        let mut rate = 0.0;
+        let mut choose = 1.0;
+        for unset in 0..=probes {
+            let missed = (1.0 - unset as f64 / 512.0).powi(probes);
+            let sign = if unset % 2 == 0 { 1.0 } else { -1.0 };
+            rate += sign * choose * (load * (missed - 1.0)).exp();
+            choose = choose * (probes - unset) as f64 / (unset + 1) as f64;
+        }
+        rate.clamp(0.0, 1.0)
+    }
+
     pub fn memory_usage(&self) -> usize {
         self.data.len() * std::mem::size_of::<AtomicU64>()
     }
 }
 
+/// Splits a key's 128-bit hash into the cache line and probe hashes.
+fn split_hash(hash: u128) -> (u32, u32) {
+    (hash as u32, (hash >> 32) as u32)
+}
+
+impl FilterStrategy for RocksDBLocalBloom {
+    fn new(expected_entries: usize) -> Self {
+        let params = BloomParams::default().with_kind(FilterKind::RocksDbLocal);
+        RocksDBLocalBloom::new(params.total_bits(expected_entries), params.num_probes)
+    }
+
+    fn add(&mut self, key: &Key) -> Result<()> {
+        let (h1, h2) = split_hash(key_hash(key));
+        self.add_hash(h1, h2);
+        Ok(())
+    }
+
+    fn may_contain(&self, key: &Key) -> bool {
+        let (h1, h2) = split_hash(key_hash(key));
+        self.may_contain(h1, h2)
+    }
+
+    fn may_contain_hash(&self, _key: &Key, hash: u128) -> bool {
+        let (h1, h2) = split_hash(hash);
+        self.may_contain(h1, h2)
+    }
+
+    fn prefetch(&self, hash: u128) {
+        self.prefetch(hash as u32)
+    }
+
+    fn false_positive_rate(&self) -> f64 {
+        self.theoretical_fp_rate(self.num_entries())
+    }
+
+    fn num_entries(&self) -> usize {
+        Self::num_entries(self)
+    }
+
+    fn memory_usage(&self) -> usize {
+        Self::memory_usage(self)
+    }
+
+    fn num_probes(&self) -> u32 {
+        self.num_probes
+    }
+
+    fn serialize(&self) -> Result<Vec<u8>> {
+        // Header of len, num_probes and num_entries, then the bit array
+        let mut bytes = Vec::with_capacity(16 + self.data.len() * 8);
+        bytes.extend_from_slice(&self.len.to_le_bytes());
+        bytes.extend_from_slice(&self.num_probes.to_le_bytes());
+        bytes.extend_from_slice(&self.num_entries.load(Ordering::Relaxed).to_le_bytes());
+        for word in self.data.iter() {
+            bytes.extend_from_slice(&word.load(Ordering::Relaxed).to_le_bytes());
+        }
+        Ok(bytes)
+    }
+
+    fn deserialize(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < 16 {
+            return Err(Error::Serialization(
+                "Invalid buffer size for RocksDB Bloom filter deserialization".to_string(),
+            ));
+        }
+        let len = u32::from_le_bytes(bytes[0..4].try_into().unwrap());
+        let num_probes = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+        let num_entries = u64::from_le_bytes(bytes[8..16].try_into().unwrap());
+        if bytes.len() - 16 != len as usize * 8 || len == 0 || len % 8 != 0 {
+            return Err(Error::Serialization(format!(
+                "RocksDB Bloom filter of {} words doesn't match its {} byte buffer",
+                len,
+                bytes.len()
+            )));
+        }
+
+        let data = bytes[16..]
+            .chunks_exact(8)
+            .map(|word| AtomicU64::new(u64::from_le_bytes(word.try_into().unwrap())))
+            .collect();
+        Ok(Self {
+            len,
+            num_probes,
+            data,
+            num_entries: AtomicU64::new(num_entries),
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::bloom::check_filter;
     use std::sync::Arc;
     use std::thread;
     use std::time::Instant;
     use xxhash_rust::xxh3::xxh3_128;
 
+    #[test]
+    fn test_filter_strategy() {
+        let num_keys = 10_000;
+        let mut bloom = RocksDBLocalBloom::new(10 * num_keys as u32, 6);
+        for key in 0..num_keys {
+            FilterStrategy::add(&mut bloom, &key).unwrap();
+        }
+        check_filter(&bloom, num_keys, 0.15);
+    }
+
     #[test]
     fn test_empty_filter() {
         let bloom = RocksDBLocalBloom::new(100, 2);
@@ -197,24 +331,24 @@ mod tests {
     #[test]
     fn test_memory_efficiency() {
         for bits_per_key in [10, 20, 40] {
-            let num_entries = 100_000;
+            let num_entries: i32 = 100_000;
             let total_bits = num_entries * bits_per_key;
 
             let bloom = RocksDBLocalBloom::new(total_bits as u32, 6);
 
             // Add entries
             for i in 0..num_entries {
-                let key_bytes = (i as i32).to_le_bytes();
+                let key_bytes = i.to_le_bytes();
                 let hash = xxh3_128(&key_bytes);
                 bloom.add_hash(hash as u32, (hash >> 32) as u32);
             }
 
             // Test false positive rate
             let mut false_positives = 0;
-            let test_entries = 10_000;
+            let test_entries: i32 = 10_000;
 
             for i in 0..test_entries {
-                let key_bytes = (i as i32 + 1_000_000_000).to_le_bytes();
+                let key_bytes = (i + 1_000_000_000).to_le_bytes();
                 let hash = xxh3_128(&key_bytes);
                 if bloom.may_contain(hash as u32, (hash >> 32) as u32) {
                     false_positives += 1;
@@ -1,19 +1,23 @@
+use super::{expected_pass_rate, BloomParams, FilterKind, MAX_PROBES};
+use crate::run::{key_hash, Error, FilterStrategy, Result};
+use crate::types::Key;
 use std::sync::atomic::{AtomicU64, Ordering};
 
 pub struct SpeedDbDynamicBloom {
     len: u32, // Length in 64-bit words
     num_double_probes: u32,
     data: Box<[AtomicU64]>,
+    num_entries: AtomicU64, // Number of hashes added so far
 }
 
 impl SpeedDbDynamicBloom {
     pub fn new(total_bits: u32, num_probes: u32) -> Self {
         // Same as before - this part looks correct
         assert!(num_probes <= 10);
-        let num_double_probes = (num_probes + u32::from(num_probes == 1)) / 2;
+        let num_double_probes = ((num_probes + u32::from(num_probes == 1)) / 2).max(1);
         let block_bytes = 8 * std::cmp::max(1, round_up_pow2(num_double_probes));
         let block_bits = block_bytes * 8;
-        let blocks = (total_bits + block_bits - 1) / block_bits;
+        let blocks = total_bits.div_ceil(block_bits).max(1);
         let sz = blocks * block_bytes;
         let len = sz / 8;
 
@@ -24,6 +28,7 @@ impl SpeedDbDynamicBloom {
             len,
             num_double_probes,
             data: data.into_boxed_slice(),
+            num_entries: AtomicU64::new(0),
         }
     }
 
@@ -42,7 +47,7 @@ impl SpeedDbDynamicBloom {
             } else if (val & mask) != mask {
                 return false;
             }
-            h = (h >> 12) | (h << 52);
+            h = h.rotate_right(12);
         }
         unreachable!()
     }
@@ -53,6 +58,7 @@ impl SpeedDbDynamicBloom {
         F: Fn(&AtomicU64, u64),
     {
         let mut h = 0x9e3779b97f4a7c13u64.wrapping_mul(h32 as u64);
+        self.num_entries.fetch_add(1, Ordering::Relaxed);
 
         for i in 0.. {
             // Two bit probes per uint64_t probe
@@ -62,7 +68,7 @@ impl SpeedDbDynamicBloom {
             if i + 1 >= self.num_double_probes as usize {
                 return;
             }
-            h = (h >> 12) | (h << 52);
+            h = h.rotate_right(12);
         }
     }
 
@@ -110,9 +116,37 @@ impl SpeedDbDynamicBloom {
     #[cfg(not(target_arch = "x86_64"))]
     pub fn prefetch(&self, _h32: u32) {}
 
+    /// Number of hashes added to the filter, duplicates included.
+    pub fn num_entries(&self) -> usize {
+        self.num_entries.load(Ordering::Relaxed) as usize
+    }
+
+    /// Theoretical false positive rate once `num_entries` hashes have been
+    /// added.
+    ///
+    /// A hash probes `num_double_probes` words of one aligned block, two bits
+    /// in each, so the number of hashes sharing a block is Poisson
+    /// distributed. When the probes don't fill the block, a hash reaches any
+    /// given word of it with probability `probes / block_words`.
     pub fn theoretical_fp_rate(&self, num_entries: usize) -> f64 {
-        let bits_per_key = (self.len * 64) as f64 / num_entries as f64;
-        (1.0 - std::f64::consts::E.powf(-bits_per_key * 0.7)).powi(6)
+        let probes = self.num_double_probes as i32;
+        let block_words = round_up_pow2(self.num_double_probes);
+        let load = num_entries as f64 * block_words as f64 / self.len as f64;
+        let reach = probes as f64 / block_words as f64;
+
+        // Chance that a hash in the block leaves one given bit of a probed
+        // word unset, and two of them
+        let one = 1.0 - reach * (1.0 - (63.0f64 / 64.0).powi(2));
+        let two = 1.0 - reach * (1.0 - (62.0f64 / 64.0).powi(2));
+        // A probe passes with chance 1 - (127/64) one^j + (63/64) two^j for
+        // `j` hashes in the block, its two bits coinciding 1 time in 64
+        let blocked = expected_pass_rate(load, |j| {
+            (1.0 - 127.0 / 64.0 * one.powi(j) + 63.0 / 64.0 * two.powi(j)).powi(probes)
+        });
+
+        // Keys whose 32-bit hashes collide with an added one always pass
+        let collision = 1.0 - (1.0 - 0.5f64.powi(32)).powf(num_entries as f64);
+        collision + (1.0 - collision) * blocked.clamp(0.0, 1.0)
     }
 
     pub fn memory_usage(&self) -> usize {
@@ -120,6 +154,95 @@ impl SpeedDbDynamicBloom {
     }
 }
 
+impl FilterStrategy for SpeedDbDynamicBloom {
+    fn new(expected_entries: usize) -> Self {
+        let params = BloomParams::default().with_kind(FilterKind::SpeedDbDynamic);
+        SpeedDbDynamicBloom::new(params.total_bits(expected_entries), params.num_probes)
+    }
+
+    fn add(&mut self, key: &Key) -> Result<()> {
+        self.add_hash(key_hash(key) as u32);
+        Ok(())
+    }
+
+    fn may_contain(&self, key: &Key) -> bool {
+        self.may_contain(key_hash(key) as u32)
+    }
+
+    fn may_contain_hash(&self, _key: &Key, hash: u128) -> bool {
+        self.may_contain(hash as u32)
+    }
+
+    fn prefetch(&self, hash: u128) {
+        self.prefetch(hash as u32)
+    }
+
+    fn false_positive_rate(&self) -> f64 {
+        self.theoretical_fp_rate(self.num_entries())
+    }
+
+    fn num_entries(&self) -> usize {
+        Self::num_entries(self)
+    }
+
+    fn memory_usage(&self) -> usize {
+        Self::memory_usage(self)
+    }
+
+    fn num_probes(&self) -> u32 {
+        self.num_double_probes * 2
+    }
+
+    fn serialize(&self) -> Result<Vec<u8>> {
+        // Header of len, num_double_probes and num_entries, then the bit array
+        let mut bytes = Vec::with_capacity(16 + self.data.len() * 8);
+        bytes.extend_from_slice(&self.len.to_le_bytes());
+        bytes.extend_from_slice(&self.num_double_probes.to_le_bytes());
+        bytes.extend_from_slice(&self.num_entries.load(Ordering::Relaxed).to_le_bytes());
+        for word in self.data.iter() {
+            bytes.extend_from_slice(&word.load(Ordering::Relaxed).to_le_bytes());
+        }
+        Ok(bytes)
+    }
+
+    fn deserialize(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < 16 {
+            return Err(Error::Serialization(
+                "Invalid buffer size for SpeedDB Bloom filter deserialization".to_string(),
+            ));
+        }
+        let len = u32::from_le_bytes(bytes[0..4].try_into().unwrap());
+        let num_double_probes = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+        let num_entries = u64::from_le_bytes(bytes[8..16].try_into().unwrap());
+        if !(1..=MAX_PROBES.div_ceil(2)).contains(&num_double_probes) {
+            return Err(Error::Serialization(format!(
+                "SpeedDB Bloom filter with {} double probes is out of range",
+                num_double_probes
+            )));
+        }
+        // Probes stay within a block, so the array must hold whole blocks
+        let block_words = round_up_pow2(num_double_probes);
+        if bytes.len() - 16 != len as usize * 8 || len == 0 || len % block_words != 0 {
+            return Err(Error::Serialization(format!(
+                "SpeedDB Bloom filter of {} words doesn't match its {} byte buffer",
+                len,
+                bytes.len()
+            )));
+        }
+
+        let data = bytes[16..]
+            .chunks_exact(8)
+            .map(|word| AtomicU64::new(u64::from_le_bytes(word.try_into().unwrap())))
+            .collect();
+        Ok(Self {
+            len,
+            num_double_probes,
+            data,
+            num_entries: AtomicU64::new(num_entries),
+        })
+    }
+}
+
 #[inline]
 fn round_up_pow2(mut x: u32) -> u32 {
     if x == 0 {
@@ -142,9 +265,26 @@ fn fast_range32(hash: u32, n: u32) -> u32 {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::bloom::check_filter;
     use std::hash::{DefaultHasher, Hash, Hasher};
     use xxhash_rust::xxh3::xxh3_128;
 
+    #[test]
+    fn test_filter_strategy() {
+        let num_keys = 10_000;
+        let mut bloom = SpeedDbDynamicBloom::new(10 * num_keys as u32, 6);
+        for key in 0..num_keys {
+            FilterStrategy::add(&mut bloom, &key).unwrap();
+        }
+        check_filter(&bloom, num_keys, 0.15);
+
+        // Headers with more double probes than the filter supports are
+        // rejected
+        let mut bytes = bloom.serialize().unwrap();
+        bytes[4..8].copy_from_slice(&16u32.to_le_bytes());
+        assert!(SpeedDbDynamicBloom::deserialize(&bytes).is_err());
+    }
+
     #[test]
     fn test_empty_filter() {
         let bloom = SpeedDbDynamicBloom::new(100, 2);
@@ -238,7 +378,7 @@ mod tests {
                 let bit2 = (h >> 6) & 63;
                 bit_counts[bit1 as usize] += 1;
                 bit_counts[bit2 as usize] += 1;
-                h = (h >> 12) | (h << 52);
+                h = h.rotate_right(12);
             }
         }
 
@@ -462,7 +602,7 @@ mod tests {
     #[test]
     fn test_memory_efficiency() {
         for bits_per_key in [10, 20, 40] {
-            let num_entries = 100_000;
+            let num_entries: i32 = 100_000;
             let total_bits = num_entries * bits_per_key;
 
             // Create filter with same parameters as SpeedDB test
@@ -470,17 +610,17 @@ mod tests {
 
             // Add entries using SpeedDB's key pattern
             for i in 0..num_entries {
-                let key_bytes = (i as i32).to_le_bytes();
+                let key_bytes = i.to_le_bytes();
                 let hash = xxh3_128(&key_bytes);
                 bloom.add_hash(hash as u32); // Use lower 32 bits
             }
 
             // Test FP rate using SpeedDB's exact test pattern - 10000 keys offset by 1000000000
             let mut false_positives = 0;
-            let test_entries = 10_000; // Exact number SpeedDB uses
+            let test_entries: i32 = 10_000; // Exact number SpeedDB uses
 
             for i in 0..test_entries {
-                let key_bytes = (i as i32 + 1_000_000_000).to_le_bytes();
+                let key_bytes = (i + 1_000_000_000).to_le_bytes();
                 let hash = xxh3_128(&key_bytes);
                 if bloom.may_contain(hash as u32) {
                     false_positives += 1;
@@ -4,10 +4,14 @@ use crate::types::{Key, Value};
 pub enum Command {
     Put(Key, Value),
     Get(Key),
+    /// `g` with several keys, looked up as one batch
+    MultiGet(Vec<Key>),
     Range(Key, Key),
     Delete(Key),
     Load(String),
     PrintStats,
+    /// `s summary`: the counts of `s` without the dump of every pair
+    PrintSummary,
     Quit,
 }
 
@@ -27,12 +31,14 @@ impl Command {
                 Some(Command::Put(key, value))
             }
             "g" => {
-                let key = parts.next()?.parse().ok()?;
-                if parts.next().is_some() {
-                    eprintln!("Extra parts in Get command: {}", input);
-                    return None;
+                let keys = parts
+                    .map(|part| part.parse().ok())
+                    .collect::<Option<Vec<Key>>>()?;
+                match keys[..] {
+                    [] => None,
+                    [key] => Some(Command::Get(key)),
+                    _ => Some(Command::MultiGet(keys)),
                 }
-                Some(Command::Get(key))
             }
             "r" => {
                 let start = parts.next()?.parse().ok()?;
@@ -60,11 +66,23 @@ impl Command {
                 Some(Command::Load(filename.trim_matches('"').to_string()))
             }
             "s" => {
+                let summary = match parts.next() {
+                    None => false,
+                    Some("summary") => true,
+                    Some(_) => {
+                        eprintln!("Extra parts in PrintStats command: {}", input);
+                        return None;
+                    }
+                };
                 if parts.next().is_some() {
                     eprintln!("Extra parts in PrintStats command: {}", input);
                     return None;
                 }
-                Some(Command::PrintStats)
+                if summary {
+                    Some(Command::PrintSummary)
+                } else {
+                    Some(Command::PrintStats)
+                }
             }
             "q" => {
                 if parts.next().is_some() {
@@ -102,6 +120,15 @@ mod tests {
         assert_eq!(Command::parse("g 10 extra"), None);
     }
 
+    #[test]
+    fn test_multi_get_command() {
+        assert_eq!(
+            Command::parse("g 10 -3 10"),
+            Some(Command::MultiGet(vec![10, -3, 10]))
+        );
+        assert_eq!(Command::parse("g 10 x 20"), None);
+    }
+
     #[test]
     fn test_range_command() {
         assert!(matches!(Command::parse("r 10 20"), Some(Command::Range(10, 20))));
@@ -136,6 +163,8 @@ mod tests {
     fn test_print_stats_command() {
         assert!(matches!(Command::parse("s"), Some(Command::PrintStats)));
         assert_eq!(Command::parse("s extra"), None);
+        assert_eq!(Command::parse("s summary"), Some(Command::PrintSummary));
+        assert_eq!(Command::parse("s summary extra"), None);
     }
 
     #[test]
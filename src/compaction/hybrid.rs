@@ -0,0 +1,163 @@
+use super::{is_idle, level_capacity, Compaction, CompactionPolicy, LevelState};
+
+/// Dostoevsky-style policy bounding the number of runs per level separately
+/// for the upper levels (`K`) and the largest level (`Z`).
+///
+/// `K = Z = 1` behaves like leveling and `K = Z = fanout - 1` like tiering;
+/// anything in between trades write amplification in the upper levels
+/// against lookup and space cost in the largest one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hybrid {
+    upper_runs: usize,
+    last_runs: usize,
+}
+
+impl Hybrid {
+    /// Allows up to `upper_runs` runs in each upper level and `last_runs` in
+    /// the largest level.
+    pub fn new(upper_runs: usize, last_runs: usize) -> Self {
+        assert!(
+            upper_runs >= 1 && last_runs >= 1,
+            "Run limits must be positive"
+        );
+        Self {
+            upper_runs,
+            last_runs,
+        }
+    }
+
+    fn run_limit(&self, index: usize, last: usize) -> usize {
+        if index >= last {
+            self.last_runs
+        } else {
+            self.upper_runs
+        }
+    }
+}
+
+impl CompactionPolicy for Hybrid {
+    fn name(&self) -> &'static str {
+        "hybrid"
+    }
+
+    fn pick(
+        &self,
+        levels: &[LevelState],
+        buffer_capacity: usize,
+        fanout: usize,
+    ) -> Option<Compaction> {
+        let last = levels.len().checked_sub(1)?;
+        for (index, level) in levels.iter().enumerate() {
+            if level.compacting {
+                continue;
+            }
+            let over_capacity = level.len() > level_capacity(index, buffer_capacity, fanout);
+            let too_many_runs = level.runs.len() > self.run_limit(index, last);
+
+            if over_capacity || (index < last && too_many_runs) {
+                if !is_idle(levels, index + 1) {
+                    continue;
+                }
+                // Merge straight into the target's runs if one more run would
+                // push it over its own limit
+                let target_runs = levels.get(index + 1).map_or(0, |level| level.runs.len());
+                let merge_target = target_runs >= self.run_limit(index + 1, last);
+                return Some(Compaction::whole_level(
+                    levels,
+                    index,
+                    index + 1,
+                    merge_target,
+                ));
+            }
+            if too_many_runs {
+                return Some(Compaction::whole_level(levels, index, index, true));
+            }
+        }
+        None
+    }
+}
+
+/// Tiers every level except the largest, which is kept as a single run.
+///
+/// Most data lives in the largest level, so leveling it alone gives close to
+/// leveling's lookup and space cost while writes to the upper levels stay as
+/// cheap as under tiering.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LazyLeveled;
+
+impl CompactionPolicy for LazyLeveled {
+    fn name(&self) -> &'static str {
+        "lazy_leveled"
+    }
+
+    fn pick(
+        &self,
+        levels: &[LevelState],
+        buffer_capacity: usize,
+        fanout: usize,
+    ) -> Option<Compaction> {
+        Hybrid::new(fanout.saturating_sub(1).max(1), 1).pick(levels, buffer_capacity, fanout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compaction::tests::levels;
+
+    /// Picks for `runs` and describes the result as
+    /// `(level, target, target runs merged)`.
+    fn pick(
+        policy: &dyn CompactionPolicy,
+        runs: &[&[usize]],
+        fanout: usize,
+    ) -> Option<(usize, usize, usize)> {
+        let compaction = policy.pick(&levels(runs), 10, fanout)?;
+        Some((
+            compaction.level,
+            compaction.target,
+            compaction.target_runs.len(),
+        ))
+    }
+
+    #[test]
+    fn test_hybrid_run_limits() {
+        let policy = Hybrid::new(2, 1);
+
+        // Upper levels collect up to K runs before being pushed down
+        assert_eq!(pick(&policy, &[&[10, 10], &[100]], 4), None);
+        assert_eq!(pick(&policy, &[&[10, 10, 10], &[100]], 4), Some((0, 1, 1)));
+
+        // An upper target with room receives a new run
+        assert_eq!(
+            pick(&policy, &[&[10, 10, 10], &[40], &[1000]], 4),
+            Some((0, 1, 0))
+        );
+
+        // The last level is merged down to Z runs in place
+        assert_eq!(pick(&policy, &[&[], &[100, 50]], 4), Some((1, 1, 0)));
+
+        // A full last level starts a new one
+        assert_eq!(pick(&policy, &[&[10], &[200]], 4), Some((1, 2, 0)));
+        assert_eq!(pick(&policy, &[], 4), None);
+    }
+
+    #[test]
+    fn test_lazy_leveled() {
+        // With a fanout of 4 the upper levels hold up to 3 runs
+        assert_eq!(
+            pick(&LazyLeveled, &[&[10, 10, 10], &[20, 20, 20], &[500]], 4),
+            None
+        );
+        assert_eq!(
+            pick(&LazyLeveled, &[&[10, 10, 10, 10], &[20], &[500]], 4),
+            Some((0, 1, 0))
+        );
+
+        // The largest level only ever holds one run
+        assert_eq!(
+            pick(&LazyLeveled, &[&[10, 10, 10, 10], &[100]], 4),
+            Some((0, 1, 1))
+        );
+    }
+}
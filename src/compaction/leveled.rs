@@ -0,0 +1,76 @@
+use super::{is_idle, level_capacity, Compaction, CompactionPolicy, LevelState};
+
+/// Keeps every level a single sorted run of at most `buffer * fanout^i`
+/// entries, trading extra merge work for the fewest runs to search.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Leveled;
+
+impl CompactionPolicy for Leveled {
+    fn name(&self) -> &'static str {
+        "leveled"
+    }
+
+    /// Levels are checked top-down so that an overflowing level is pushed
+    /// down before anything below it is considered.
+    fn pick(
+        &self,
+        levels: &[LevelState],
+        buffer_capacity: usize,
+        fanout: usize,
+    ) -> Option<Compaction> {
+        for (index, level) in levels.iter().enumerate() {
+            if level.compacting {
+                continue;
+            }
+            if level.len() > level_capacity(index, buffer_capacity, fanout) {
+                if !is_idle(levels, index + 1) {
+                    continue;
+                }
+                return Some(Compaction::whole_level(levels, index, index + 1, true));
+            }
+            if level.runs.len() > 1 {
+                return Some(Compaction::whole_level(levels, index, index, true));
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compaction::tests::levels;
+
+    #[test]
+    fn test_pick_leveled() {
+        // A single run within capacity needs nothing
+        assert_eq!(Leveled.pick(&levels(&[&[10]]), 10, 2), None);
+
+        // A second run is merged into the first
+        let state = levels(&[&[10, 10]]);
+        assert_eq!(
+            Leveled.pick(&state, 10, 2),
+            Some(Compaction::whole_level(&state, 0, 0, true))
+        );
+
+        // An overflowing level is pushed down
+        let state = levels(&[&[20, 10], &[30]]);
+        let compaction = Leveled.pick(&state, 10, 2).unwrap();
+        assert_eq!((compaction.level, compaction.target), (0, 1));
+        assert_eq!(compaction.target_runs, vec![0]);
+
+        let state = levels(&[&[10], &[50]]);
+        assert_eq!(
+            Leveled.pick(&state, 10, 2),
+            Some(Compaction::whole_level(&state, 1, 2, true))
+        );
+
+        // Levels being merged are left alone
+        let mut state = levels(&[&[20, 10], &[30], &[10, 10]]);
+        state[1].compacting = true;
+        assert_eq!(
+            Leveled.pick(&state, 10, 2),
+            Some(Compaction::whole_level(&state, 2, 2, true))
+        );
+    }
+}
@@ -0,0 +1,301 @@
+mod hybrid;
+mod leveled;
+mod partial;
+mod tiered;
+
+pub use hybrid::{Hybrid, LazyLeveled};
+pub use leveled::Leveled;
+pub use partial::{FileChoice, Partial};
+pub use tiered::Tiered;
+
+use crate::level::Level;
+use crate::merge::MergingIterator;
+use crate::run::Run;
+use crate::types::{Key, Value, TOMBSTONE};
+use std::fmt;
+use std::sync::Arc;
+
+/// A merge of some runs of `level` into `target`, chosen by a policy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Compaction {
+    pub level: usize,
+    /// Indices of the runs in `level` to merge
+    pub runs: Vec<usize>,
+    pub target: usize,
+    /// Indices of the runs in `target` merged in as well; always empty when
+    /// merging within a level
+    pub target_runs: Vec<usize>,
+    /// Split the output into runs of at most this many entries, or write a
+    /// single run if `None`
+    pub max_run_len: Option<usize>,
+}
+
+impl Compaction {
+    /// Merges every run in `level` into a single run in `target`. With
+    /// `merge_target` the target's runs are merged in too, as leveling does to
+    /// keep one run per level; tiering leaves them alone.
+    pub fn whole_level(
+        levels: &[LevelState],
+        level: usize,
+        target: usize,
+        merge_target: bool,
+    ) -> Self {
+        let run_count = |index: usize| levels.get(index).map_or(0, |level| level.runs.len());
+        let target_runs = if merge_target && target != level {
+            (0..run_count(target)).collect()
+        } else {
+            Vec::new()
+        };
+        Self {
+            level,
+            runs: (0..run_count(level)).collect(),
+            target,
+            target_runs,
+            max_run_len: None,
+        }
+    }
+}
+
+/// What a policy gets to see of a run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RunState {
+    /// Number of entries, tombstones included
+    pub len: usize,
+    pub min_key: Key,
+    pub max_key: Key,
+}
+
+impl RunState {
+    pub fn overlaps(&self, min_key: Key, max_key: Key) -> bool {
+        self.min_key <= max_key && self.max_key >= min_key
+    }
+}
+
+/// What a policy gets to see of a level when deciding on the next merge.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LevelState {
+    /// Runs in the order they were added, oldest first
+    pub runs: Vec<RunState>,
+    /// Whether a merge reading from or writing to this level is in progress.
+    /// Merges run concurrently on a worker pool, so policies must leave such
+    /// levels alone until it finishes.
+    pub compacting: bool,
+    /// Largest key of the runs last merged out of this level into the next,
+    /// for policies that cycle through the key space. The tree advances it
+    /// once such a merge is installed and keeps it in the manifest.
+    pub cursor: Option<Key>,
+}
+
+impl LevelState {
+    /// Total number of entries in the level.
+    pub fn len(&self) -> usize {
+        self.runs.iter().map(|run| run.len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.runs.is_empty()
+    }
+
+    /// Indices of the runs holding keys in `[min_key, max_key]`.
+    pub fn overlapping(&self, min_key: Key, max_key: Key) -> Vec<usize> {
+        (0..self.runs.len())
+            .filter(|&index| self.runs[index].overlaps(min_key, max_key))
+            .collect()
+    }
+}
+
+/// Whether a merge may read from or write to level `index`; levels that
+/// don't exist yet are always free.
+pub fn is_idle(levels: &[LevelState], index: usize) -> bool {
+    levels.get(index).map_or(true, |level| !level.compacting)
+}
+
+/// Decides when and how levels are merged.
+///
+/// The tree asks its policy for the next merge after every flush and whenever
+/// a merge finishes, and keeps running merges until the policy returns `None`,
+/// so a policy only has to describe its steady state.
+pub trait CompactionPolicy: fmt::Debug + Send + Sync {
+    /// Name accepted by the server's `-l` option.
+    fn name(&self) -> &'static str;
+
+    /// Picks the next merge, or `None` if the levels satisfy the policy.
+    ///
+    /// `buffer_capacity` is the size of a flushed run in entries and `fanout`
+    /// the size ratio between adjacent levels.
+    fn pick(
+        &self,
+        levels: &[LevelState],
+        buffer_capacity: usize,
+        fanout: usize,
+    ) -> Option<Compaction>;
+}
+
+/// Looks up a built-in policy by the name used on the command line.
+///
+/// The hybrid policy takes its run limits inline, as `hybrid:<K>:<Z>`, and
+/// the partial policy picks files round-robin unless named
+/// `partial:least_overlap`.
+pub fn policy_from_name(name: &str) -> Option<Arc<dyn CompactionPolicy>> {
+    match name {
+        "leveled" => Some(Arc::new(Leveled)),
+        "tiered" => Some(Arc::new(Tiered)),
+        "lazy_leveled" => Some(Arc::new(LazyLeveled)),
+        "partial" => Some(Arc::new(Partial::new(FileChoice::RoundRobin))),
+        "partial:least_overlap" => Some(Arc::new(Partial::new(FileChoice::LeastOverlap))),
+        _ => {
+            let limits = name.strip_prefix("hybrid:")?;
+            let (upper_runs, last_runs) = limits.split_once(':')?;
+            let upper_runs = upper_runs.parse().ok().filter(|&k| k >= 1)?;
+            let last_runs = last_runs.parse().ok().filter(|&z| z >= 1)?;
+            Some(Arc::new(Hybrid::new(upper_runs, last_runs)))
+        }
+    }
+}
+
+/// Maximum number of entries level `index` may hold under leveling.
+///
+/// The buffer counts as level 0, so `levels[0]` is the first on-disk level
+/// and holds up to `buffer_capacity * fanout` entries.
+pub fn level_capacity(index: usize, buffer_capacity: usize, fanout: usize) -> usize {
+    let mut capacity = buffer_capacity;
+    for _ in 0..=index {
+        capacity = capacity.saturating_mul(fanout);
+    }
+    capacity
+}
+
+/// Snapshot of the tree's levels for a policy to inspect.
+pub(crate) fn level_states(
+    levels: &[Level],
+    compacting: &[bool],
+    cursors: &[Option<Key>],
+) -> Vec<LevelState> {
+    levels
+        .iter()
+        .enumerate()
+        .map(|(index, level)| LevelState {
+            runs: level.runs().iter().map(|run| run_state(run)).collect(),
+            compacting: compacting.get(index).copied().unwrap_or(false),
+            cursor: cursors.get(index).copied().flatten(),
+        })
+        .collect()
+}
+
+pub(crate) fn run_state(run: &Run) -> RunState {
+    // An empty run overlaps nothing
+    let (min_key, max_key) = run.key_range().unwrap_or((Key::MAX, Key::MIN));
+    RunState {
+        len: run.len(),
+        min_key,
+        max_key,
+    }
+}
+
+/// Merges `runs`, given oldest first, into a single sorted list of entries.
+///
+/// For keys present in several runs the newest value wins. Tombstones are only
+/// dropped when the caller knows no older data for their keys remains anywhere
+/// below the merged runs.
+pub(crate) fn merge_runs<'a>(
+    runs: impl IntoIterator<Item = &'a Run>,
+    drop_tombstones: bool,
+) -> Vec<(Key, Value)> {
+    let mut sources: Vec<_> = runs.into_iter().map(Run::entries).collect();
+    sources.reverse();
+    MergingIterator::new(sources)
+        .filter(|&(_, value)| !drop_tombstones || value != TOMBSTONE)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bloom::BloomParams;
+
+    /// Levels of key-disjoint runs with the given entry counts.
+    pub fn levels(runs: &[&[usize]]) -> Vec<LevelState> {
+        runs.iter()
+            .map(|runs| {
+                let mut next_key = 0;
+                let runs = runs.iter().map(|&len| {
+                    let min_key = next_key;
+                    next_key += len as Key;
+                    RunState {
+                        len,
+                        min_key,
+                        max_key: next_key - 1,
+                    }
+                });
+                LevelState {
+                    runs: runs.collect(),
+                    compacting: false,
+                    cursor: None,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_whole_level() {
+        let levels = levels(&[&[10, 10], &[30, 30]]);
+        let compaction = Compaction::whole_level(&levels, 0, 1, true);
+        assert_eq!(compaction.runs, vec![0, 1]);
+        assert_eq!(compaction.target_runs, vec![0, 1]);
+
+        assert!(Compaction::whole_level(&levels, 0, 1, false)
+            .target_runs
+            .is_empty());
+        assert!(Compaction::whole_level(&levels, 1, 1, true)
+            .target_runs
+            .is_empty());
+        assert!(Compaction::whole_level(&levels, 1, 2, true)
+            .target_runs
+            .is_empty());
+        assert_eq!(levels[1].overlapping(25, 35), vec![0, 1]);
+        assert_eq!(levels[1].overlapping(30, 100), vec![1]);
+    }
+
+    #[test]
+    fn test_level_capacity() {
+        assert_eq!(level_capacity(0, 100, 2), 200);
+        assert_eq!(level_capacity(1, 100, 2), 400);
+        assert_eq!(level_capacity(2, 10, 10), 10_000);
+        assert_eq!(level_capacity(100, 100, 10), usize::MAX);
+    }
+
+    #[test]
+    fn test_policy_from_name() {
+        assert_eq!(policy_from_name("leveled").unwrap().name(), "leveled");
+        assert_eq!(policy_from_name("tiered").unwrap().name(), "tiered");
+        assert_eq!(
+            policy_from_name("lazy_leveled").unwrap().name(),
+            "lazy_leveled"
+        );
+        assert_eq!(policy_from_name("hybrid:3:2").unwrap().name(), "hybrid");
+        assert_eq!(policy_from_name("partial").unwrap().name(), "partial");
+        assert_eq!(
+            policy_from_name("partial:least_overlap").unwrap().name(),
+            "partial"
+        );
+        assert!(policy_from_name("hybrid:0:1").is_none());
+        assert!(policy_from_name("hybrid:3").is_none());
+        assert!(policy_from_name("unknown").is_none());
+    }
+
+    #[test]
+    fn test_merge_runs_newest_wins() {
+        let older = Run::new(vec![(1, 10), (2, 20), (3, 30)], BloomParams::default());
+        let newer = Run::new(
+            vec![(2, 21), (3, TOMBSTONE), (4, 40)],
+            BloomParams::default(),
+        );
+        let runs = [older, newer];
+
+        assert_eq!(
+            merge_runs(&runs, false),
+            vec![(1, 10), (2, 21), (3, TOMBSTONE), (4, 40)]
+        );
+        assert_eq!(merge_runs(&runs, true), vec![(1, 10), (2, 21), (4, 40)]);
+    }
+}
@@ -0,0 +1,191 @@
+use super::{is_idle, level_capacity, Compaction, CompactionPolicy, LevelState};
+
+/// How [`Partial`] chooses the file to push down from an overflowing level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileChoice {
+    /// Cycle through the key space so every file is eventually compacted
+    RoundRobin,
+    /// Pick the file overlapping the fewest entries in the next level, which
+    /// minimizes the work of each step
+    LeastOverlap,
+}
+
+/// Leveling at file granularity, in the style of LevelDB and RocksDB.
+///
+/// Every level below the first is split into key-disjoint files of at most
+/// one buffer each. When a level overflows a single file is merged into the
+/// files of the next level it overlaps, so the work per step is bounded by a
+/// few files instead of a whole level. The first level holds the flushed
+/// runs, which may overlap, and is merged into the second as a whole.
+#[derive(Debug)]
+pub struct Partial {
+    choice: FileChoice,
+}
+
+impl Partial {
+    pub fn new(choice: FileChoice) -> Self {
+        Self { choice }
+    }
+
+    fn choose_file(&self, levels: &[LevelState], index: usize) -> usize {
+        let level = &levels[index];
+        match self.choice {
+            FileChoice::RoundRobin => {
+                // Continue after the last file compacted, wrapping around
+                let mut by_key: Vec<usize> = (0..level.runs.len()).collect();
+                by_key.sort_by_key(|&run| level.runs[run].min_key);
+                by_key
+                    .iter()
+                    .copied()
+                    .find(|&run| {
+                        level
+                            .cursor
+                            .map_or(true, |key| level.runs[run].min_key > key)
+                    })
+                    .unwrap_or(by_key[0])
+            }
+            FileChoice::LeastOverlap => {
+                let next = levels.get(index + 1);
+                let overlap = |run: usize| {
+                    let run = &level.runs[run];
+                    next.map_or(0, |next| {
+                        let overlapping = next.overlapping(run.min_key, run.max_key);
+                        overlapping.iter().map(|&i| next.runs[i].len).sum()
+                    })
+                };
+                (0..level.runs.len())
+                    .min_by_key(|&run| overlap(run))
+                    .unwrap()
+            }
+        }
+    }
+}
+
+impl CompactionPolicy for Partial {
+    fn name(&self) -> &'static str {
+        "partial"
+    }
+
+    fn pick(
+        &self,
+        levels: &[LevelState],
+        buffer_capacity: usize,
+        fanout: usize,
+    ) -> Option<Compaction> {
+        let index = levels.iter().enumerate().position(|(index, level)| {
+            !level.is_empty()
+                && !level.compacting
+                && is_idle(levels, index + 1)
+                && level.len() > level_capacity(index, buffer_capacity, fanout)
+        })?;
+        let level = &levels[index];
+
+        let runs = if index == 0 {
+            (0..level.runs.len()).collect()
+        } else {
+            vec![self.choose_file(levels, index)]
+        };
+        let min_key = runs.iter().map(|&run| level.runs[run].min_key).min()?;
+        let max_key = runs.iter().map(|&run| level.runs[run].max_key).max()?;
+        let target_runs = levels
+            .get(index + 1)
+            .map_or(Vec::new(), |next| next.overlapping(min_key, max_key));
+
+        Some(Compaction {
+            level: index,
+            runs,
+            target: index + 1,
+            target_runs,
+            max_run_len: Some(buffer_capacity),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compaction::RunState;
+    use crate::types::Key;
+
+    fn run(min_key: Key, max_key: Key) -> RunState {
+        RunState {
+            len: 10,
+            min_key,
+            max_key,
+        }
+    }
+
+    fn level(runs: &[(Key, Key)]) -> LevelState {
+        LevelState {
+            runs: runs.iter().map(|&(min, max)| run(min, max)).collect(),
+            compacting: false,
+            cursor: None,
+        }
+    }
+
+    #[test]
+    fn test_first_level_merges_whole() {
+        let policy = Partial::new(FileChoice::RoundRobin);
+        let levels = [
+            level(&[(0, 50), (40, 90), (10, 20)]),
+            level(&[(0, 9), (30, 60), (95, 99)]),
+        ];
+
+        // 30 entries fit within the first level's capacity of 40
+        assert_eq!(policy.pick(&levels, 10, 4), None);
+
+        let compaction = policy.pick(&levels, 10, 2).unwrap();
+        assert_eq!(compaction.runs, vec![0, 1, 2]);
+        assert_eq!(compaction.target, 1);
+        assert_eq!(compaction.target_runs, vec![0, 1]);
+        assert_eq!(compaction.max_run_len, Some(10));
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let policy = Partial::new(FileChoice::RoundRobin);
+        let mut levels = [
+            level(&[]),
+            level(&[(20, 29), (0, 9), (10, 19)]),
+            level(&[(0, 14), (15, 40)]),
+        ];
+
+        // Picking alone doesn't move the cursor
+        assert_eq!(policy.pick(&levels, 5, 2), policy.pick(&levels, 5, 2));
+
+        // Files are visited in key order, wrapping around at the end, as the
+        // tree advances the cursor past each installed merge
+        let picks: Vec<_> = (0..4)
+            .map(|_| {
+                let compaction = policy.pick(&levels, 5, 2).unwrap();
+                levels[1].cursor = Some(levels[1].runs[compaction.runs[0]].max_key);
+                (compaction.runs[0], compaction.target_runs)
+            })
+            .collect();
+        assert_eq!(
+            picks,
+            vec![(1, vec![0]), (2, vec![0, 1]), (0, vec![1]), (1, vec![0])]
+        );
+    }
+
+    #[test]
+    fn test_least_overlap() {
+        let policy = Partial::new(FileChoice::LeastOverlap);
+        let mut levels = vec![
+            level(&[]),
+            level(&[(0, 9), (10, 19), (20, 29)]),
+            level(&[(0, 5), (6, 12), (25, 30)]),
+        ];
+        levels[2].runs[2].len = 1;
+
+        let compaction = policy.pick(&levels, 5, 2).unwrap();
+        assert_eq!(compaction.runs, vec![2]);
+        assert_eq!(compaction.target_runs, vec![2]);
+
+        // A file overlapping nothing below is simply moved down
+        levels[1].runs.push(run(50, 60));
+        let compaction = policy.pick(&levels, 5, 2).unwrap();
+        assert_eq!(compaction.runs, vec![3]);
+        assert!(compaction.target_runs.is_empty());
+    }
+}
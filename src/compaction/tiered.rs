@@ -0,0 +1,52 @@
+use super::{is_idle, Compaction, CompactionPolicy, LevelState};
+
+/// Lets each level collect up to `fanout` runs and only then merges them all
+/// into one new run in the next level, so every entry is rewritten once per
+/// level. Suits write-heavy workloads at the cost of more runs per lookup.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Tiered;
+
+impl CompactionPolicy for Tiered {
+    fn name(&self) -> &'static str {
+        "tiered"
+    }
+
+    fn pick(
+        &self,
+        levels: &[LevelState],
+        _buffer_capacity: usize,
+        fanout: usize,
+    ) -> Option<Compaction> {
+        let index = levels.iter().enumerate().position(|(index, level)| {
+            level.runs.len() >= fanout && !level.compacting && is_idle(levels, index + 1)
+        })?;
+        Some(Compaction::whole_level(levels, index, index + 1, false))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compaction::tests::levels;
+
+    #[test]
+    fn test_pick_tiered() {
+        assert_eq!(Tiered.pick(&levels(&[&[10, 10]]), 10, 3), None);
+        assert_eq!(Tiered.pick(&levels(&[&[10, 10], &[10]]), 10, 3), None);
+
+        // A full level is merged into a new run in the next level
+        let state = levels(&[&[10, 10, 10]]);
+        let compaction = Tiered.pick(&state, 10, 3).unwrap();
+        assert_eq!((compaction.level, compaction.target), (0, 1));
+        assert_eq!(compaction.runs, vec![0, 1, 2]);
+
+        let mut state = levels(&[&[10], &[10, 10, 10], &[30]]);
+        let compaction = Tiered.pick(&state, 10, 3).unwrap();
+        assert_eq!((compaction.level, compaction.target), (1, 2));
+        assert!(compaction.target_runs.is_empty());
+
+        // Nothing may be merged into a level that is being compacted
+        state[2].compacting = true;
+        assert_eq!(Tiered.pick(&state, 10, 3), None);
+    }
+}
@@ -0,0 +1,171 @@
+use crate::bloom::{FilterAllocation, FilterKind, RangeFilterParams};
+use crate::compaction::{CompactionPolicy, Leveled};
+use crate::types::{Error, Result};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+pub use crate::wal::SyncMode;
+
+/// Default size of the write buffer in disk pages (`-n`).
+pub const DEFAULT_BUFFER_PAGES: usize = 1024;
+
+/// Default size ratio between adjacent levels (`-f`).
+pub const DEFAULT_FANOUT: usize = 2;
+
+/// Default false-positive rate of the runs' Bloom filters (`-e`).
+pub const DEFAULT_BLOOM_ERROR_RATE: f64 = 0.01;
+
+/// Default number of compaction worker threads.
+pub const DEFAULT_COMPACTION_THREADS: usize = 2;
+
+/// Default number of full memtables that may wait for the flush thread.
+pub const DEFAULT_MAX_IMMUTABLE_MEMTABLES: usize = 2;
+
+/// Tunable parameters for an [`LSMTree`](crate::lsm_tree::LSMTree).
+#[derive(Debug, Clone)]
+pub struct LSMConfig {
+    /// Size of the in-memory buffer in disk pages
+    pub buffer_pages: usize,
+    /// Size ratio between adjacent levels; must be at least 2
+    pub fanout: usize,
+    /// When and how levels are merged (`-l`)
+    pub compaction: Arc<dyn CompactionPolicy>,
+    /// Target false-positive rate of each run's Bloom filter (`-e`); sets the
+    /// filters' bits per key and probe count
+    pub bloom_error_rate: f64,
+    /// How filter memory is shared between levels (`-a`); the error rate sets
+    /// the average bits per key either way
+    pub bloom_allocation: FilterAllocation,
+    /// Filter implementation new runs are built with (`-k`); runs already on
+    /// disk keep theirs
+    pub filter_kind: FilterKind,
+    /// Range filter built for each new run (`-r`), letting short range
+    /// queries skip runs with no keys in the range; `None` builds none
+    pub range_filter: Option<RangeFilterParams>,
+    /// Directory holding the tree's files; `None` keeps everything in memory
+    pub data_dir: Option<PathBuf>,
+    /// When writes are forced to disk by the write-ahead log
+    pub wal_sync: SyncMode,
+    /// Number of background threads merging levels
+    pub compaction_threads: usize,
+    /// Number of full memtables that may wait to be flushed before writes
+    /// stall; at least 1
+    pub max_immutable_memtables: usize,
+}
+
+impl Default for LSMConfig {
+    fn default() -> Self {
+        Self {
+            buffer_pages: DEFAULT_BUFFER_PAGES,
+            fanout: DEFAULT_FANOUT,
+            compaction: Arc::new(Leveled),
+            bloom_error_rate: DEFAULT_BLOOM_ERROR_RATE,
+            bloom_allocation: FilterAllocation::default(),
+            filter_kind: FilterKind::default(),
+            range_filter: None,
+            data_dir: None,
+            wal_sync: SyncMode::default(),
+            compaction_threads: DEFAULT_COMPACTION_THREADS,
+            max_immutable_memtables: DEFAULT_MAX_IMMUTABLE_MEMTABLES,
+        }
+    }
+}
+
+impl LSMConfig {
+    /// Checks that the parameters make a workable tree, describing the first
+    /// one that doesn't.
+    pub fn validate(&self) -> Result<()> {
+        if self.buffer_pages == 0 {
+            return Err(Error::InvalidConfig(
+                "Buffer must be at least one page".to_string(),
+            ));
+        }
+        if self.fanout < 2 {
+            return Err(Error::InvalidConfig(format!(
+                "Fanout must be at least 2, not {}",
+                self.fanout
+            )));
+        }
+        if !(self.bloom_error_rate > 0.0 && self.bloom_error_rate < 1.0) {
+            return Err(Error::InvalidConfig(format!(
+                "Bloom error rate must be between 0 and 1, not {}",
+                self.bloom_error_rate
+            )));
+        }
+        if let Some(range) = &self.range_filter {
+            if range.bits_per_key.is_nan() || range.bits_per_key <= 0.0 {
+                return Err(Error::InvalidConfig(format!(
+                    "Range filter bits per key must be positive, not {}",
+                    range.bits_per_key
+                )));
+            }
+        }
+        if let Some(dir) = &self.data_dir {
+            if dir.exists() && !dir.is_dir() {
+                return Err(Error::InvalidConfig(format!(
+                    "Data directory {} is not a directory",
+                    dir.display()
+                )));
+            }
+        }
+        if self.compaction_threads == 0 {
+            return Err(Error::InvalidConfig(
+                "At least one compaction thread is needed".to_string(),
+            ));
+        }
+        if self.max_immutable_memtables == 0 {
+            return Err(Error::InvalidConfig(
+                "At least one full memtable must be allowed to wait".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        assert!(LSMConfig::default().validate().is_ok());
+
+        let invalid = [
+            LSMConfig {
+                buffer_pages: 0,
+                ..LSMConfig::default()
+            },
+            LSMConfig {
+                fanout: 1,
+                ..LSMConfig::default()
+            },
+            LSMConfig {
+                bloom_error_rate: 1.0,
+                ..LSMConfig::default()
+            },
+            LSMConfig {
+                bloom_error_rate: f64::NAN,
+                ..LSMConfig::default()
+            },
+            LSMConfig {
+                range_filter: Some(RangeFilterParams::new(0.0)),
+                ..LSMConfig::default()
+            },
+            LSMConfig {
+                data_dir: Some(PathBuf::from("Cargo.toml")),
+                ..LSMConfig::default()
+            },
+            LSMConfig {
+                compaction_threads: 0,
+                ..LSMConfig::default()
+            },
+        ];
+        for config in invalid {
+            assert!(
+                matches!(config.validate(), Err(Error::InvalidConfig(_))),
+                "{:?} passed",
+                config
+            );
+        }
+    }
+}
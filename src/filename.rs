@@ -0,0 +1,123 @@
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const RUN_EXTENSION: &str = "run";
+const WAL_EXTENSION: &str = "wal";
+const MANIFEST_PREFIX: &str = "MANIFEST-";
+const CURRENT: &str = "CURRENT";
+const TEMP_SUFFIX: &str = ".tmp";
+
+/// The kinds of file that live in a tree's data directory.
+///
+/// Runs, logs and manifests share one id space, so every file name is unique
+/// and a larger id always means a newer file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    Run(u64),
+    Wal(u64),
+    Manifest(u64),
+    Current,
+    /// Partially written copy of one of the files above, left behind by a
+    /// crash
+    Temp,
+}
+
+pub fn run_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:06}.{}", id, RUN_EXTENSION))
+}
+
+pub fn wal_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:06}.{}", id, WAL_EXTENSION))
+}
+
+pub fn manifest_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}{:06}", MANIFEST_PREFIX, id))
+}
+
+pub fn current_path(dir: &Path) -> PathBuf {
+    dir.join(CURRENT)
+}
+
+/// Where `path` is written before being renamed into place.
+pub fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(TEMP_SUFFIX);
+    PathBuf::from(name)
+}
+
+/// Classifies a data directory entry; `None` for files the tree doesn't own.
+pub fn parse(path: &Path) -> Option<FileKind> {
+    let name = path.file_name()?.to_str()?;
+    if name == CURRENT {
+        return Some(FileKind::Current);
+    }
+    if let Some(target) = name.strip_suffix(TEMP_SUFFIX) {
+        // Only temporary copies of the tree's own files
+        return parse(Path::new(target))
+            .filter(|kind| *kind != FileKind::Temp)
+            .map(|_| FileKind::Temp);
+    }
+    if let Some(id) = name.strip_prefix(MANIFEST_PREFIX) {
+        return id.parse().ok().map(FileKind::Manifest);
+    }
+
+    let (stem, extension) = name.split_once('.')?;
+    match extension {
+        RUN_EXTENSION => stem.parse().ok().map(FileKind::Run),
+        WAL_EXTENSION => stem.parse().ok().map(FileKind::Wal),
+        _ => None,
+    }
+}
+
+/// Makes renames and deletions in `dir` durable.
+pub fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+impl FileKind {
+    /// The file's id, for the kinds that carry one.
+    pub fn id(&self) -> Option<u64> {
+        match *self {
+            FileKind::Run(id) | FileKind::Wal(id) | FileKind::Manifest(id) => Some(id),
+            FileKind::Current | FileKind::Temp => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let dir = Path::new("/data");
+        assert_eq!(parse(&run_path(dir, 7)), Some(FileKind::Run(7)));
+        assert_eq!(parse(&wal_path(dir, 42)), Some(FileKind::Wal(42)));
+        assert_eq!(parse(&manifest_path(dir, 3)), Some(FileKind::Manifest(3)));
+        assert_eq!(parse(&current_path(dir)), Some(FileKind::Current));
+        assert_eq!(run_path(dir, 7), Path::new("/data/000007.run"));
+        assert_eq!(
+            temp_path(&run_path(dir, 7)),
+            Path::new("/data/000007.run.tmp")
+        );
+        assert_eq!(parse(&temp_path(&current_path(dir))), Some(FileKind::Temp));
+    }
+
+    #[test]
+    fn test_foreign_files() {
+        assert_eq!(
+            parse(Path::new("/data/000007.run.tmp")),
+            Some(FileKind::Temp)
+        );
+        assert_eq!(parse(Path::new("/data/CURRENT.tmp")), Some(FileKind::Temp));
+        assert_eq!(parse(Path::new("/data/notes.txt")), None);
+        assert_eq!(parse(Path::new("/data/notes.tmp")), None);
+        assert_eq!(parse(Path::new("/data/000007.tmp")), None);
+        assert_eq!(parse(Path::new("/data/000007.run.tmp.tmp")), None);
+        assert_eq!(parse(Path::new("/data/abc.run")), None);
+        assert_eq!(parse(Path::new("/data/MANIFEST-x")), None);
+        assert_eq!(FileKind::Temp.id(), None);
+        assert_eq!(FileKind::Wal(9).id(), Some(9));
+    }
+}
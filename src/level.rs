@@ -1,8 +1,11 @@
-use crate::run::Run;
+use crate::run::{Lookup, Run, RunCursor};
 use crate::types::{Key, Value};
+use std::sync::Arc;
 
+// Runs are shared between versions of the tree, so a level is cheap to clone
+#[derive(Clone, Default)]
 pub struct Level {
-    runs: Vec<Run>,
+    runs: Vec<Arc<Run>>,
 }
 
 impl Level {
@@ -11,13 +14,30 @@ impl Level {
     }
 
     // Add a new run to this level
-    pub fn add_run(&mut self, run: Run) {
-        self.runs.push(run);
+    pub fn add_run(&mut self, run: impl Into<Arc<Run>>) {
+        self.runs.push(run.into());
     }
 
-    // Retrieve a value for a key by searching all runs
+    // Runs in this level, oldest first
+    pub fn runs(&self) -> &[Arc<Run>] {
+        &self.runs
+    }
+
+    // Swap `inputs` for `outputs`. The outputs take the place of the oldest
+    // input so that runs added since the inputs were chosen stay newer; if no
+    // input was in this level they are appended as the newest runs
+    pub fn replace(&mut self, inputs: &[Arc<Run>], outputs: Vec<Arc<Run>>) {
+        let is_input = |run: &Arc<Run>| inputs.iter().any(|input| Arc::ptr_eq(input, run));
+        let position = self.runs.iter().position(is_input);
+        self.runs.retain(|run| !is_input(run));
+
+        let position = position.unwrap_or(self.runs.len());
+        self.runs.splice(position..position, outputs);
+    }
+
+    // Retrieve a value for a key, searching the newest run first
     pub fn get(&self, key: Key) -> Option<Value> {
-        for run in &self.runs {
+        for run in self.runs.iter().rev() {
             if let Some(value) = run.get(key) {
                 return Some(value);
             }
@@ -25,20 +45,34 @@ impl Level {
         None
     }
 
-    // Retrieve all key-value pairs in the specified range
-    pub fn range(&self, start: Key, end: Key) -> Vec<(Key, Value)> {
-        let mut results = Vec::new();
-        for run in &self.runs {
-            results.extend(run.range(start, end));
+    // Look up a batch of keys, newest run first, dropping those found from
+    // `lookups`
+    pub fn multi_get(&self, lookups: &mut Vec<Lookup>, results: &mut [Option<Value>]) {
+        for run in self.runs.iter().rev() {
+            if lookups.is_empty() {
+                return;
+            }
+            run.multi_get(lookups, results);
         }
-        results
+    }
+
+    // Cursors over the entries of every run in the specified range, newest
+    // run first
+    pub fn cursors(&self, start: Key, end: Key) -> impl Iterator<Item = RunCursor> + '_ {
+        let runs = self.runs.iter().rev();
+        runs.map(move |run| Run::cursor(Arc::clone(run), start, end))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::run::Run;
+    use crate::bloom::BloomParams;
+    use crate::merge::MergingIterator;
+
+    fn range(level: &Level, start: Key, end: Key) -> Vec<(Key, Value)> {
+        MergingIterator::new(level.cursors(start, end).collect()).collect()
+    }
 
     #[test]
     fn test_level_operations() {
@@ -47,8 +81,8 @@ mod tests {
         let data2 = vec![(3, 300), (4, 400)];
 
         // Add runs to the level
-        level.add_run(Run::new(data1));
-        level.add_run(Run::new(data2));
+        level.add_run(Run::new(data1, BloomParams::default()));
+        level.add_run(Run::new(data2, BloomParams::default()));
 
         // Test key lookups
         assert_eq!(level.get(2), Some(200));
@@ -56,7 +90,53 @@ mod tests {
         assert_eq!(level.get(5), None);
 
         // Test range queries
-        let range = level.range(2, 4);
+        let range = range(&level, 2, 4);
         assert_eq!(range, vec![(2, 200), (3, 300)]);
     }
+
+    #[test]
+    fn test_replace_runs() {
+        let mut level = Level::new();
+        for key in 0..4 {
+            level.add_run(Run::new(
+                vec![(key, key), (10, key)],
+                BloomParams::default(),
+            ));
+        }
+        let inputs = [level.runs()[1].clone(), level.runs()[2].clone()];
+
+        // The merged run lands where the oldest input was
+        level.replace(
+            &inputs,
+            vec![Arc::new(Run::new(vec![(10, 12)], BloomParams::default()))],
+        );
+        assert_eq!(level.runs().len(), 3);
+        assert_eq!(level.get(1), None);
+        assert_eq!(level.get(3), Some(3));
+        assert_eq!(level.get(10), Some(3));
+        assert_eq!(level.runs()[1].get(10), Some(12));
+
+        // Runs from another level are added as the newest
+        level.replace(
+            &inputs,
+            vec![Arc::new(Run::new(vec![(10, 20)], BloomParams::default()))],
+        );
+        assert_eq!(level.get(10), Some(20));
+    }
+
+    #[test]
+    fn test_newest_run_wins() {
+        let mut level = Level::new();
+        level.add_run(Run::new(
+            vec![(1, 100), (2, 200), (3, 300)],
+            BloomParams::default(),
+        ));
+        level.add_run(Run::new(vec![(2, 201), (3, 301)], BloomParams::default()));
+        level.add_run(Run::new(vec![(3, 302)], BloomParams::default()));
+
+        assert_eq!(level.get(1), Some(100));
+        assert_eq!(level.get(2), Some(201));
+        assert_eq!(level.get(3), Some(302));
+        assert_eq!(range(&level, 1, 4), vec![(1, 100), (2, 201), (3, 302)]);
+    }
 }
@@ -1,10 +1,18 @@
 pub mod command;
+pub mod compaction;
+pub mod config;
+mod filename;
 mod level;
+mod load;
 pub mod lsm_tree;
+mod manifest;
 pub mod memtable;
+mod merge;
 mod run;
 pub mod test_helpers;
 pub mod types;
+mod version;
+mod wal;
 pub mod bloom;
 
 // Constants
@@ -0,0 +1,178 @@
+use crate::types::{Key, Result, Value};
+use std::io::{ErrorKind, Read};
+
+/// Bytes per pair: a 32-bit `KEY_t` and a 32-bit `VAL_t`, as declared in
+/// `generator/data_types.h`, in the generator's native little-endian order.
+const PAIR_SIZE: usize = 4 + 4;
+
+/// Pairs read from the file and handed on together.
+const CHUNK_PAIRS: usize = 64 * 1024;
+
+/// What a bulk load got through.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LoadSummary {
+    /// Pairs read and written to the tree
+    pub pairs: u64,
+    /// Bytes at the end of the file too few to make up a whole pair; the
+    /// file was truncated or isn't a file of pairs
+    pub trailing_bytes: usize,
+}
+
+impl std::fmt::Display for LoadSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Loaded {} pairs", self.pairs)?;
+        if self.trailing_bytes > 0 {
+            write!(
+                f,
+                ", ignored {} trailing bytes that don't make up a pair",
+                self.trailing_bytes
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Streams the packed pairs in `reader` to `write` in chunks, so a file of
+/// any size is loaded with a bounded buffer.
+///
+/// Pairs written before an error stay written; the summary is only returned
+/// once the whole file has been read.
+pub fn read_pairs(
+    mut reader: impl Read,
+    mut write: impl FnMut(&[(Key, Value)]) -> Result<()>,
+) -> Result<LoadSummary> {
+    let mut buf = vec![0u8; CHUNK_PAIRS * PAIR_SIZE];
+    let mut pairs = Vec::with_capacity(CHUNK_PAIRS);
+    let mut filled = 0;
+    let mut summary = LoadSummary::default();
+
+    loop {
+        let n = match reader.read(&mut buf[filled..]) {
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        filled += n;
+        if n > 0 && filled < buf.len() {
+            continue;
+        }
+
+        // Hand on the whole pairs and keep a partial one for the next read
+        let whole = filled - filled % PAIR_SIZE;
+        pairs.clear();
+        pairs.extend(buf[..whole].chunks_exact(PAIR_SIZE).map(decode));
+        if !pairs.is_empty() {
+            write(&pairs)?;
+        }
+        summary.pairs += pairs.len() as u64;
+        buf.copy_within(whole..filled, 0);
+        filled -= whole;
+
+        if n == 0 {
+            summary.trailing_bytes = filled;
+            return Ok(summary);
+        }
+    }
+}
+
+fn decode(pair: &[u8]) -> (Key, Value) {
+    let key = i32::from_le_bytes(pair[0..4].try_into().unwrap());
+    let value = i32::from_le_bytes(pair[4..8].try_into().unwrap());
+    (key as Key, value as Value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Error;
+
+    fn encode(pairs: &[(i32, i32)]) -> Vec<u8> {
+        pairs
+            .iter()
+            .flat_map(|&(key, value)| [key.to_le_bytes(), value.to_le_bytes()])
+            .flatten()
+            .collect()
+    }
+
+    /// Hands out at most `limit` bytes per read, like a slow socket or pipe.
+    struct Trickle<'a> {
+        bytes: &'a [u8],
+        limit: usize,
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = buf.len().min(self.limit).min(self.bytes.len());
+            buf[..n].copy_from_slice(&self.bytes[..n]);
+            self.bytes = &self.bytes[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_read_pairs() {
+        let input: Vec<(i32, i32)> = (0..CHUNK_PAIRS as i32 * 2 + 5)
+            .map(|i| (i.wrapping_mul(7919) - 1000, -i))
+            .collect();
+        let bytes = encode(&input);
+
+        for limit in [bytes.len(), 4096, 3] {
+            let mut loaded = Vec::new();
+            let mut chunks = 0;
+            let reader = Trickle {
+                bytes: &bytes,
+                limit,
+            };
+            let summary = read_pairs(reader, |pairs| {
+                loaded.extend_from_slice(pairs);
+                chunks += 1;
+                Ok(())
+            })
+            .unwrap();
+
+            assert_eq!(summary.pairs, input.len() as u64);
+            assert_eq!(summary.trailing_bytes, 0);
+            assert_eq!(chunks, 3);
+            let expected: Vec<(Key, Value)> = input
+                .iter()
+                .map(|&(key, value)| (key as Key, value as Value))
+                .collect();
+            assert_eq!(loaded, expected);
+        }
+    }
+
+    #[test]
+    fn test_trailing_bytes() {
+        let mut bytes = encode(&[(1, 2), (i32::MIN, i32::MAX)]);
+        bytes.extend_from_slice(&[9, 9, 9]);
+
+        let mut loaded = Vec::new();
+        let summary = read_pairs(bytes.as_slice(), |pairs| {
+            loaded.extend_from_slice(pairs);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(loaded, vec![(1, 2), (i32::MIN as Key, i32::MAX as Value)]);
+        assert_eq!(
+            summary,
+            LoadSummary {
+                pairs: 2,
+                trailing_bytes: 3
+            }
+        );
+        assert_eq!(
+            summary.to_string(),
+            "Loaded 2 pairs, ignored 3 trailing bytes that don't make up a pair"
+        );
+
+        let empty = read_pairs(&[][..], |_| panic!("nothing to write")).unwrap();
+        assert_eq!(empty.to_string(), "Loaded 0 pairs");
+    }
+
+    #[test]
+    fn test_write_errors_stop_the_load() {
+        let bytes = encode(&[(1, 2)]);
+        let result = read_pairs(bytes.as_slice(), |_| Err(Error::BufferFull));
+        assert!(matches!(result, Err(Error::BufferFull)));
+    }
+}
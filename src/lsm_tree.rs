@@ -1,92 +1,1141 @@
+use crate::bloom::{BloomParams, FilterKind};
+use crate::compaction::{self, Compaction, LevelState, RunState};
+use crate::config::LSMConfig;
+use crate::filename::{self, FileKind};
 use crate::level::Level;
+use crate::load;
+use crate::manifest::{Manifest, ManifestState, VersionEdit};
 use crate::memtable::Memtable;
-use crate::run::Run;
-use crate::types::{Key, Result, Value, TOMBSTONE};
-use std::sync::{Arc, RwLock};
+use crate::merge::MergingIterator;
+use crate::run::{Lookup, Run};
+use crate::types::{Error, Key, Result, Value, TOMBSTONE};
+use crate::version::Version;
+use crate::wal::{Wal, WalRecord};
 
+pub use crate::load::LoadSummary;
+pub use crate::run::FilterCounts;
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock};
+use std::thread::{self, JoinHandle};
+
+/// A memtable together with the logs holding its records.
+struct Buffer {
+    memtable: Arc<Memtable>,
+    /// Logs that can be deleted once the memtable has been flushed
+    wal_ids: Vec<u64>,
+}
+
+/// Everything a reader needs, swapped atomically as the tree changes.
+#[derive(Clone)]
+struct State {
+    active: Arc<Buffer>,
+    /// Full memtables waiting for the flush thread, oldest first
+    immutables: VecDeque<Arc<Buffer>>,
+    version: Arc<Version>,
+}
+
+/// Bookkeeping for changes to the level structure, held while a flush or
+/// compaction installs a new version.
+struct VersionSet {
+    /// Record of which runs make up the tree; `None` for in-memory trees
+    manifest: Option<Manifest>,
+    /// Logs older than this have been fully flushed to runs
+    log_number: u64,
+    /// Levels read or written by a merge in progress
+    compacting: Vec<bool>,
+    /// Largest key last merged out of each level, see
+    /// [`LevelState::cursor`](crate::compaction::LevelState::cursor)
+    cursors: Vec<Option<Key>>,
+    /// Number of merges in progress
+    running: usize,
+    /// Bumped whenever a new version is installed
+    generation: u64,
+    /// Generation at which a worker last found nothing to merge
+    settled: Option<u64>,
+    /// A full compaction was asked for and isn't done yet
+    full_compaction: bool,
+}
+
+/// A merge claimed by a compaction worker.
+struct CompactionJob {
+    level: usize,
+    target: usize,
+    /// Runs to merge, oldest first
+    inputs: Vec<Arc<Run>>,
+    /// Removal of the inputs from the manifest
+    edit: VersionEdit,
+    max_run_len: Option<usize>,
+    drop_tombstones: bool,
+}
+
+struct Inner {
+    config: LSMConfig,
+    /// Number of entries that fill a memtable
+    buffer_capacity: usize,
+    /// Filter sizing derived from the error rate; with a non-uniform
+    /// allocation only its bits per key, the average over all entries, holds
+    bloom: BloomParams,
+    state: RwLock<State>,
+    /// Log backing the active memtable; held by writers so that log order
+    /// matches memtable order. `None` for in-memory trees.
+    writer: Mutex<Option<Wal>>,
+    versions: Mutex<VersionSet>,
+    next_file_id: AtomicU64,
+    /// Signalled whenever the state changes or the tree shuts down
+    work: Mutex<()>,
+    changed: Condvar,
+    shutdown: AtomicBool,
+    /// First error hit by a background thread; writes fail once it is set
+    background_error: Mutex<Option<String>>,
+}
+
+/// Size of one level of the tree, as reported by [`LSMTree::level_stats`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct LevelStats {
+    pub runs: usize,
+    /// Number of entries, tombstones included
+    pub entries: usize,
+    /// Filter parameters the allocation picks for the level as it stands
+    pub bloom: BloomParams,
+    /// Expected number of the level's runs a lookup for an absent key reads,
+    /// from the theoretical false-positive rates of their filters
+    pub expected_false_positives: f64,
+    /// What the level's filters did on lookups so far
+    pub filter: FilterCounts,
+    /// The level's runs, newest first
+    pub run_stats: Vec<RunStats>,
+}
+
+/// One run of a level and its filter, as reported by
+/// [`LSMTree::level_stats`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RunStats {
+    /// File id of the run in a persistent tree
+    pub id: Option<u64>,
+    /// Number of entries, tombstones included
+    pub entries: usize,
+    pub filter_kind: FilterKind,
+    /// Keys added to the filter
+    pub filter_entries: usize,
+    /// Bytes of memory the filter takes up
+    pub filter_bytes: usize,
+    /// Bits or slots of the filter a lookup checks
+    pub filter_probes: u32,
+    /// Theoretical false-positive rate of the filter
+    pub expected_false_positive_rate: f64,
+    /// What the filter did on lookups so far
+    pub filter: FilterCounts,
+}
+
+impl RunStats {
+    fn of(run: &Run) -> Self {
+        Self {
+            id: run.id(),
+            entries: run.len(),
+            filter_kind: run.filter_kind(),
+            filter_entries: run.filter_entries(),
+            filter_bytes: run.filter_memory_usage(),
+            filter_probes: run.filter_probes(),
+            expected_false_positive_rate: run.filter_false_positive_rate(),
+            filter: run.filter_counts(),
+        }
+    }
+}
+
+/// Where a tree's live keys are, as reported by [`LSMTree::tree_stats`].
+///
+/// Each key is counted once, at the level holding its current value. Level 0
+/// is the memtables and on-disk levels are numbered from 1, top level first.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TreeStats {
+    /// Live keys, tombstones and older versions left out
+    pub logical_pairs: usize,
+    /// Live keys whose current value is in each level
+    pub level_keys: Vec<usize>,
+    /// The live pairs of each level in key order, if a dump was asked for
+    pub dump: Option<Vec<Vec<(Key, Value)>>>,
+}
+
+impl std::fmt::Display for TreeStats {
+    /// Follows the CS265 `s` output: the pair count, the key count of each
+    /// non-empty level, then a line of `key:value:level` per level.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Logical Pairs: {}", self.logical_pairs)?;
+        let counts: Vec<String> = self
+            .level_keys
+            .iter()
+            .enumerate()
+            .filter(|&(_, &keys)| keys > 0)
+            .map(|(level, keys)| format!("LVL{}: {}", level, keys))
+            .collect();
+        if !counts.is_empty() {
+            write!(f, "\n{}", counts.join(", "))?;
+        }
+        for (level, pairs) in self.dump.iter().flatten().enumerate() {
+            if pairs.is_empty() {
+                continue;
+            }
+            let pairs: Vec<String> = pairs
+                .iter()
+                .map(|(key, value)| format!("{}:{}:L{}", key, value, level))
+                .collect();
+            write!(f, "\n{}", pairs.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+/// A log-structured merge tree.
+///
+/// Writes go to the active memtable (and its write-ahead log). A full memtable
+/// is frozen and handed to a background flush thread, and merges between
+/// levels run on a pool of compaction workers, so neither ever blocks a
+/// reader. Reads work on a snapshot of the memtables and the current
+/// [`Version`] of the levels, which stays consistent however the tree changes
+/// underneath.
 pub struct LSMTree {
-    buffer: Arc<RwLock<Memtable>>,
-    levels: Vec<Level>,
+    inner: Arc<Inner>,
+    threads: Vec<JoinHandle<()>>,
 }
 
 impl LSMTree {
+    /// Creates an in-memory tree whose write buffer holds `buffer_size`
+    /// pages, rounded up to one.
     pub fn new(buffer_size: usize) -> Self {
-        Self {
-            buffer: Arc::new(RwLock::new(Memtable::new(buffer_size))),
-            levels: Vec::new(),
+        Self::with_config(LSMConfig {
+            buffer_pages: buffer_size.max(1),
+            ..LSMConfig::default()
+        })
+        .expect("The default configuration is valid")
+    }
+
+    /// Opens the persistent tree in `path` with the default configuration,
+    /// creating it if it doesn't exist yet.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::with_config(LSMConfig {
+            data_dir: Some(path.as_ref().to_path_buf()),
+            ..LSMConfig::default()
+        })
+    }
+
+    /// Creates a tree from `config`, creating its data directory if needed.
+    /// Configurations that [`LSMConfig::validate`] rejects are refused.
+    ///
+    /// For persistent trees the levels recorded in the manifest are restored,
+    /// files a crash left behind are removed, and any write-ahead logs that
+    /// were not yet flushed are replayed before a fresh log is started.
+    pub fn with_config(config: LSMConfig) -> Result<Self> {
+        config.validate()?;
+        let memtable = Memtable::new(config.buffer_pages);
+        let inner = Arc::new(Inner {
+            buffer_capacity: memtable.max_size(),
+            bloom: BloomParams::from_error_rate(config.bloom_error_rate)
+                .with_kind(config.filter_kind)
+                .with_range_filter(config.range_filter),
+            state: RwLock::new(State {
+                active: Arc::new(Buffer {
+                    memtable: Arc::new(memtable),
+                    wal_ids: Vec::new(),
+                }),
+                immutables: VecDeque::new(),
+                version: Arc::new(Version::default()),
+            }),
+            writer: Mutex::new(None),
+            versions: Mutex::new(VersionSet {
+                manifest: None,
+                log_number: 0,
+                compacting: Vec::new(),
+                cursors: Vec::new(),
+                running: 0,
+                generation: 0,
+                settled: None,
+                full_compaction: false,
+            }),
+            next_file_id: AtomicU64::new(1),
+            work: Mutex::new(()),
+            changed: Condvar::new(),
+            shutdown: AtomicBool::new(false),
+            background_error: Mutex::new(None),
+            config,
+        });
+
+        if let Some(dir) = inner.config.data_dir.clone() {
+            fs::create_dir_all(&dir)?;
+            inner.recover(&dir)?;
+        }
+
+        let mut threads = Vec::new();
+        let flusher = Arc::clone(&inner);
+        threads.push(
+            thread::Builder::new()
+                .name("lsm-flush".to_string())
+                .spawn(move || flusher.flush_loop())?,
+        );
+        for worker in 0..inner.config.compaction_threads.max(1) {
+            let compactor = Arc::clone(&inner);
+            threads.push(
+                thread::Builder::new()
+                    .name(format!("lsm-compaction-{}", worker))
+                    .spawn(move || compactor.compaction_loop())?,
+            );
+        }
+
+        // Recovery may have left work behind
+        inner.notify();
+        Ok(Self { inner, threads })
+    }
+
+    pub fn put(&self, key: Key, value: Value) -> Result<()> {
+        self.inner.write(key, value)
+    }
+
+    /// Writes `entries` in order, as if by [`Self::put`] for each.
+    ///
+    /// The entries are logged and inserted a memtable's worth at a time, so
+    /// a large batch costs one log append per slice rather than per entry,
+    /// and other writers get their turn in between slices.
+    pub fn put_batch(&self, entries: &[(Key, Value)]) -> Result<()> {
+        self.inner.write_batch(entries)
+    }
+
+    /// Bulk-loads a file of packed key/value pairs as written by the CS265
+    /// workload generator.
+    pub fn load_file(&self, path: &Path) -> Result<LoadSummary> {
+        load::read_pairs(File::open(path)?, |pairs| self.put_batch(pairs))
+    }
+
+    pub fn get(&self, key: Key) -> Option<Value> {
+        let state = self.inner.snapshot();
+
+        // Check buffers first, newest to oldest
+        let buffers = std::iter::once(&state.active).chain(state.immutables.iter().rev());
+        let value = buffers
+            .filter_map(|buffer| buffer.memtable.get(&key))
+            .next()
+            .or_else(|| state.version.get(key));
+
+        // Ignore tombstone values
+        value.filter(|&value| value != TOMBSTONE)
+    }
+
+    /// Looks up a batch of keys against a single snapshot, returning their
+    /// values in the same order.
+    ///
+    /// Each key is hashed once for all runs, and the runs are probed level by
+    /// level with the filter memory for a batch of keys prefetched together,
+    /// which is cheaper than calling [`Self::get`] for each key.
+    pub fn multi_get(&self, keys: &[Key]) -> Vec<Option<Value>> {
+        let state = self.inner.snapshot();
+        let buffers: Vec<_> = std::iter::once(&state.active)
+            .chain(state.immutables.iter().rev())
+            .collect();
+
+        let mut results = vec![None; keys.len()];
+        let mut lookups = Vec::new();
+        for (index, &key) in keys.iter().enumerate() {
+            match buffers.iter().find_map(|buffer| buffer.memtable.get(&key)) {
+                Some(value) => results[index] = Some(value),
+                None => lookups.push(Lookup::new(index, key)),
+            }
+        }
+        state.version.multi_get(lookups, &mut results);
+
+        // Ignore tombstone values
+        results
+            .into_iter()
+            .map(|value| value.filter(|&value| value != TOMBSTONE))
+            .collect()
+    }
+
+    pub fn range(&self, start: Key, end: Key) -> Vec<(Key, Value)> {
+        self.scan(start, end).collect()
+    }
+
+    /// Streams the live entries in `[start, end)` in key order.
+    ///
+    /// Every memtable and run contributes a cursor to a k-way merge, so
+    /// entries are produced one at a time with the newest version of each key
+    /// winning and deleted keys skipped. The scan reads from the snapshot taken
+    /// when it starts and is unaffected by later writes. Memtables can still
+    /// change underneath, so their matches are copied up front; they are small
+    /// and bounded by the buffer size.
+    pub fn scan(&self, start: Key, end: Key) -> impl Iterator<Item = (Key, Value)> {
+        let state = self.inner.snapshot();
+        let buffers = std::iter::once(&state.active).chain(state.immutables.iter().rev());
+
+        let mut sources: Vec<Box<dyn Iterator<Item = (Key, Value)> + Send>> = Vec::new();
+        for buffer in buffers {
+            sources.push(Box::new(buffer.memtable.range(start, end).into_iter()));
+        }
+        for cursor in state.version.cursors(start, end) {
+            sources.push(Box::new(cursor));
         }
+
+        MergingIterator::new(sources).filter(|&(_, value)| value != TOMBSTONE)
     }
 
-    pub fn put(&mut self, key: Key, value: Value) -> Result<()> {
-        let flush_required = {
-            let buffer = self.buffer.write().unwrap();
-            let result = buffer.put(key, value);
-            result.is_ok() && buffer.is_full()
+    pub fn delete(&self, key: Key) -> Result<()> {
+        self.put(key, TOMBSTONE)
+    }
+
+    /// Freezes the active memtable and waits until every frozen memtable has
+    /// been written to a run.
+    pub fn flush(&self) -> Result<()> {
+        {
+            let mut wal = self.inner.writer.lock().unwrap();
+            if !self.inner.snapshot().active.memtable.is_empty() {
+                self.inner.freeze(&mut wal)?;
+            }
+        }
+        self.inner
+            .wait_until(|inner| inner.state.read().unwrap().immutables.is_empty())
+    }
+
+    /// Waits until no merge is running and the compaction policy has nothing
+    /// left to do.
+    pub fn wait_for_compactions(&self) -> Result<()> {
+        self.inner.wait_until(|inner| {
+            let versions = inner.versions.lock().unwrap();
+            versions.running == 0 && versions.settled == Some(versions.generation)
+        })
+    }
+
+    /// Flushes the memtables and merges every run into a single run in the
+    /// last level, whatever the compaction policy would do.
+    ///
+    /// The merges are made by the compaction workers one level at a time,
+    /// once the policy has nothing left to do, so they never race with its
+    /// own. Returns when the tree has settled again.
+    pub fn compact(&self) -> Result<()> {
+        self.flush()?;
+        self.inner.versions.lock().unwrap().full_compaction = true;
+        self.inner.notify();
+        self.inner.wait_until(|inner| {
+            let versions = inner.versions.lock().unwrap();
+            !versions.full_compaction
+                && versions.running == 0
+                && versions.settled == Some(versions.generation)
+        })
+    }
+
+    /// Bloom filter parameters derived from the configured error rate.
+    pub fn bloom_params(&self) -> BloomParams {
+        self.inner.bloom
+    }
+
+    /// Runs, entries and filter behaviour of every level, top level first.
+    pub fn level_stats(&self) -> Vec<LevelStats> {
+        let version = self.inner.snapshot().version;
+        let shape = compaction::level_states(version.levels(), &[], &[]);
+        let params = self.inner.allocate_filters(&shape);
+        version
+            .levels()
+            .iter()
+            .zip(shape.iter().zip(params))
+            .map(|(level, (state, bloom))| {
+                let mut filter = FilterCounts::default();
+                for run in level.runs() {
+                    filter += run.filter_counts();
+                }
+                LevelStats {
+                    runs: state.runs.len(),
+                    entries: state.len(),
+                    bloom,
+                    expected_false_positives: level
+                        .runs()
+                        .iter()
+                        .map(|run| run.filter_false_positive_rate())
+                        .sum(),
+                    filter,
+                    run_stats: level
+                        .runs()
+                        .iter()
+                        .rev()
+                        .map(|run| RunStats::of(run))
+                        .collect(),
+                }
+            })
+            .collect()
+    }
+
+    /// Counts the live keys by the level their current value is in, and with
+    /// `dump` set collects them too.
+    ///
+    /// Reads every memtable and run of a snapshot, so it costs a full scan of
+    /// the tree; without `dump` only the counts are kept in memory.
+    pub fn tree_stats(&self, dump: bool) -> TreeStats {
+        let state = self.inner.snapshot();
+        let buffers = std::iter::once(&state.active).chain(state.immutables.iter().rev());
+
+        // Sources newest first, along with the level each belongs to
+        let mut sources: Vec<Box<dyn Iterator<Item = (Key, Value)> + '_>> = Vec::new();
+        let mut source_levels = Vec::new();
+        for buffer in buffers {
+            sources.push(Box::new(buffer.memtable.iter().into_iter()));
+            source_levels.push(0);
+        }
+        for (level, runs) in state.version.levels().iter().enumerate() {
+            for run in runs.runs().iter().rev() {
+                sources.push(Box::new(run.entries()));
+                source_levels.push(level + 1);
+            }
+        }
+
+        let levels = state.version.levels().len() + 1;
+        let mut stats = TreeStats {
+            logical_pairs: 0,
+            level_keys: vec![0; levels],
+            dump: dump.then(|| vec![Vec::new(); levels]),
         };
+        let mut merged = MergingIterator::new(sources);
+        while let Some((key, value, source)) = merged.next_with_source() {
+            if value == TOMBSTONE {
+                continue;
+            }
+            let level = source_levels[source];
+            stats.logical_pairs += 1;
+            stats.level_keys[level] += 1;
+            if let Some(dump) = &mut stats.dump {
+                dump[level].push((key, value));
+            }
+        }
+        stats
+    }
+
+    #[cfg(test)]
+    fn version(&self) -> Arc<Version> {
+        self.inner.snapshot().version
+    }
+}
 
-        if flush_required {
-            self.flush_buffer_to_level0()?;
+impl Drop for LSMTree {
+    fn drop(&mut self) {
+        // The flush thread drains the frozen memtables before it exits
+        self.inner.shutdown.store(true, Ordering::Release);
+        self.inner.notify();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Inner {
+    fn snapshot(&self) -> State {
+        self.state.read().unwrap().clone()
+    }
+
+    /// Filter parameters for each level of a tree shaped like `shape`.
+    fn allocate_filters(&self, shape: &[LevelState]) -> Vec<BloomParams> {
+        self.config
+            .bloom_allocation
+            .allocate(shape, self.bloom.bits_per_key)
+            .into_iter()
+            .map(|params| {
+                params
+                    .with_kind(self.bloom.kind)
+                    .with_range_filter(self.bloom.range)
+            })
+            .collect()
+    }
+
+    /// Filter parameters for a run of `entries` about to be added to `level`
+    /// of `version`.
+    ///
+    /// The allocation is recomputed from the shape of the tree at the time,
+    /// so runs written as the levels grow follow the sizes they have reached.
+    fn bloom_for(&self, version: &Version, level: usize, entries: usize) -> BloomParams {
+        let mut shape = compaction::level_states(version.levels(), &[], &[]);
+        if shape.len() <= level {
+            shape.resize_with(level + 1, LevelState::default);
+        }
+        shape[level].runs.push(RunState {
+            len: entries,
+            min_key: Key::MAX,
+            max_key: Key::MIN,
+        });
+        self.allocate_filters(&shape)[level]
+    }
+
+    fn notify(&self) {
+        let _guard = self.work.lock().unwrap();
+        self.changed.notify_all();
+    }
+
+    /// Blocks until `done` holds, failing if a background thread gave up.
+    fn wait_until(&self, done: impl Fn(&Self) -> bool) -> Result<()> {
+        let mut guard = self.work.lock().unwrap();
+        loop {
+            self.check_background_error()?;
+            if done(self) {
+                return Ok(());
+            }
+            if self.shutdown.load(Ordering::Acquire) {
+                return Err(Error::Storage("The tree is shutting down".to_string()));
+            }
+            guard = self.changed.wait(guard).unwrap();
+        }
+    }
+
+    fn check_background_error(&self) -> Result<()> {
+        match &*self.background_error.lock().unwrap() {
+            Some(msg) => Err(Error::Storage(format!("Background work failed: {}", msg))),
+            None => Ok(()),
+        }
+    }
+
+    fn set_background_error(&self, error: Error) {
+        eprintln!("Background work failed: {}", error);
+        self.background_error
+            .lock()
+            .unwrap()
+            .get_or_insert(error.to_string());
+        self.notify();
+    }
+
+    fn allocate_file_id(&self) -> u64 {
+        self.next_file_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn write(&self, key: Key, value: Value) -> Result<()> {
+        self.check_background_error()?;
+        let mut wal = self.writer.lock().unwrap();
+
+        // The write is only acknowledged once it has been logged
+        if let Some(wal) = wal.as_ref() {
+            wal.append(WalRecord::from_write(key, value))?;
+        }
+        let full = {
+            let state = self.state.read().unwrap();
+            state.active.memtable.put(key, value)?;
+            state.active.memtable.is_full()
+        };
+
+        if full {
+            self.freeze(&mut wal)?;
         }
         Ok(())
     }
 
-    pub fn get(&self, key: Key) -> Option<Value> {
-        // Check buffer first
-        if let Some(value) = self.buffer.read().unwrap().get(&key) {
-            if value == TOMBSTONE {
-                return None; // Ignore tombstone values
+    /// Logs and applies `entries` in slices that fit in the active memtable,
+    /// taking the writer lock once per slice.
+    ///
+    /// A slice never spans a memtable switch: the records of a memtable must
+    /// all be in the logs that are kept until it has been flushed.
+    fn write_batch(&self, mut entries: &[(Key, Value)]) -> Result<()> {
+        while !entries.is_empty() {
+            self.check_background_error()?;
+            let mut wal = self.writer.lock().unwrap();
+
+            // The active memtable only changes under the writer lock
+            let memtable = Arc::clone(&self.state.read().unwrap().active.memtable);
+            let room = memtable.max_size().saturating_sub(memtable.len()).max(1);
+            let (slice, rest) = entries.split_at(room.min(entries.len()));
+
+            if let Some(wal) = wal.as_ref() {
+                let records: Vec<_> = slice
+                    .iter()
+                    .map(|&(key, value)| WalRecord::from_write(key, value))
+                    .collect();
+                wal.append_batch(&records)?;
+            }
+            for &(key, value) in slice {
+                memtable.put(key, value)?;
+            }
+
+            if memtable.is_full() {
+                self.freeze(&mut wal)?;
+            }
+            entries = rest;
+        }
+        Ok(())
+    }
+
+    /// Hands the active memtable to the flush thread and starts a new one,
+    /// with a new log if the tree is persistent.
+    ///
+    /// Stalls while the flush thread is `max_immutable_memtables` behind. The
+    /// caller holds the writer lock, so every other write waits as well and
+    /// memory stays bounded however fast clients write.
+    fn freeze(&self, wal: &mut MutexGuard<Option<Wal>>) -> Result<()> {
+        let max_immutables = self.config.max_immutable_memtables.max(1);
+        self.wait_until(|inner| inner.state.read().unwrap().immutables.len() < max_immutables)?;
+
+        let mut wal_ids = Vec::new();
+        if let Some(dir) = &self.config.data_dir {
+            let id = self.allocate_file_id();
+            let new_wal = Wal::create(&filename::wal_path(dir, id), self.config.wal_sync)?;
+            // Dropping the old log syncs it
+            drop(wal.replace(new_wal));
+            wal_ids.push(id);
+        }
+        self.rotate(wal_ids);
+        self.notify();
+        Ok(())
+    }
+
+    fn rotate(&self, wal_ids: Vec<u64>) {
+        let mut state = self.state.write().unwrap();
+        let active = Arc::new(Buffer {
+            memtable: Arc::new(Memtable::new(self.config.buffer_pages)),
+            wal_ids,
+        });
+        let frozen = std::mem::replace(&mut state.active, active);
+        state.immutables.push_back(frozen);
+    }
+
+    /// Rebuilds the tree from the files in `dir`.
+    ///
+    /// Only runs named by the manifest are part of the tree. Anything else is
+    /// debris from a flush or compaction that crashed before committing its
+    /// manifest edit, and is deleted; the data it held is still in the logs or
+    /// in the runs it was meant to replace.
+    fn recover(&self, dir: &Path) -> Result<()> {
+        let (manifest_state, managed) = match Manifest::recover(dir)? {
+            Some(state) => (state, true),
+            None => (Self::adopt_unmanaged_runs(dir)?, false),
+        };
+
+        let mut levels = Vec::new();
+        for ids in &manifest_state.levels {
+            let mut level = Level::new();
+            for &id in ids {
+                level.add_run(Run::restore(&filename::run_path(dir, id))?);
             }
-            return Some(value);
+            levels.push(level);
         }
+        let log_number = manifest_state.log_number;
+        let mut next_file_id = manifest_state.next_file_id.max(1);
 
-        // Check levels
-        for level in &self.levels {
-            if let Some(value) = level.get(key) {
-                if value == TOMBSTONE {
-                    return None; // Ignore tombstone values
+        let mut wals = Vec::new();
+        let mut old_manifests = Vec::new();
+        let mut kept = 0;
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let Some(kind) = filename::parse(&path) else {
+                continue;
+            };
+            // Never hand out a file id that is already in use
+            if let Some(id) = kind.id() {
+                next_file_id = next_file_id.max(id + 1);
+            }
+
+            let obsolete = match kind {
+                FileKind::Run(id) => !manifest_state.contains_run(id),
+                FileKind::Wal(id) if id >= log_number => {
+                    wals.push(id);
+                    false
                 }
-                return Some(value);
+                FileKind::Wal(_) | FileKind::Temp => true,
+                // Superseded by the snapshot written below
+                FileKind::Manifest(_) if managed => {
+                    old_manifests.push(path.clone());
+                    false
+                }
+                FileKind::Manifest(_) => true,
+                FileKind::Current => false,
+            };
+            // Without a manifest nothing says which files are stale, so none
+            // are deleted
+            if obsolete && managed {
+                fs::remove_file(&path)?;
+            } else if obsolete {
+                kept += 1;
             }
         }
+        if kept > 0 {
+            eprintln!(
+                "No CURRENT file in {}; left {} files it doesn't account for in place",
+                dir.display(),
+                kept
+            );
+        }
+        wals.sort();
 
-        None
+        // Start a fresh manifest so recovery never has to replay a long log
+        let version = Version::new(levels);
+        self.next_file_id.store(next_file_id, Ordering::Relaxed);
+        let manifest_id = self.allocate_file_id();
+        let snapshot = version.manifest_state(
+            log_number,
+            self.next_file_id.load(Ordering::Relaxed),
+            manifest_state.cursors.clone(),
+        );
+        {
+            let mut versions = self.versions.lock().unwrap();
+            versions.manifest = Some(Manifest::create(dir, manifest_id, &snapshot.snapshot())?);
+            versions.log_number = log_number;
+            versions.cursors = manifest_state.cursors;
+        }
+        self.state.write().unwrap().version = Arc::new(version);
+        for path in old_manifests.into_iter().filter(|path| path.exists()) {
+            fs::remove_file(path)?;
+        }
+
+        // Memtables filled during replay are flushed straight away. The
+        // replayed logs are kept until the last of their records has been
+        // flushed, so a second crash before then loses nothing.
+        for &id in &wals {
+            for record in Wal::replay(&filename::wal_path(dir, id))? {
+                let (key, value) = record.into_write();
+                let memtable = Arc::clone(&self.snapshot().active.memtable);
+                memtable.put(key, value)?;
+                if memtable.is_full() {
+                    self.rotate(Vec::new());
+                    self.flush_oldest()?;
+                }
+            }
+        }
+
+        let wal_id = self.allocate_file_id();
+        let wal = Wal::create(&filename::wal_path(dir, wal_id), self.config.wal_sync)?;
+        *self.writer.lock().unwrap() = Some(wal);
+        wals.push(wal_id);
+
+        let mut state = self.state.write().unwrap();
+        state.active = Arc::new(Buffer {
+            memtable: Arc::clone(&state.active.memtable),
+            wal_ids: wals,
+        });
+        Ok(())
     }
 
-    pub fn range(&self, start: Key, end: Key) -> Vec<(Key, Value)> {
-        let mut results = self.buffer.read().unwrap().range(start, end);
+    /// Builds the initial state for a directory without a manifest.
+    ///
+    /// Run files are only ever renamed into place once complete, so any that
+    /// exist were written by a version that predates the manifest and are
+    /// placed in level 0, oldest first.
+    fn adopt_unmanaged_runs(dir: &Path) -> Result<ManifestState> {
+        let mut ids = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            if let Some(FileKind::Run(id)) = filename::parse(&entry?.path()) {
+                ids.push(id);
+            }
+        }
+        ids.sort();
+
+        let mut state = ManifestState::default();
+        if !ids.is_empty() {
+            state.levels.push(ids);
+        }
+        Ok(state)
+    }
+
+    fn flush_loop(&self) {
+        loop {
+            let buffer = {
+                let mut guard = self.work.lock().unwrap();
+                loop {
+                    if !self.state.read().unwrap().immutables.is_empty() {
+                        break true;
+                    }
+                    if self.shutdown.load(Ordering::Acquire) {
+                        break false;
+                    }
+                    guard = self.changed.wait(guard).unwrap();
+                }
+            };
+            if !buffer {
+                return;
+            }
+            if let Err(e) = self.flush_oldest() {
+                self.set_background_error(e);
+                return;
+            }
+        }
+    }
 
-        // Add results from levels
-        for level in &self.levels {
-            results.extend(level.range(start, end));
+    /// Writes the oldest frozen memtable to a new run in the first level.
+    fn flush_oldest(&self) -> Result<()> {
+        let Some(buffer) = self.snapshot().immutables.front().cloned() else {
+            return Ok(());
+        };
+        // Readers may still be looking at the memtable, so it is copied rather
+        // than drained
+        let entries = buffer.memtable.iter();
+        let bloom = self.bloom_for(&self.snapshot().version, 0, entries.len());
+        let mut run = Run::new(entries, bloom);
+
+        // Only drop the buffered data once the run is safely on disk
+        let mut edit = VersionEdit::default();
+        if let Some(dir) = &self.config.data_dir {
+            let id = self.allocate_file_id();
+            run.persist(&filename::run_path(dir, id))?;
+            // Lookups read its blocks from the file from now on
+            run.release_blocks();
+            filename::sync_dir(dir)?;
+            edit.add_run(0, id);
         }
 
-        // Sort by key and remove duplicates, keeping only the most recent value
-        results.sort_by_key(|&(key, _)| key);
-        results.dedup_by_key(|&mut (key, _)| key);
+        {
+            let mut versions = self.versions.lock().unwrap();
+            let state = self.snapshot();
+            let version = state.version.with_flushed_run(Arc::new(run));
 
-        // Filter out tombstones
-        results.retain(|&(_, value)| value != TOMBSTONE);
+            // Logs still backing a memtable must survive a crash
+            let remaining = state.immutables.iter().skip(1).chain([&state.active]);
+            if let Some(log_number) = remaining.flat_map(|b| b.wal_ids.iter().copied()).min() {
+                versions.log_number = log_number;
+                edit.log_number = Some(log_number);
+            }
+            self.install(&mut versions, version, edit)?;
 
-        results
+            let mut state = self.state.write().unwrap();
+            debug_assert!(Arc::ptr_eq(&state.immutables[0], &buffer));
+            state.immutables.pop_front();
+        }
+
+        // Everything in these logs is now in a run the manifest knows about
+        if let Some(dir) = &self.config.data_dir {
+            for &id in &buffer.wal_ids {
+                fs::remove_file(filename::wal_path(dir, id))?;
+            }
+        }
+        self.notify();
+        Ok(())
     }
 
-    pub fn delete(&mut self, key: Key) -> Result<()> {
-        self.put(key, TOMBSTONE)
+    /// Commits `edit` to the manifest and makes `version` current.
+    ///
+    /// The manifest rolls over to a fresh snapshot once it has grown long.
+    fn install(
+        &self,
+        versions: &mut VersionSet,
+        version: Version,
+        mut edit: VersionEdit,
+    ) -> Result<()> {
+        if let Some(manifest) = versions.manifest.as_mut() {
+            edit.next_file_id = Some(self.next_file_id.load(Ordering::Relaxed));
+            manifest.log_edit(&edit)?;
+        }
+        for &(level, key) in &edit.cursors {
+            if versions.cursors.len() <= level {
+                versions.cursors.resize(level + 1, None);
+            }
+            versions.cursors[level] = Some(key);
+        }
+
+        if let Some(manifest) = versions.manifest.as_ref().filter(|m| m.should_snapshot()) {
+            let dir = manifest.dir().to_path_buf();
+            let id = self.allocate_file_id();
+            let next_file_id = self.next_file_id.load(Ordering::Relaxed);
+            let snapshot =
+                version.manifest_state(versions.log_number, next_file_id, versions.cursors.clone());
+            versions.manifest = Some(Manifest::create(&dir, id, &snapshot.snapshot())?);
+        }
+
+        versions.generation += 1;
+        self.state.write().unwrap().version = Arc::new(version);
+        Ok(())
     }
 
-    fn flush_buffer_to_level0(&mut self) -> Result<()> {
-        let data = {
-            let buffer = self.buffer.write().unwrap();
-            let data = buffer.take_all(); // Get copy of all data
-            buffer.clear();
-            data
+    fn compaction_loop(&self) {
+        loop {
+            let job = {
+                let mut guard = self.work.lock().unwrap();
+                loop {
+                    if self.shutdown.load(Ordering::Acquire) {
+                        return;
+                    }
+                    if let Some(job) = self.claim_compaction() {
+                        break job;
+                    }
+                    guard = self.changed.wait(guard).unwrap();
+                }
+            };
+
+            let result = self.run_compaction(&job);
+            {
+                let mut versions = self.versions.lock().unwrap();
+                versions.compacting[job.level] = false;
+                versions.compacting[job.target] = false;
+                versions.running -= 1;
+            }
+            if let Err(e) = result {
+                self.set_background_error(e);
+                return;
+            }
+            self.notify();
+        }
+    }
+
+    /// Asks the policy for the next merge and reserves its levels, so that
+    /// concurrent workers only ever pick disjoint work.
+    fn claim_compaction(&self) -> Option<CompactionJob> {
+        if self.background_error.lock().unwrap().is_some() {
+            return None;
+        }
+        let mut versions = self.versions.lock().unwrap();
+        let version = self.snapshot().version;
+        let levels = version.levels();
+        let states = compaction::level_states(levels, &versions.compacting, &versions.cursors);
+        let Some(Compaction {
+            level,
+            runs,
+            target,
+            target_runs,
+            max_run_len,
+        }) = self
+            .config
+            .compaction
+            .pick(&states, self.buffer_capacity, self.config.fanout)
+            .or_else(|| self.full_compaction_step(&mut versions, &states))
+        else {
+            // Let anyone waiting for the tree to settle know, without waking
+            // the other workers over and over
+            if versions.running == 0 && versions.settled != Some(versions.generation) {
+                versions.settled = Some(versions.generation);
+                self.changed.notify_all();
+            }
+            return None;
         };
 
-        let run = Run::new(data);
-        if self.levels.is_empty() {
-            self.levels.push(Level::new());
+        // The target's runs hold older data than the level being pushed down
+        let empty = Level::new();
+        let level_runs = |index: usize| levels.get(index).unwrap_or(&empty).runs();
+        let mut inputs = Vec::new();
+        let mut edit = VersionEdit::default();
+        for (index, runs) in [(target, &target_runs), (level, &runs)] {
+            for &run in runs {
+                let run = &level_runs(index)[run];
+                if let Some(id) = run.id() {
+                    edit.remove_run(index, id);
+                }
+                inputs.push(Arc::clone(run));
+            }
+        }
+        // Round-robin policies resume after the pushed down runs, but only
+        // once the merge is installed
+        if target != level {
+            if let Some(max_key) = runs
+                .iter()
+                .map(|&run| states[level].runs[run].max_key)
+                .max()
+            {
+                edit.set_cursor(level, max_key);
+            }
+        }
+
+        // Deletes can be forgotten once nothing older than the merged runs is
+        // left: no deeper levels, and no unmerged run in the target that
+        // overlaps them. Runs flushed meanwhile are newer, so they don't count.
+        let merged_range = inputs
+            .iter()
+            .filter_map(|run| run.key_range())
+            .reduce(|a, b| (a.0.min(b.0), a.1.max(b.1)));
+        let drop_tombstones = target + 1 >= levels.len()
+            && merged_range.is_some_and(|(min_key, max_key)| {
+                let remaining = level_runs(target)
+                    .iter()
+                    .filter(|run| !inputs.iter().any(|input| Arc::ptr_eq(input, run)));
+                !remaining
+                    .filter_map(|run| run.key_range())
+                    .any(|(min, max)| min <= max_key && max >= min_key)
+            });
+
+        if versions.compacting.len() <= target {
+            versions.compacting.resize(target + 1, false);
+        }
+        versions.compacting[level] = true;
+        versions.compacting[target] = true;
+        versions.running += 1;
+        Some(CompactionJob {
+            level,
+            target,
+            inputs,
+            edit,
+            max_run_len,
+            drop_tombstones,
+        })
+    }
+
+    /// The next merge of a full compaction: each level in turn is merged
+    /// into the one below, until a single run is left in the last level.
+    ///
+    /// Only taken while no other merge runs. Clears the request once there
+    /// is nothing left to merge.
+    fn full_compaction_step(
+        &self,
+        versions: &mut VersionSet,
+        levels: &[LevelState],
+    ) -> Option<Compaction> {
+        if !versions.full_compaction || versions.running > 0 {
+            return None;
+        }
+        let mut non_empty = (0..levels.len()).filter(|&index| !levels[index].runs.is_empty());
+        let step = match (non_empty.next(), non_empty.next()) {
+            (Some(first), Some(_)) => Some(Compaction::whole_level(levels, first, first + 1, true)),
+            (Some(last), None) if levels[last].runs.len() > 1 => {
+                Some(Compaction::whole_level(levels, last, last, false))
+            }
+            _ => None,
+        };
+        if step.is_none() {
+            // The tree may have been settled already, in which case nothing
+            // else would wake the caller of `compact`
+            versions.full_compaction = false;
+            self.changed.notify_all();
+        }
+        step
+    }
+
+    fn run_compaction(&self, job: &CompactionJob) -> Result<()> {
+        let mut edit = job.edit.clone();
+
+        // A lone run with nothing to merge into can move down as it is
+        let trivial_move = match job.inputs.as_slice() {
+            [run] => {
+                job.target != job.level && job.max_run_len.map_or(true, |max| run.len() <= max)
+            }
+            _ => false,
+        };
+
+        let mut outputs = Vec::new();
+        if trivial_move {
+            outputs.push(Arc::clone(&job.inputs[0]));
+        } else {
+            let inputs = job.inputs.iter().map(|run| run.as_ref());
+            let merged = compaction::merge_runs(inputs, job.drop_tombstones);
+            let chunk_len = job.max_run_len.unwrap_or(merged.len()).max(1);
+            // Size the filters for the tree as it will be once the inputs are gone
+            let remaining = self.snapshot().version.with_compaction(
+                job.level,
+                job.target,
+                &job.inputs,
+                Vec::new(),
+            );
+            let bloom = self.bloom_for(&remaining, job.target, merged.len());
+            for chunk in merged.chunks(chunk_len) {
+                let mut run = Run::new(chunk.to_vec(), bloom);
+                if let Some(dir) = &self.config.data_dir {
+                    run.persist(&filename::run_path(dir, self.allocate_file_id()))?;
+                    run.release_blocks();
+                }
+                outputs.push(Arc::new(run));
+            }
+            if let Some(dir) = &self.config.data_dir {
+                filename::sync_dir(dir)?;
+            }
+        }
+        for id in outputs.iter().filter_map(|run| run.id()) {
+            edit.add_run(job.target, id);
+        }
+
+        {
+            let mut versions = self.versions.lock().unwrap();
+            let current = self.snapshot().version;
+            let version = current.with_compaction(job.level, job.target, &job.inputs, outputs);
+            self.install(&mut versions, version, edit)?;
+        }
+
+        // The inputs may only be deleted once the manifest no longer needs them
+        if !trivial_move {
+            for path in job.inputs.iter().filter_map(|run| run.path()) {
+                fs::remove_file(path)?;
+            }
         }
-        self.levels[0].add_run(run);
         Ok(())
     }
 }
@@ -94,10 +1143,20 @@ impl LSMTree {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::bloom::{FilterAllocation, FilterKind, RangeFilterParams};
+    use crate::compaction::{
+        CompactionPolicy, FileChoice, Hybrid, LazyLeveled, Leveled, Partial, Tiered,
+    };
+    use crate::test_helpers::temp_dir;
+    use crate::wal::SyncMode;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::collections::BTreeMap;
+    use std::sync::atomic::AtomicI64;
 
     #[test]
     fn test_put_and_get() {
-        let mut lsm_tree = LSMTree::new(128);
+        let lsm_tree = LSMTree::new(128);
         lsm_tree.put(1, 100).unwrap();
         lsm_tree.put(2, 200).unwrap();
 
@@ -108,7 +1167,7 @@ mod tests {
 
     #[test]
     fn test_range_query() {
-        let mut lsm_tree = LSMTree::new(128);
+        let lsm_tree = LSMTree::new(128);
         lsm_tree.put(1, 100).unwrap();
         lsm_tree.put(2, 200).unwrap();
         lsm_tree.put(3, 300).unwrap();
@@ -119,10 +1178,1012 @@ mod tests {
 
     #[test]
     fn test_delete() {
-        let mut lsm_tree = LSMTree::new(128);
+        let lsm_tree = LSMTree::new(128);
         lsm_tree.put(1, 100).unwrap();
         lsm_tree.delete(1).unwrap();
 
         assert_eq!(lsm_tree.get(1), None);
     }
+
+    #[test]
+    fn test_scan() {
+        let lsm_tree = LSMTree::new(1);
+        let capacity = lsm_tree.inner.buffer_capacity as Key;
+        for i in 0..capacity * 3 {
+            lsm_tree.put(i, i).unwrap();
+        }
+        settle(&lsm_tree);
+
+        // Updates and deletes in the memtable shadow the runs below
+        for i in (0..capacity * 3).step_by(3) {
+            lsm_tree.delete(i).unwrap();
+        }
+        lsm_tree.put(1, -1).unwrap();
+
+        let mut scan = lsm_tree.scan(0, capacity * 3);
+        assert_eq!(scan.next(), Some((1, -1)));
+        assert_eq!(scan.next(), Some((2, 2)));
+
+        // The scan keeps reading the snapshot it started from
+        lsm_tree.put(4, -4).unwrap();
+        assert_eq!(scan.next(), Some((4, 4)));
+        let rest: Vec<_> = scan.collect();
+        assert_eq!(rest.len() as Key, capacity * 2 - 3);
+        assert!(rest.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert!(rest
+            .iter()
+            .all(|&(key, value)| key == value && key % 3 != 0));
+
+        assert_eq!(lsm_tree.scan(10, 10).next(), None);
+        assert_eq!(lsm_tree.scan(4, 5).collect::<Vec<_>>(), vec![(4, -4)]);
+    }
+
+    #[test]
+    fn test_bloom_params_follow_error_rate() {
+        let lsm_tree = LSMTree::with_config(LSMConfig {
+            buffer_pages: 1,
+            bloom_error_rate: 0.001,
+            ..LSMConfig::default()
+        })
+        .unwrap();
+        assert_eq!(lsm_tree.bloom_params(), BloomParams::from_error_rate(0.001));
+        assert_eq!(
+            LSMTree::new(1).bloom_params(),
+            BloomParams::from_error_rate(crate::config::DEFAULT_BLOOM_ERROR_RATE)
+        );
+    }
+
+    #[test]
+    fn test_filter_stats() {
+        let lsm_tree = LSMTree::with_config(LSMConfig {
+            buffer_pages: 1,
+            bloom_error_rate: 0.05,
+            ..LSMConfig::default()
+        })
+        .unwrap();
+        let num_keys = (lsm_tree.inner.buffer_capacity * 10) as Key;
+        for i in 0..num_keys {
+            lsm_tree.put(i * 2, i).unwrap();
+        }
+        lsm_tree.flush().unwrap();
+        lsm_tree.wait_for_compactions().unwrap();
+
+        // Every lookup for an absent key consults the filter of every run
+        let lookups = 50_000;
+        for i in 0..lookups {
+            assert_eq!(lsm_tree.get(i * 2 + 1), None);
+        }
+        let stats = lsm_tree.level_stats();
+        let runs: usize = stats.iter().map(|level| level.runs).sum();
+        let mut filter = FilterCounts::default();
+        for level in &stats {
+            filter += level.filter;
+        }
+        assert_eq!(
+            filter.true_negatives + filter.false_positives,
+            (lookups as usize * runs) as u64
+        );
+
+        // And about as many get past them as their sizes predict
+        let expected: f64 = stats
+            .iter()
+            .map(|level| level.expected_false_positives)
+            .sum();
+        let observed = filter.false_positives as f64 / lookups as f64;
+        assert!(
+            (observed - expected).abs() < expected * 0.2,
+            "observed {}, expected {}",
+            observed,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_multi_get() {
+        let lsm_tree = LSMTree::with_config(LSMConfig {
+            buffer_pages: 1,
+            ..LSMConfig::default()
+        })
+        .unwrap();
+        let num_keys = (lsm_tree.inner.buffer_capacity * 5) as Key;
+        for i in 0..num_keys {
+            lsm_tree.put(i * 2, i).unwrap();
+        }
+        lsm_tree.flush().unwrap();
+        lsm_tree.wait_for_compactions().unwrap();
+
+        // Newer values in memory shadow the runs, tombstones included
+        lsm_tree.put(0, -1).unwrap();
+        lsm_tree.delete(2).unwrap();
+
+        let keys: Vec<Key> = (-10..num_keys * 2 + 10).rev().collect();
+        let expected: Vec<_> = keys.iter().map(|&key| lsm_tree.get(key)).collect();
+        assert_eq!(lsm_tree.multi_get(&keys), expected);
+        assert_eq!(
+            lsm_tree.multi_get(&[0, 1, 2, 4, 4]),
+            vec![Some(-1), None, None, Some(2), Some(2)]
+        );
+        assert!(lsm_tree.multi_get(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_tree_stats() {
+        let lsm_tree = LSMTree::with_config(LSMConfig {
+            buffer_pages: 1,
+            ..LSMConfig::default()
+        })
+        .unwrap();
+        assert_eq!(lsm_tree.tree_stats(true).to_string(), "Logical Pairs: 0");
+
+        let num_keys = (lsm_tree.inner.buffer_capacity * 5) as Key;
+        for i in 0..num_keys {
+            lsm_tree.put(i, i).unwrap();
+        }
+        lsm_tree.flush().unwrap();
+        lsm_tree.wait_for_compactions().unwrap();
+
+        // Keys overwritten or deleted in memory are counted there, or not at all
+        lsm_tree.put(3, -3).unwrap();
+        lsm_tree.put(num_keys + 1, 7).unwrap();
+        lsm_tree.delete(5).unwrap();
+
+        let stats = lsm_tree.tree_stats(true);
+        assert_eq!(stats.logical_pairs, num_keys as usize);
+        assert_eq!(stats.level_keys.iter().sum::<usize>(), stats.logical_pairs);
+        assert_eq!(stats.level_keys[0], 2);
+        let version = lsm_tree.version();
+        for (level, keys) in version.levels().iter().zip(&stats.level_keys[1..]) {
+            let entries: usize = level.runs().iter().map(|run| run.len()).sum();
+            assert!(*keys <= entries);
+        }
+
+        let dump = stats.dump.as_ref().unwrap();
+        assert_eq!(dump[0], vec![(3, -3), (num_keys + 1, 7)]);
+        let mut pairs: Vec<_> = dump.iter().flatten().copied().collect();
+        pairs.sort();
+        assert_eq!(pairs, lsm_tree.range(Key::MIN, Key::MAX));
+
+        let text = stats.to_string();
+        let mut lines = text.lines();
+        assert_eq!(
+            lines.next(),
+            Some(format!("Logical Pairs: {}", num_keys).as_str())
+        );
+        assert!(lines.next().unwrap().starts_with("LVL0: 2, LVL"));
+        assert_eq!(
+            lines.next(),
+            Some(format!("3:-3:L0 {}:7:L0", num_keys + 1).as_str())
+        );
+        assert!(lines.all(|line| !line.split(' ').any(|pair| pair.starts_with("5:"))));
+
+        // The summary has the counts alone
+        let summary = lsm_tree.tree_stats(false);
+        assert_eq!(summary.dump, None);
+        assert_eq!(summary.level_keys, stats.level_keys);
+        assert_eq!(summary.to_string().lines().count(), 2);
+    }
+
+    #[test]
+    fn test_compact() {
+        let dir = temp_dir("lsm_tree_compact");
+        let config = || LSMConfig {
+            fanout: 4,
+            compaction: Arc::new(Tiered),
+            ..persistent_config(&dir)
+        };
+        let run_count =
+            |tree: &LSMTree| -> usize { tree.level_stats().iter().map(|level| level.runs).sum() };
+        let lsm_tree = LSMTree::with_config(config()).unwrap();
+        // Nothing to merge
+        lsm_tree.compact().unwrap();
+
+        let num_keys = (lsm_tree.inner.buffer_capacity * 7) as Key;
+        for i in 0..num_keys {
+            lsm_tree.put(i % (num_keys / 2), i).unwrap();
+        }
+        lsm_tree.delete(3).unwrap();
+        lsm_tree.flush().unwrap();
+        lsm_tree.wait_for_compactions().unwrap();
+        let expected = lsm_tree.range(Key::MIN, Key::MAX);
+        assert!(run_count(&lsm_tree) > 1);
+
+        lsm_tree.compact().unwrap();
+        let version = lsm_tree.version();
+        let (last, upper) = version.levels().split_last().unwrap();
+        assert!(upper.iter().all(|level| level.runs().is_empty()));
+        assert_eq!(last.runs().len(), 1);
+        // Older versions and the tombstone are gone
+        assert_eq!(last.runs()[0].len(), expected.len());
+        assert_eq!(lsm_tree.range(Key::MIN, Key::MAX), expected);
+        assert_eq!(lsm_tree.get(3), None);
+
+        // The tree takes writes and compacts again afterwards
+        lsm_tree.put(num_keys, 1).unwrap();
+        lsm_tree.compact().unwrap();
+        assert_eq!(lsm_tree.get(num_keys), Some(1));
+        assert_eq!(run_count(&lsm_tree), 1);
+
+        // The manifest followed every step
+        drop(lsm_tree);
+        let lsm_tree = LSMTree::with_config(config()).unwrap();
+        assert_eq!(run_count(&lsm_tree), 1);
+        assert_eq!(lsm_tree.get(num_keys), Some(1));
+        assert_eq!(lsm_tree.get(3), None);
+    }
+
+    #[test]
+    fn test_run_stats() {
+        let lsm_tree = LSMTree::with_config(LSMConfig {
+            buffer_pages: 1,
+            ..LSMConfig::default()
+        })
+        .unwrap();
+        assert!(lsm_tree.level_stats().is_empty());
+
+        let num_keys = (lsm_tree.inner.buffer_capacity * 5) as Key;
+        for i in 0..num_keys {
+            lsm_tree.put(i * 2, i).unwrap();
+        }
+        lsm_tree.flush().unwrap();
+        lsm_tree.wait_for_compactions().unwrap();
+        for i in 0..100 {
+            lsm_tree.get(i * 2 + 1);
+        }
+
+        let bloom = lsm_tree.bloom_params();
+        for level in lsm_tree.level_stats() {
+            let runs = &level.run_stats;
+            assert_eq!(runs.len(), level.runs);
+            assert_eq!(
+                runs.iter().map(|run| run.entries).sum::<usize>(),
+                level.entries
+            );
+            let mut filter = FilterCounts::default();
+            for run in runs {
+                assert_eq!(run.id, None);
+                assert_eq!(run.filter_kind, bloom.kind);
+                assert_eq!(run.filter_entries, run.entries);
+                assert!(run.filter_bytes > 0);
+                assert!(run.filter_probes > 0);
+                assert!(run.expected_false_positive_rate < 0.1);
+                filter += run.filter;
+            }
+            assert_eq!(filter, level.filter);
+        }
+    }
+
+    #[test]
+    fn test_range_filters() {
+        let lsm_tree = LSMTree::with_config(LSMConfig {
+            buffer_pages: 1,
+            range_filter: Some(RangeFilterParams::new(32.0)),
+            ..LSMConfig::default()
+        })
+        .unwrap();
+        let num_keys = (lsm_tree.inner.buffer_capacity * 5) as Key;
+        for i in 0..num_keys {
+            lsm_tree.put(i * 100, i).unwrap();
+        }
+        lsm_tree.flush().unwrap();
+        lsm_tree.wait_for_compactions().unwrap();
+
+        // Runs skipped by their range filters don't change any results
+        let version = lsm_tree.inner.snapshot().version.clone();
+        let runs: Vec<_> = version
+            .levels()
+            .iter()
+            .flat_map(|level| level.runs())
+            .collect();
+        let mut skipped = 0;
+        for i in 0..num_keys {
+            let (start, end) = (i * 100 + 1, i * 100 + 50);
+            assert!(lsm_tree.range(start, end).is_empty());
+            skipped += runs
+                .iter()
+                .filter(|run| !run.may_contain_range(start, end))
+                .count();
+            assert_eq!(lsm_tree.range(start - 1, end), vec![(i * 100, i)]);
+        }
+        assert!(skipped as f64 > (num_keys as usize * runs.len()) as f64 * 0.8);
+        assert_eq!(lsm_tree.range(450, 650), vec![(500, 5), (600, 6)]);
+    }
+
+    #[test]
+    fn test_monkey_level_stats() {
+        let build = |bloom_allocation| {
+            let lsm_tree = LSMTree::with_config(LSMConfig {
+                buffer_pages: 1,
+                fanout: 3,
+                bloom_allocation,
+                ..LSMConfig::default()
+            })
+            .unwrap();
+            let capacity = lsm_tree.inner.buffer_capacity as Key;
+            let num_keys = capacity * 20;
+            for i in 0..num_keys {
+                lsm_tree.put(i, i).unwrap();
+                // Settling after every buffer keeps the shape of the tree
+                // independent of how fast the background threads run
+                if (i + 1) % capacity == 0 {
+                    lsm_tree.flush().unwrap();
+                    lsm_tree.wait_for_compactions().unwrap();
+                }
+            }
+
+            let stats = lsm_tree.level_stats();
+            let entries: usize = stats.iter().map(|level| level.entries).sum();
+            assert_eq!(entries, num_keys as usize);
+            stats
+                .into_iter()
+                .filter(|level| level.entries > 0)
+                .collect::<Vec<_>>()
+        };
+
+        let uniform = build(FilterAllocation::Uniform);
+        assert!(uniform.len() >= 2);
+        let default = BloomParams::default();
+        assert!(uniform.iter().all(|level| level.bloom == default));
+
+        // Smaller levels get more bits and so lower false-positive rates
+        let monkey = build(FilterAllocation::Monkey);
+        let (first, last) = (&monkey[0], &monkey[monkey.len() - 1]);
+        assert!(first.entries < last.entries);
+        assert!(first.bloom.bits_per_key > last.bloom.bits_per_key);
+        assert!(first.bloom.false_positive_rate() < default.false_positive_rate());
+        assert!(last.bloom.false_positive_rate() > default.false_positive_rate());
+    }
+
+    #[test]
+    fn test_rejects_fanout_below_two() {
+        for fanout in [0, 1] {
+            for policy in [
+                Arc::new(Leveled) as Arc<dyn CompactionPolicy>,
+                Arc::new(Tiered),
+            ] {
+                let result = LSMTree::with_config(LSMConfig {
+                    fanout,
+                    compaction: policy,
+                    ..LSMConfig::default()
+                });
+                assert!(matches!(result, Err(Error::InvalidConfig(_))));
+            }
+        }
+    }
+
+    #[test]
+    fn test_new_rounds_empty_buffer_up() {
+        let lsm_tree = LSMTree::new(0);
+        lsm_tree.put(1, 10).unwrap();
+        assert_eq!(lsm_tree.get(1), Some(10));
+        assert_eq!(lsm_tree.inner.config.buffer_pages, 1);
+    }
+
+    #[test]
+    fn test_leveled_compaction() {
+        let lsm_tree = LSMTree::with_config(LSMConfig {
+            buffer_pages: 1,
+            fanout: 3,
+            ..LSMConfig::default()
+        })
+        .unwrap();
+        let capacity = lsm_tree.inner.buffer_capacity;
+        let num_keys = (capacity * 20) as Key;
+        for i in 0..num_keys {
+            lsm_tree.put(i, i).unwrap();
+        }
+        for i in (0..num_keys).step_by(7) {
+            lsm_tree.delete(i).unwrap();
+        }
+
+        // Every level is one sorted run within its capacity
+        settle(&lsm_tree);
+        assert!(lsm_tree.version().levels().len() >= 2);
+        for (index, level) in lsm_tree.version().levels().iter().enumerate() {
+            assert!(level.runs().len() <= 1, "Level {} has several runs", index);
+            let len: usize = level.runs().iter().map(|run| run.len()).sum();
+            assert!(len <= compaction::level_capacity(index, capacity, 3));
+        }
+
+        for i in 0..num_keys {
+            let expected = if i % 7 == 0 { None } else { Some(i) };
+            assert_eq!(lsm_tree.get(i), expected, "Mismatch for key {}", i);
+        }
+    }
+
+    #[test]
+    fn test_tiered_compaction() {
+        let fanout = 3;
+        let lsm_tree = LSMTree::with_config(LSMConfig {
+            buffer_pages: 1,
+            fanout,
+            compaction: Arc::new(Tiered),
+            ..LSMConfig::default()
+        })
+        .unwrap();
+        let capacity = lsm_tree.inner.buffer_capacity;
+        let num_keys = capacity as Key * 4;
+
+        // Overwrite the same keys in every pass so that runs overlap
+        for pass in 0..5 {
+            for i in 0..num_keys {
+                lsm_tree.put(i, i * 10 + pass).unwrap();
+            }
+        }
+        for i in (0..num_keys).step_by(5) {
+            lsm_tree.delete(i).unwrap();
+        }
+
+        // No level ever holds a full tier
+        settle(&lsm_tree);
+        assert!(lsm_tree.version().levels().len() >= 2);
+        for level in lsm_tree.version().levels() {
+            assert!(level.runs().len() < fanout);
+        }
+
+        for i in 0..num_keys {
+            let expected = if i % 5 == 0 { None } else { Some(i * 10 + 4) };
+            assert_eq!(lsm_tree.get(i), expected, "Mismatch for key {}", i);
+        }
+        let range = lsm_tree.range(0, num_keys);
+        let live_keys = (0..num_keys).filter(|i| i % 5 != 0).count();
+        assert_eq!(range.len(), live_keys);
+        assert!(range.iter().all(|&(k, v)| v == k * 10 + 4));
+    }
+
+    #[test]
+    fn test_hybrid_policies() {
+        let policies: [Arc<dyn CompactionPolicy>; 3] = [
+            Arc::new(LazyLeveled),
+            Arc::new(Hybrid::new(2, 2)),
+            Arc::new(Hybrid::new(1, 3)),
+        ];
+        for policy in policies {
+            let lsm_tree = LSMTree::with_config(LSMConfig {
+                buffer_pages: 1,
+                fanout: 3,
+                compaction: Arc::clone(&policy),
+                ..LSMConfig::default()
+            })
+            .unwrap();
+            let capacity = lsm_tree.inner.buffer_capacity;
+            let num_keys = capacity as Key * 6;
+            for pass in 0..4 {
+                for i in 0..num_keys {
+                    lsm_tree.put(i, i + pass).unwrap();
+                }
+            }
+            for i in (0..num_keys).step_by(3) {
+                lsm_tree.delete(i).unwrap();
+            }
+
+            // The policy is satisfied once the background work has settled
+            settle(&lsm_tree);
+            let levels = compaction::level_states(lsm_tree.version().levels(), &[], &[]);
+            assert_eq!(policy.pick(&levels, capacity, 3), None, "{:?}", policy);
+            for i in 0..num_keys {
+                let expected = if i % 3 == 0 { None } else { Some(i + 3) };
+                assert_eq!(lsm_tree.get(i), expected, "{:?}: key {}", policy, i);
+            }
+        }
+    }
+
+    #[test]
+    fn test_partial_compaction() {
+        for choice in [FileChoice::RoundRobin, FileChoice::LeastOverlap] {
+            let dir = temp_dir("lsm_tree_partial");
+            let config = LSMConfig {
+                wal_sync: SyncMode::None,
+                fanout: 3,
+                compaction: Arc::new(Partial::new(choice)),
+                ..persistent_config(&dir)
+            };
+            let lsm_tree = LSMTree::with_config(config.clone()).unwrap();
+            let capacity = lsm_tree.inner.buffer_capacity;
+            let num_keys = capacity as Key * 30;
+
+            // Spread writes over the key space so files overlap across levels
+            let mut rng = StdRng::seed_from_u64(7);
+            let mut model = BTreeMap::new();
+            for _ in 0..capacity * 40 {
+                let key = rng.gen_range(0..num_keys);
+                lsm_tree.put(key, key + 1).unwrap();
+                model.insert(key, key + 1);
+            }
+
+            // Below the first level files are small and key-disjoint
+            settle(&lsm_tree);
+            assert!(lsm_tree.version().levels().len() >= 3);
+            for level in &lsm_tree.version().levels()[1..] {
+                let mut ranges: Vec<_> = level
+                    .runs()
+                    .iter()
+                    .filter_map(|run| run.key_range())
+                    .collect();
+                ranges.sort();
+                assert!(ranges.windows(2).all(|pair| pair[0].1 < pair[1].0));
+                assert!(level.runs().iter().all(|run| run.len() <= capacity));
+            }
+
+            for (&key, &value) in &model {
+                assert_eq!(lsm_tree.get(key), Some(value), "{:?}: key {}", choice, key);
+            }
+            let cursors = lsm_tree.inner.versions.lock().unwrap().cursors.clone();
+            assert!(cursors[1..].iter().any(Option::is_some));
+            drop(lsm_tree);
+
+            // The round-robin position survives a restart
+            let lsm_tree = LSMTree::with_config(config).unwrap();
+            assert_eq!(lsm_tree.inner.versions.lock().unwrap().cursors, cursors);
+            assert_eq!(lsm_tree.range(0, num_keys).len(), model.len());
+        }
+    }
+
+    #[test]
+    fn test_compaction_removes_merged_files() {
+        let dir = temp_dir("lsm_tree_compaction_files");
+        let lsm_tree = LSMTree::with_config(persistent_config(&dir)).unwrap();
+        let capacity = lsm_tree.inner.buffer_capacity as Key;
+        for i in 0..capacity * 5 {
+            lsm_tree.put(i % (capacity * 2), i).unwrap();
+        }
+
+        settle(&lsm_tree);
+        let live_runs: usize = (lsm_tree.version().levels().iter())
+            .map(|level| level.runs().len())
+            .sum();
+        let run_files = fs::read_dir(&dir)
+            .unwrap()
+            .filter(|entry| {
+                let path = entry.as_ref().unwrap().path();
+                matches!(filename::parse(&path), Some(FileKind::Run(_)))
+            })
+            .count();
+        assert_eq!(run_files, live_runs);
+        drop(lsm_tree);
+
+        let lsm_tree = LSMTree::with_config(persistent_config(&dir)).unwrap();
+        for i in capacity * 3..capacity * 5 {
+            assert_eq!(lsm_tree.get(i % (capacity * 2)), Some(i));
+        }
+    }
+
+    #[test]
+    fn test_flush_writes_run_files() {
+        let dir = temp_dir("lsm_tree_flush");
+        let lsm_tree = LSMTree::with_config(persistent_config(&dir)).unwrap();
+
+        let capacity = lsm_tree.inner.buffer_capacity as Key;
+        for i in 0..capacity {
+            lsm_tree.put(i, i * 10).unwrap();
+        }
+
+        // Filling the buffer froze it and started a new log, and the flush
+        // thread wrote it into a run file on disk
+        lsm_tree.flush().unwrap();
+        let run_path = dir.join("000004.run");
+        assert!(run_path.exists());
+        assert!(lsm_tree.inner.snapshot().active.memtable.is_empty());
+        assert_eq!(lsm_tree.get(capacity - 1), Some((capacity - 1) * 10));
+
+        let restored = Run::restore(&run_path).unwrap();
+        for i in 0..capacity {
+            assert_eq!(restored.get(i), Some(i * 10));
+        }
+
+        // The flushed buffer's log was replaced by a fresh one
+        assert!(!dir.join("000002.wal").exists());
+        assert!(dir.join("000003.wal").exists());
+    }
+
+    #[test]
+    fn test_wal_replay_after_restart() {
+        let dir = temp_dir("lsm_tree_wal_replay");
+
+        {
+            let lsm_tree = LSMTree::with_config(persistent_config(&dir)).unwrap();
+            lsm_tree.put(1, 100).unwrap();
+            lsm_tree.put(2, 200).unwrap();
+            lsm_tree.put(1, 150).unwrap();
+            lsm_tree.delete(2).unwrap();
+            // Dropped without flushing, as if the process had died
+        }
+
+        let lsm_tree = LSMTree::with_config(persistent_config(&dir)).unwrap();
+        assert_eq!(lsm_tree.get(1), Some(150));
+        assert_eq!(lsm_tree.get(2), None);
+
+        // Writes after recovery go to a new log; the replayed one is kept until
+        // its records have been flushed
+        lsm_tree.put(3, 300).unwrap();
+        drop(lsm_tree);
+        assert!(dir.join("000002.wal").exists());
+
+        let lsm_tree = LSMTree::with_config(persistent_config(&dir)).unwrap();
+        assert_eq!(lsm_tree.get(1), Some(150));
+        assert_eq!(lsm_tree.get(2), None);
+        assert_eq!(lsm_tree.get(3), Some(300));
+    }
+
+    #[test]
+    fn test_load_file() {
+        let dir = temp_dir("lsm_tree_load_file");
+        let path = dir.join("data.bin");
+        let capacity;
+
+        {
+            let lsm_tree = LSMTree::with_config(persistent_config(&dir)).unwrap();
+            capacity = lsm_tree.inner.buffer_capacity as i32;
+
+            // Several memtables' worth, with later pairs overwriting earlier
+            // ones and a torn pair at the end
+            let mut bytes = Vec::new();
+            for i in 0..capacity * 3 + 7 {
+                let key = i % (capacity * 2);
+                bytes.extend_from_slice(&key.to_le_bytes());
+                bytes.extend_from_slice(&(-i).to_le_bytes());
+            }
+            bytes.extend_from_slice(&[1, 2]);
+            fs::write(&path, &bytes).unwrap();
+
+            lsm_tree.put(-1, 1).unwrap();
+            let summary = lsm_tree.load_file(&path).unwrap();
+            assert_eq!(summary.pairs, (capacity * 3 + 7) as u64);
+            assert_eq!(summary.trailing_bytes, 2);
+            assert!(lsm_tree.load_file(&dir.join("missing.bin")).is_err());
+            // Dropped without flushing, as if the process had died
+        }
+
+        // Loaded pairs are logged like any other write
+        let lsm_tree = LSMTree::with_config(persistent_config(&dir)).unwrap();
+        assert_eq!(lsm_tree.get(-1), Some(1));
+        for key in 0..capacity * 2 {
+            let last = if key < capacity + 7 {
+                key + capacity * 2
+            } else {
+                key
+            };
+            assert_eq!(lsm_tree.get(key as Key), Some(-last as Value));
+        }
+    }
+
+    #[test]
+    fn test_wal_replay_larger_than_buffer() {
+        let dir = temp_dir("lsm_tree_wal_replay_large");
+        let capacity;
+
+        {
+            let lsm_tree = LSMTree::with_config(LSMConfig {
+                wal_sync: SyncMode::None,
+                ..persistent_config(&dir)
+            })
+            .unwrap();
+            capacity = lsm_tree.inner.buffer_capacity as Key;
+            for i in 0..capacity + 10 {
+                lsm_tree.put(i, i).unwrap();
+            }
+        }
+
+        // Only the records written after the last flush need replaying
+        let lsm_tree = LSMTree::with_config(persistent_config(&dir)).unwrap();
+        assert_eq!(lsm_tree.inner.snapshot().active.memtable.len(), 10);
+        for i in capacity..capacity + 10 {
+            assert_eq!(lsm_tree.get(i), Some(i));
+        }
+    }
+
+    #[test]
+    fn test_reopen_with_mixed_filter_kinds() {
+        let dir = temp_dir("lsm_tree_mixed_filters");
+        let config = |filter_kind| LSMConfig {
+            filter_kind,
+            compaction: Arc::new(Tiered),
+            fanout: 10,
+            ..persistent_config(&dir)
+        };
+        let filter_kinds = |lsm_tree: &LSMTree| {
+            let version = lsm_tree.version();
+            let mut kinds: Vec<_> = version
+                .levels()
+                .iter()
+                .flat_map(|level| level.runs().iter().map(|run| run.filter_kind().name()))
+                .collect();
+            kinds.sort_unstable();
+            kinds.dedup();
+            kinds
+        };
+
+        // Each session writes its runs with a different filter
+        let mut capacity = 0;
+        let kinds = [
+            FilterKind::Bloom,
+            FilterKind::RocksDbLocal,
+            FilterKind::SpeedDbDynamic,
+        ];
+        for (session, kind) in kinds.into_iter().enumerate() {
+            let lsm_tree = LSMTree::with_config(config(kind)).unwrap();
+            capacity = lsm_tree.inner.buffer_capacity as Key;
+            let start = session as Key * capacity;
+            for i in start..start + capacity {
+                lsm_tree.put(i, i * 10).unwrap();
+            }
+            settle(&lsm_tree);
+        }
+
+        let lsm_tree = LSMTree::with_config(config(FilterKind::Bloom)).unwrap();
+        assert_eq!(filter_kinds(&lsm_tree), vec!["bloom", "rocksdb", "speeddb"]);
+        for i in 0..capacity * 3 {
+            assert_eq!(lsm_tree.get(i), Some(i * 10));
+        }
+        assert_eq!(lsm_tree.get(capacity * 3), None);
+    }
+
+    #[test]
+    fn test_reopen_restores_levels() {
+        let dir = temp_dir("lsm_tree_reopen");
+        let capacity;
+        let run_ids;
+
+        {
+            let lsm_tree = LSMTree::with_config(persistent_config(&dir)).unwrap();
+            capacity = lsm_tree.inner.buffer_capacity as Key;
+            for i in 0..capacity * 3 {
+                lsm_tree.put(i, i * 10).unwrap();
+            }
+            lsm_tree.delete(0).unwrap();
+            settle(&lsm_tree);
+            run_ids = level_run_ids(&lsm_tree);
+        }
+
+        let lsm_tree = LSMTree::with_config(persistent_config(&dir)).unwrap();
+        assert_eq!(level_run_ids(&lsm_tree), run_ids);
+        assert_eq!(lsm_tree.get(0), None);
+        for i in 1..capacity * 3 {
+            assert_eq!(lsm_tree.get(i), Some(i * 10));
+        }
+
+        // Only the fresh manifest and log remain alongside the runs
+        let manifests = fs::read_dir(&dir)
+            .unwrap()
+            .filter(|entry| {
+                let path = entry.as_ref().unwrap().path();
+                matches!(filename::parse(&path), Some(FileKind::Manifest(_)))
+            })
+            .count();
+        assert_eq!(manifests, 1);
+    }
+
+    #[test]
+    fn test_open_uses_default_config() {
+        let dir = temp_dir("lsm_tree_open");
+        {
+            let lsm_tree = LSMTree::open(&dir).unwrap();
+            lsm_tree.put(1, 100).unwrap();
+        }
+
+        let lsm_tree = LSMTree::open(&dir).unwrap();
+        assert_eq!(lsm_tree.get(1), Some(100));
+        assert!(filename::current_path(&dir).exists());
+    }
+
+    #[test]
+    fn test_recovery_removes_uncommitted_files() {
+        let dir = temp_dir("lsm_tree_orphans");
+
+        {
+            let lsm_tree = LSMTree::with_config(persistent_config(&dir)).unwrap();
+            lsm_tree.put(1, 100).unwrap();
+        }
+
+        // A flush that crashed after writing its run but before committing it
+        // to the manifest, plus a half-written file
+        let orphan = filename::run_path(&dir, 99);
+        Run::new(vec![(1, 999), (2, 200)], BloomParams::default())
+            .persist(&orphan)
+            .unwrap();
+        fs::write(dir.join("000100.run.tmp"), b"partial").unwrap();
+        fs::write(dir.join("notes.tmp"), b"notes").unwrap();
+
+        let lsm_tree = LSMTree::with_config(persistent_config(&dir)).unwrap();
+        assert!(!orphan.exists());
+        assert!(!dir.join("000100.run.tmp").exists());
+        assert!(dir.join("notes.tmp").exists());
+        assert_eq!(lsm_tree.get(1), Some(100));
+        assert_eq!(lsm_tree.get(2), None);
+
+        // New files never reuse the orphan's id
+        assert!(lsm_tree.inner.next_file_id.load(Ordering::Relaxed) > 100);
+    }
+
+    #[test]
+    fn test_recovery_adopts_runs_without_manifest() {
+        let dir = temp_dir("lsm_tree_unmanaged_runs");
+        Run::new(vec![(1, 100), (2, 200)], BloomParams::default())
+            .persist(&filename::run_path(&dir, 1))
+            .unwrap();
+        Run::new(vec![(2, 250)], BloomParams::default())
+            .persist(&filename::run_path(&dir, 2))
+            .unwrap();
+        // Files the tree can't vouch for stay where they are
+        fs::write(dir.join("000003.run.tmp"), b"partial").unwrap();
+        fs::write(dir.join("notes.tmp"), b"notes").unwrap();
+
+        let lsm_tree = LSMTree::with_config(persistent_config(&dir)).unwrap();
+        assert!(dir.join("000003.run.tmp").exists());
+        assert!(dir.join("notes.tmp").exists());
+        // Adopted oldest first, so the later run's value wins however the
+        // workers merge them
+        lsm_tree.wait_for_compactions().unwrap();
+        assert_eq!(lsm_tree.get(1), Some(100));
+        assert_eq!(lsm_tree.get(2), Some(250));
+        assert!(filename::current_path(&dir).exists());
+    }
+
+    #[test]
+    fn test_reads_during_background_work() {
+        let lsm_tree = Arc::new(
+            LSMTree::with_config(LSMConfig {
+                buffer_pages: 1,
+                compaction: Arc::new(Tiered),
+                ..LSMConfig::default()
+            })
+            .unwrap(),
+        );
+        let num_keys = lsm_tree.inner.buffer_capacity as Key * 20;
+        let written = Arc::new(AtomicI64::new(0));
+
+        // Readers never miss an acknowledged write while memtables are
+        // flushed and runs merged underneath them
+        let readers: Vec<_> = (0..3)
+            .map(|seed| {
+                let lsm_tree = Arc::clone(&lsm_tree);
+                let written = Arc::clone(&written);
+                thread::spawn(move || {
+                    let mut rng = StdRng::seed_from_u64(seed);
+                    while written.load(Ordering::Acquire) < num_keys {
+                        let done = written.load(Ordering::Acquire);
+                        if done > 0 {
+                            let key = rng.gen_range(0..done);
+                            assert_eq!(lsm_tree.get(key), Some(key * 2), "key {}", key);
+                            assert_eq!(lsm_tree.range(0, done).len() as Key, done);
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        for i in 0..num_keys {
+            lsm_tree.put(i, i * 2).unwrap();
+            written.store(i + 1, Ordering::Release);
+        }
+        for reader in readers {
+            reader.join().unwrap();
+        }
+
+        settle(&lsm_tree);
+        assert_eq!(lsm_tree.range(0, num_keys).len() as Key, num_keys);
+    }
+
+    #[test]
+    fn test_writes_stall_on_full_queue() {
+        let lsm_tree = Arc::new(
+            LSMTree::with_config(LSMConfig {
+                buffer_pages: 1,
+                max_immutable_memtables: 1,
+                ..LSMConfig::default()
+            })
+            .unwrap(),
+        );
+        let num_keys = lsm_tree.inner.buffer_capacity as Key * 10;
+        let done = Arc::new(AtomicBool::new(false));
+
+        let watcher = {
+            let lsm_tree = Arc::clone(&lsm_tree);
+            let done = Arc::clone(&done);
+            thread::spawn(move || {
+                let mut longest = 0;
+                while !done.load(Ordering::Acquire) {
+                    longest = longest.max(lsm_tree.inner.snapshot().immutables.len());
+                }
+                longest
+            })
+        };
+        for i in 0..num_keys {
+            lsm_tree.put(i, i).unwrap();
+        }
+        done.store(true, Ordering::Release);
+
+        // The queue never outgrew its limit, and frozen memtables stayed
+        // visible until flushed
+        assert!(watcher.join().unwrap() <= 1);
+        for i in 0..num_keys {
+            assert_eq!(lsm_tree.get(i), Some(i));
+        }
+        assert_eq!(lsm_tree.range(0, num_keys).len() as Key, num_keys);
+    }
+
+    #[test]
+    fn test_matches_btreemap_model() {
+        let policies: [Arc<dyn CompactionPolicy>; 4] = [
+            Arc::new(Leveled),
+            Arc::new(Tiered),
+            Arc::new(LazyLeveled),
+            Arc::new(Partial::new(FileChoice::RoundRobin)),
+        ];
+        for (seed, policy) in policies.into_iter().enumerate() {
+            let dir = temp_dir("lsm_tree_model");
+            let config = LSMConfig {
+                wal_sync: SyncMode::None,
+                compaction: Arc::clone(&policy),
+                ..persistent_config(&dir)
+            };
+            let mut lsm_tree = LSMTree::with_config(config.clone()).unwrap();
+            let num_keys = lsm_tree.inner.buffer_capacity as Key * 4;
+
+            // A small key space makes updates and deletes of keys already
+            // flushed, and merged, the common case
+            let mut rng = StdRng::seed_from_u64(seed as u64);
+            let mut model = BTreeMap::new();
+            for step in 0..lsm_tree.inner.buffer_capacity * 30 {
+                let key = rng.gen_range(0..num_keys);
+                match rng.gen_range(0..10) {
+                    0..=5 => {
+                        let value = rng.gen_range(0..Value::MAX);
+                        lsm_tree.put(key, value).unwrap();
+                        model.insert(key, value);
+                    }
+                    6..=7 => {
+                        lsm_tree.delete(key).unwrap();
+                        model.remove(&key);
+                    }
+                    8 => {
+                        let expected = model.get(&key).copied();
+                        assert_eq!(lsm_tree.get(key), expected, "{:?}: key {}", policy, key);
+                    }
+                    _ => {
+                        let end = key + rng.gen_range(1..100);
+                        let expected: Vec<_> =
+                            model.range(key..end).map(|(&k, &v)| (k, v)).collect();
+                        assert_eq!(lsm_tree.range(key, end), expected, "{:?}", policy);
+                    }
+                }
+
+                // Reopening must not bring back anything older
+                if step % 5000 == 4999 {
+                    drop(lsm_tree);
+                    lsm_tree = LSMTree::with_config(config.clone()).unwrap();
+                }
+            }
+
+            settle(&lsm_tree);
+            for key in 0..num_keys {
+                assert_eq!(lsm_tree.get(key), model.get(&key).copied(), "{:?}", policy);
+            }
+            let expected: Vec<_> = model.into_iter().collect();
+            assert_eq!(lsm_tree.range(0, num_keys), expected, "{:?}", policy);
+        }
+    }
+
+    /// Waits until every write so far is in a run and the policy is satisfied.
+    fn settle(lsm_tree: &LSMTree) {
+        lsm_tree.flush().unwrap();
+        lsm_tree.wait_for_compactions().unwrap();
+    }
+
+    fn level_run_ids(lsm_tree: &LSMTree) -> Vec<Vec<Option<u64>>> {
+        let version = lsm_tree.version();
+        version
+            .levels()
+            .iter()
+            .map(|level| level.runs().iter().map(|run| run.id()).collect())
+            .collect()
+    }
+
+    fn persistent_config(dir: &Path) -> LSMConfig {
+        LSMConfig {
+            buffer_pages: 1,
+            data_dir: Some(dir.to_path_buf()),
+            wal_sync: SyncMode::Always,
+            ..LSMConfig::default()
+        }
+    }
 }
@@ -0,0 +1,484 @@
+use crate::filename::{current_path, manifest_path, sync_dir, temp_path, FileKind};
+use crate::types::{Error, Key, Result};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use xxhash_rust::xxh3::xxh3_64;
+
+/// Number of edits appended to a manifest before it is replaced by a snapshot
+pub const SNAPSHOT_INTERVAL: usize = 128;
+
+const TAG_ADD_RUN: u8 = 1;
+const TAG_REMOVE_RUN: u8 = 2;
+const TAG_LOG_NUMBER: u8 = 3;
+const TAG_NEXT_FILE_ID: u8 = 4;
+const TAG_CURSOR: u8 = 5;
+/// payload length (u32) + checksum (u64)
+const RECORD_HEADER_SIZE: usize = 4 + 8;
+
+/// A change to the level structure, applied atomically.
+///
+/// Flushes and compactions describe everything they did (runs added, runs
+/// removed, logs retired) in a single edit. The edit is one checksummed
+/// manifest record, so after a crash it is either fully visible or not at all.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VersionEdit {
+    /// `(level, run id)` pairs added to their level, in order. They take the
+    /// place of the oldest run the edit removes from that level, as in
+    /// [`Level::replace`](crate::level::Level::replace), or are appended as
+    /// its newest runs
+    pub added_runs: Vec<(usize, u64)>,
+    /// `(level, run id)` pairs removed from their level
+    pub removed_runs: Vec<(usize, u64)>,
+    /// Logs with a smaller id hold nothing that isn't already in a run
+    pub log_number: Option<u64>,
+    /// Lower bound for the next file id to hand out
+    pub next_file_id: Option<u64>,
+    /// `(level, key)` pairs: the largest key merged out of the level, where
+    /// a round-robin policy picks up again
+    pub cursors: Vec<(usize, Key)>,
+}
+
+impl VersionEdit {
+    pub fn add_run(&mut self, level: usize, id: u64) {
+        self.added_runs.push((level, id));
+    }
+
+    pub fn remove_run(&mut self, level: usize, id: u64) {
+        self.removed_runs.push((level, id));
+    }
+
+    pub fn set_cursor(&mut self, level: usize, key: Key) {
+        self.cursors.push((level, key));
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for &(level, id) in &self.removed_runs {
+            buf.push(TAG_REMOVE_RUN);
+            buf.extend_from_slice(&(level as u32).to_le_bytes());
+            buf.extend_from_slice(&id.to_le_bytes());
+        }
+        for &(level, id) in &self.added_runs {
+            buf.push(TAG_ADD_RUN);
+            buf.extend_from_slice(&(level as u32).to_le_bytes());
+            buf.extend_from_slice(&id.to_le_bytes());
+        }
+        if let Some(log_number) = self.log_number {
+            buf.push(TAG_LOG_NUMBER);
+            buf.extend_from_slice(&log_number.to_le_bytes());
+        }
+        if let Some(next_file_id) = self.next_file_id {
+            buf.push(TAG_NEXT_FILE_ID);
+            buf.extend_from_slice(&next_file_id.to_le_bytes());
+        }
+        for &(level, key) in &self.cursors {
+            buf.push(TAG_CURSOR);
+            buf.extend_from_slice(&(level as u32).to_le_bytes());
+            buf.extend_from_slice(&key.to_le_bytes());
+        }
+        buf
+    }
+
+    fn decode(mut buf: &[u8]) -> Result<Self> {
+        let mut edit = VersionEdit::default();
+        while let Some((&tag, rest)) = buf.split_first() {
+            buf = rest;
+            match tag {
+                TAG_ADD_RUN | TAG_REMOVE_RUN => {
+                    let level = take_u32(&mut buf)? as usize;
+                    let id = take_u64(&mut buf)?;
+                    if tag == TAG_ADD_RUN {
+                        edit.add_run(level, id);
+                    } else {
+                        edit.remove_run(level, id);
+                    }
+                }
+                TAG_LOG_NUMBER => edit.log_number = Some(take_u64(&mut buf)?),
+                TAG_NEXT_FILE_ID => edit.next_file_id = Some(take_u64(&mut buf)?),
+                TAG_CURSOR => {
+                    let level = take_u32(&mut buf)? as usize;
+                    edit.set_cursor(level, take_u64(&mut buf)? as Key);
+                }
+                _ => return Err(corruption(format!("unknown manifest tag {}", tag))),
+            }
+        }
+        Ok(edit)
+    }
+}
+
+/// The level structure rebuilt by replaying a manifest.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ManifestState {
+    /// Run ids per level, oldest first
+    pub levels: Vec<Vec<u64>>,
+    pub log_number: u64,
+    pub next_file_id: u64,
+    /// Where merges out of each level pick up again, if anywhere
+    pub cursors: Vec<Option<Key>>,
+}
+
+impl ManifestState {
+    pub fn apply(&mut self, edit: &VersionEdit) -> Result<()> {
+        // Where the oldest removed run of each level was
+        let mut insert_at = vec![None; self.levels.len()];
+        for &(level, id) in &edit.removed_runs {
+            let runs = self.levels.get_mut(level);
+            let position = runs
+                .as_ref()
+                .and_then(|runs| runs.iter().position(|&run| run == id));
+            match (runs, position) {
+                (Some(runs), Some(position)) => {
+                    runs.remove(position);
+                    let oldest = insert_at[level].map_or(position, |p: usize| p.min(position));
+                    insert_at[level] = Some(oldest);
+                }
+                _ => {
+                    return Err(corruption(format!(
+                        "run {} removed from level {} but never added",
+                        id, level
+                    )))
+                }
+            }
+        }
+        for &(level, id) in &edit.added_runs {
+            if self.levels.len() <= level {
+                self.levels.resize_with(level + 1, Vec::new);
+            }
+            // Runs flushed while a compaction ran must stay newer than its
+            // outputs
+            match insert_at.get_mut(level) {
+                Some(Some(position)) => {
+                    self.levels[level].insert(*position, id);
+                    *position += 1;
+                }
+                _ => self.levels[level].push(id),
+            }
+        }
+        if let Some(log_number) = edit.log_number {
+            self.log_number = log_number;
+        }
+        if let Some(next_file_id) = edit.next_file_id {
+            self.next_file_id = self.next_file_id.max(next_file_id);
+        }
+        for &(level, key) in &edit.cursors {
+            if self.cursors.len() <= level {
+                self.cursors.resize(level + 1, None);
+            }
+            self.cursors[level] = Some(key);
+        }
+        Ok(())
+    }
+
+    /// An edit that rebuilds this state from nothing.
+    pub fn snapshot(&self) -> VersionEdit {
+        let mut edit = VersionEdit {
+            log_number: Some(self.log_number),
+            next_file_id: Some(self.next_file_id),
+            ..VersionEdit::default()
+        };
+        for (level, runs) in self.levels.iter().enumerate() {
+            for &id in runs {
+                edit.add_run(level, id);
+            }
+        }
+        for (level, cursor) in self.cursors.iter().enumerate() {
+            if let Some(key) = *cursor {
+                edit.set_cursor(level, key);
+            }
+        }
+        edit
+    }
+
+    /// Whether `id` names a run that is part of this state.
+    pub fn contains_run(&self, id: u64) -> bool {
+        self.levels.iter().any(|runs| runs.contains(&id))
+    }
+}
+
+/// Append-only log of [`VersionEdit`]s describing which runs make up the tree.
+///
+/// A manifest file starts with a snapshot of the full level structure and is
+/// followed by incremental edits. The `CURRENT` file names the live manifest
+/// and is replaced atomically, so switching to a fresh snapshot is crash-safe.
+pub struct Manifest {
+    dir: PathBuf,
+    file: File,
+    edits: usize,
+}
+
+impl Manifest {
+    /// Writes a new manifest seeded with `snapshot` and makes it current.
+    ///
+    /// The previous manifest, if any, is deleted once `CURRENT` points at the
+    /// new one.
+    pub fn create(dir: &Path, id: u64, snapshot: &VersionEdit) -> Result<Self> {
+        let path = manifest_path(dir, id);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(&path)?;
+        write_record(&mut file, snapshot)?;
+
+        let previous = current_manifest(dir)?;
+        let current_tmp = temp_path(&current_path(dir));
+        let file_name = path.file_name().unwrap().to_str().unwrap();
+        fs::write(&current_tmp, format!("{}\n", file_name))?;
+        File::open(&current_tmp)?.sync_all()?;
+        fs::rename(&current_tmp, current_path(dir))?;
+        sync_dir(dir)?;
+
+        if let Some(previous) = previous.filter(|&previous| previous != id) {
+            let _ = fs::remove_file(manifest_path(dir, previous));
+        }
+
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            file,
+            edits: 0,
+        })
+    }
+
+    /// Replays the current manifest in `dir`, or `None` for a fresh directory.
+    ///
+    /// A torn record at the end of the log is an edit that never committed and
+    /// is ignored; corruption anywhere else is an error.
+    pub fn recover(dir: &Path) -> Result<Option<ManifestState>> {
+        let Some(id) = current_manifest(dir)? else {
+            return Ok(None);
+        };
+
+        let bytes = fs::read(manifest_path(dir, id))?;
+        let mut state = ManifestState::default();
+        let mut offset = 0;
+        while offset + RECORD_HEADER_SIZE <= bytes.len() {
+            let len = u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap()) as usize;
+            let checksum = u64::from_le_bytes(bytes[offset + 4..offset + 12].try_into().unwrap());
+            let start = offset + RECORD_HEADER_SIZE;
+            let end = start + len;
+
+            let intact = end <= bytes.len() && xxh3_64(&bytes[start..end]) == checksum;
+            if !intact {
+                if end >= bytes.len() {
+                    break; // Torn final record
+                }
+                return Err(corruption(format!(
+                    "bad manifest record at offset {}",
+                    offset
+                )));
+            }
+
+            state.apply(&VersionEdit::decode(&bytes[start..end])?)?;
+            offset = end;
+        }
+
+        Ok(Some(state))
+    }
+
+    /// Durably appends `edit`; it takes effect once this returns.
+    pub fn log_edit(&mut self, edit: &VersionEdit) -> Result<()> {
+        write_record(&mut self.file, edit)?;
+        self.edits += 1;
+        Ok(())
+    }
+
+    /// Whether enough edits have piled up to warrant a fresh snapshot.
+    pub fn should_snapshot(&self) -> bool {
+        self.edits >= SNAPSHOT_INTERVAL
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+}
+
+fn write_record(file: &mut File, edit: &VersionEdit) -> Result<()> {
+    let payload = edit.encode();
+    let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + payload.len());
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(&xxh3_64(&payload).to_le_bytes());
+    record.extend_from_slice(&payload);
+    file.write_all(&record)?;
+    file.sync_data()?;
+    Ok(())
+}
+
+/// Reads the id of the manifest named by `CURRENT`.
+fn current_manifest(dir: &Path) -> Result<Option<u64>> {
+    let contents = match fs::read_to_string(current_path(dir)) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    match crate::filename::parse(Path::new(contents.trim())) {
+        Some(FileKind::Manifest(id)) => Ok(Some(id)),
+        _ => Err(corruption(format!(
+            "CURRENT names an invalid manifest {:?}",
+            contents.trim()
+        ))),
+    }
+}
+
+fn take_u32(buf: &mut &[u8]) -> Result<u32> {
+    if buf.len() < 4 {
+        return Err(corruption("truncated manifest edit".to_string()));
+    }
+    let (head, rest) = buf.split_at(4);
+    *buf = rest;
+    Ok(u32::from_le_bytes(head.try_into().unwrap()))
+}
+
+fn take_u64(buf: &mut &[u8]) -> Result<u64> {
+    if buf.len() < 8 {
+        return Err(corruption("truncated manifest edit".to_string()));
+    }
+    let (head, rest) = buf.split_at(8);
+    *buf = rest;
+    Ok(u64::from_le_bytes(head.try_into().unwrap()))
+}
+
+fn corruption(msg: String) -> Error {
+    Error::Storage(format!("Manifest corrupted: {}", msg))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::temp_dir;
+
+    fn edit(added: &[(usize, u64)], removed: &[(usize, u64)]) -> VersionEdit {
+        VersionEdit {
+            added_runs: added.to_vec(),
+            removed_runs: removed.to_vec(),
+            ..VersionEdit::default()
+        }
+    }
+
+    #[test]
+    fn test_edit_encoding_roundtrip() {
+        let edit = VersionEdit {
+            added_runs: vec![(0, 5), (1, 7)],
+            removed_runs: vec![(0, 3)],
+            log_number: Some(9),
+            next_file_id: Some(10),
+            cursors: vec![(1, -4)],
+        };
+        assert_eq!(VersionEdit::decode(&edit.encode()).unwrap(), edit);
+        assert_eq!(
+            VersionEdit::decode(&VersionEdit::default().encode()).unwrap(),
+            VersionEdit::default()
+        );
+
+        // Truncated and unknown records are rejected
+        let bytes = edit.encode();
+        assert!(VersionEdit::decode(&bytes[..bytes.len() - 1]).is_err());
+        assert!(VersionEdit::decode(&[0xFF]).is_err());
+    }
+
+    #[test]
+    fn test_state_apply() {
+        let mut state = ManifestState::default();
+        state.apply(&edit(&[(0, 1), (0, 2)], &[])).unwrap();
+        state.apply(&edit(&[(1, 3)], &[(0, 1), (0, 2)])).unwrap();
+        state.apply(&edit(&[(0, 4)], &[])).unwrap();
+        assert_eq!(state.levels, vec![vec![4], vec![3]]);
+        assert!(state.contains_run(3));
+        assert!(!state.contains_run(1));
+
+        // Removing a run that was never added means the manifest is corrupt
+        assert!(state.apply(&edit(&[], &[(2, 99)])).is_err());
+
+        // Outputs replace the oldest input, below runs added since
+        state.apply(&edit(&[(1, 5), (1, 6), (1, 7)], &[])).unwrap();
+        state
+            .apply(&edit(&[(1, 8), (1, 9)], &[(1, 6), (1, 3)]))
+            .unwrap();
+        assert_eq!(state.levels[1], vec![8, 9, 5, 7]);
+
+        // The latest cursor of each level wins
+        state
+            .apply(&VersionEdit {
+                cursors: vec![(1, 20), (1, 30)],
+                ..VersionEdit::default()
+            })
+            .unwrap();
+        assert_eq!(state.cursors, vec![None, Some(30)]);
+
+        // A snapshot rebuilds the same state
+        let mut rebuilt = ManifestState::default();
+        rebuilt.apply(&state.snapshot()).unwrap();
+        assert_eq!(rebuilt, state);
+    }
+
+    #[test]
+    fn test_create_log_and_recover() {
+        let dir = temp_dir("manifest_recover");
+        assert_eq!(Manifest::recover(&dir).unwrap(), None);
+
+        let mut manifest = Manifest::create(&dir, 1, &VersionEdit::default()).unwrap();
+        manifest.log_edit(&edit(&[(0, 2)], &[])).unwrap();
+        manifest
+            .log_edit(&VersionEdit {
+                added_runs: vec![(1, 4)],
+                removed_runs: vec![(0, 2)],
+                log_number: Some(3),
+                next_file_id: Some(5),
+                cursors: vec![(1, 12)],
+            })
+            .unwrap();
+        drop(manifest);
+
+        let state = Manifest::recover(&dir).unwrap().unwrap();
+        assert_eq!(state.levels, vec![vec![], vec![4]]);
+        assert_eq!(state.log_number, 3);
+        assert_eq!(state.next_file_id, 5);
+        assert_eq!(state.cursors, vec![None, Some(12)]);
+    }
+
+    #[test]
+    fn test_torn_edit_is_ignored() {
+        let dir = temp_dir("manifest_torn_edit");
+        let mut manifest = Manifest::create(&dir, 1, &VersionEdit::default()).unwrap();
+        manifest.log_edit(&edit(&[(0, 2)], &[])).unwrap();
+        manifest.log_edit(&edit(&[(0, 3)], &[])).unwrap();
+        drop(manifest);
+
+        // Chop the last record in half, as a crash during the append would
+        let path = manifest_path(&dir, 1);
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..bytes.len() - 5]).unwrap();
+        let state = Manifest::recover(&dir).unwrap().unwrap();
+        assert_eq!(state.levels, vec![vec![2]]);
+
+        // Damage in the middle of the log is not a torn write
+        let mut manifest = Manifest::create(&dir, 2, &edit(&[(0, 2)], &[])).unwrap();
+        manifest.log_edit(&edit(&[(0, 3)], &[])).unwrap();
+        drop(manifest);
+        let path = manifest_path(&dir, 2);
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[RECORD_HEADER_SIZE] ^= 0xFF;
+        fs::write(&path, &bytes).unwrap();
+        assert!(Manifest::recover(&dir).is_err());
+    }
+
+    #[test]
+    fn test_snapshot_switches_current() {
+        let dir = temp_dir("manifest_snapshot");
+        let mut manifest = Manifest::create(&dir, 1, &VersionEdit::default()).unwrap();
+        for id in 0..SNAPSHOT_INTERVAL as u64 {
+            manifest.log_edit(&edit(&[(0, id + 10)], &[])).unwrap();
+        }
+        assert!(manifest.should_snapshot());
+
+        let state = Manifest::recover(&dir).unwrap().unwrap();
+        let manifest = Manifest::create(&dir, 500, &state.snapshot()).unwrap();
+        assert!(!manifest.should_snapshot());
+
+        // The old manifest is gone and the new one replays to the same state
+        assert!(!manifest_path(&dir, 1).exists());
+        assert_eq!(current_manifest(&dir).unwrap(), Some(500));
+        assert_eq!(Manifest::recover(&dir).unwrap().unwrap(), state);
+    }
+}
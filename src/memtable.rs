@@ -384,7 +384,7 @@ mod tests {
 
         // Value should be from one of the threads
         let final_value = table.get(&1).unwrap();
-        assert!(final_value >= 0 && final_value < 200);
+        assert!((0..200).contains(&final_value));
     }
 
     #[test]
@@ -0,0 +1,101 @@
+use crate::types::{Key, Value};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+/// Merges sorted sources into a single stream in key order.
+///
+/// Sources are given newest first. Where several sources hold a key only the
+/// value from the newest one is returned; tombstones are passed through so
+/// the caller decides whether they still matter. A heap holds the next entry
+/// of every source, so memory use is proportional to the number of sources
+/// rather than the number of entries.
+pub struct MergingIterator<I> {
+    sources: Vec<I>,
+    /// Next entry of each source, keyed by source index on ties so that the
+    /// newest source comes out first
+    heap: BinaryHeap<Reverse<(Key, usize, Value)>>,
+    last_key: Option<Key>,
+}
+
+impl<I: Iterator<Item = (Key, Value)>> MergingIterator<I> {
+    pub fn new(mut sources: Vec<I>) -> Self {
+        let mut heap = BinaryHeap::with_capacity(sources.len());
+        for (index, source) in sources.iter_mut().enumerate() {
+            if let Some((key, value)) = source.next() {
+                heap.push(Reverse((key, index, value)));
+            }
+        }
+        Self {
+            sources,
+            heap,
+            last_key: None,
+        }
+    }
+
+    /// Like [`Iterator::next`], along with the index of the source the entry
+    /// came from.
+    pub fn next_with_source(&mut self) -> Option<(Key, Value, usize)> {
+        loop {
+            let Reverse((key, index, value)) = self.heap.pop()?;
+            if let Some((next_key, next_value)) = self.sources[index].next() {
+                self.heap.push(Reverse((next_key, index, next_value)));
+            }
+
+            // Older versions of a key come out right after the newest one
+            if self.last_key == Some(key) {
+                continue;
+            }
+            self.last_key = Some(key);
+            return Some((key, value, index));
+        }
+    }
+}
+
+impl<I: Iterator<Item = (Key, Value)>> Iterator for MergingIterator<I> {
+    type Item = (Key, Value);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, value, _) = self.next_with_source()?;
+        Some((key, value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::TOMBSTONE;
+
+    #[test]
+    fn test_newest_source_wins() {
+        let newest = vec![(2, 21), (5, TOMBSTONE)];
+        let middle = vec![(1, 10), (2, 20), (4, 40)];
+        let oldest = vec![(2, 19), (3, 30), (5, 50), (6, 60)];
+        let sources = vec![newest.into_iter(), middle.into_iter(), oldest.into_iter()];
+
+        let merged: Vec<_> = MergingIterator::new(sources).collect();
+        assert_eq!(
+            merged,
+            vec![(1, 10), (2, 21), (3, 30), (4, 40), (5, TOMBSTONE), (6, 60)]
+        );
+    }
+
+    #[test]
+    fn test_next_with_source() {
+        let newest = vec![(2, 21)];
+        let oldest = vec![(1, 10), (2, 20)];
+        let mut merged = MergingIterator::new(vec![newest.into_iter(), oldest.into_iter()]);
+        assert_eq!(merged.next_with_source(), Some((1, 10, 1)));
+        assert_eq!(merged.next_with_source(), Some((2, 21, 0)));
+        assert_eq!(merged.next_with_source(), None);
+    }
+
+    #[test]
+    fn test_empty_sources() {
+        let sources: Vec<std::vec::IntoIter<(Key, Value)>> = vec![Vec::new().into_iter()];
+        assert_eq!(MergingIterator::new(sources).next(), None);
+        assert_eq!(
+            MergingIterator::<std::vec::IntoIter<(Key, Value)>>::new(Vec::new()).next(),
+            None
+        );
+    }
+}
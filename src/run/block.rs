@@ -1,9 +1,10 @@
-use super::{CompressionStrategy, Result};
+use super::{CompressionStrategy, Error, Result};
 use crate::types::{Key, Value};
 use std::cmp::{max, min};
 use std::mem;
+use xxhash_rust::xxh3::xxh3_64;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 #[allow(dead_code)]
 pub struct BlockConfig {
     pub target_size: usize,
@@ -11,6 +12,17 @@ pub struct BlockConfig {
     pub max_fill_ratio: f32,
 }
 
+impl BlockConfig {
+    /// Number of entries that fill a block to `max_fill_ratio` of the target
+    /// size; always at least one.
+    pub fn max_entries(&self) -> usize {
+        let budget = (self.target_size as f32 * self.max_fill_ratio) as usize;
+        let entries = budget.saturating_sub(mem::size_of::<BlockHeader>())
+            / mem::size_of::<(Key, Value)>();
+        entries.max(1)
+    }
+}
+
 impl Default for BlockConfig {
     fn default() -> Self {
         Self {
@@ -21,7 +33,7 @@ impl Default for BlockConfig {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 #[allow(dead_code)]
 pub struct BlockHeader {
     pub entry_count: u32,
@@ -46,7 +58,7 @@ impl BlockHeader {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Block {
     pub header: BlockHeader,
     pub entries: Vec<(Key, Value)>,
@@ -100,19 +112,11 @@ impl Block {
             .map(|idx| self.entries[idx].1)
     }
 
-    pub fn range(&self, start: Key, end: Key) -> Vec<(Key, Value)> {
-        if !self.is_sealed || start >= end {
-            return Vec::new();
-        }
-
-        self.entries
-            .iter()
-            .filter(|(k, _)| *k >= start && *k < end)
-            .cloned()
-            .collect()
+    /// Index of the first entry with a key of at least `key`.
+    pub fn seek(&self, key: Key) -> usize {
+        self.entries.partition_point(|&(k, _)| k < key)
     }
 
-    #[allow(dead_code)]
     pub fn serialize(&mut self, compression: &dyn CompressionStrategy) -> Result<Vec<u8>> {
         // First serialize entries to bytes
         let mut data = Vec::new();
@@ -125,21 +129,56 @@ impl Block {
         let compressed = compression.compress(&data)?;
         self.header.compressed_size = compressed.len() as u32;
         self.header.uncompressed_size = data.len() as u32;
+        self.header.checksum = xxh3_64(&compressed);
 
         Ok(compressed)
     }
 
-    #[allow(dead_code)]
-    pub fn deserialize(bytes: &[u8], compression: &dyn CompressionStrategy) -> Result<Self> {
-        let _decompressed = compression.decompress(bytes)?;
-        // TODO: Implement deserialization
-        todo!()
+    /// Rebuilds a block from its serialized form, after checking the bytes
+    /// against the `checksum` recorded when it was written.
+    pub fn deserialize(
+        bytes: &[u8],
+        checksum: u64,
+        compression: &dyn CompressionStrategy,
+    ) -> Result<Self> {
+        let actual = xxh3_64(bytes);
+        if actual != checksum {
+            return Err(Error::Corruption(format!(
+                "Block checksum mismatch: expected {:016x}, found {:016x}",
+                checksum, actual
+            )));
+        }
+        let data = compression.decompress(bytes)?;
+
+        let entry_size = mem::size_of::<(Key, Value)>();
+        if data.len() % entry_size != 0 {
+            return Err(Error::Block(format!(
+                "Block payload of {} bytes is not a multiple of the entry size",
+                data.len()
+            )));
+        }
+
+        let mut block = Block::new();
+        for chunk in data.chunks_exact(entry_size) {
+            let key = Key::from_le_bytes(chunk[0..8].try_into().unwrap());
+            let value = Value::from_le_bytes(chunk[8..16].try_into().unwrap());
+            block.add_entry(key, value)?;
+        }
+        block.seal()?;
+
+        // Sizes reflect the on-disk representation, as after serialize()
+        block.header.compressed_size = bytes.len() as u32;
+        block.header.uncompressed_size = data.len() as u32;
+        block.header.checksum = checksum;
+
+        Ok(block)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::run::NoopCompression;
 
     #[test]
     fn test_block_operations() {
@@ -168,14 +207,39 @@ mod tests {
         assert_eq!(block.get(&2), Some(200));
         assert_eq!(block.get(&4), None);
 
-        // Test range after sealing
-        let range = block.range(1, 3);
-        assert_eq!(range, vec![(1, 100), (2, 200)]);
+        // Test seeking after sealing
+        assert_eq!(block.seek(0), 0);
+        assert_eq!(block.seek(2), 1);
+        assert_eq!(block.seek(4), 3);
 
         // Test adding after sealing
         assert!(!block.add_entry(4, 400).unwrap());
     }
 
+    #[test]
+    fn test_block_config_max_entries() {
+        let config = BlockConfig {
+            target_size: 4096,
+            min_fill_ratio: 0.5,
+            max_fill_ratio: 0.9,
+        };
+        let max_entries = config.max_entries();
+        let mut block = Block::new();
+        for key in 0..max_entries as Key {
+            block.add_entry(key, key).unwrap();
+        }
+        assert!(block.estimated_size() <= 3686);
+        block.add_entry(Key::MAX, 0).unwrap();
+        assert!(block.estimated_size() > 3686);
+
+        // Even a tiny target holds one entry per block
+        let tiny = BlockConfig {
+            target_size: 1,
+            ..config
+        };
+        assert_eq!(tiny.max_entries(), 1);
+    }
+
     #[test]
     fn test_block_header() {
         let mut block = Block::new();
@@ -188,4 +252,38 @@ mod tests {
         assert_eq!(block.header.min_key, 3);
         assert_eq!(block.header.max_key, 7);
     }
+
+    #[test]
+    fn test_block_serialization_roundtrip() {
+        let compression = NoopCompression;
+        let mut block = Block::new();
+        block.add_entry(3, 300).unwrap();
+        block.add_entry(1, 100).unwrap();
+        block.add_entry(2, 200).unwrap();
+        block.seal().unwrap();
+
+        let bytes = block.serialize(&compression).unwrap();
+        assert_eq!(block.header.checksum, xxh3_64(&bytes));
+        let restored = Block::deserialize(&bytes, block.header.checksum, &compression).unwrap();
+
+        assert!(restored.is_sealed);
+        assert_eq!(restored.entries, block.entries);
+        assert_eq!(restored.header.entry_count, 3);
+        assert_eq!(restored.header.min_key, 1);
+        assert_eq!(restored.header.max_key, 3);
+        assert_eq!(restored.header.compressed_size, block.header.compressed_size);
+        assert_eq!(restored.get(&2), Some(200));
+
+        // Truncated payloads are rejected rather than misread
+        let truncated = &bytes[..bytes.len() - 1];
+        assert!(Block::deserialize(truncated, xxh3_64(truncated), &compression).is_err());
+
+        // As are payloads that don't match their checksum
+        let mut flipped = bytes.clone();
+        flipped[3] ^= 0x10;
+        assert!(matches!(
+            Block::deserialize(&flipped, block.header.checksum, &compression),
+            Err(Error::Corruption(_))
+        ));
+    }
 }
\ No newline at end of file
@@ -30,7 +30,7 @@ mod tests {
 
     #[test]
     fn test_noop_compression() {
-        let compression = NoopCompression::default();
+        let compression = NoopCompression;
 
         // Test empty data
         let empty: &[u8] = &[];
@@ -51,7 +51,7 @@ mod tests {
 
     #[test]
     fn test_compression_large_data() {
-        let compression = NoopCompression::default();
+        let compression = NoopCompression;
         let data: Vec<u8> = (0..1000).map(|i| (i % 256) as u8).collect();
 
         let compressed = compression.compress(&data).unwrap();
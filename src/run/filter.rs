@@ -1,6 +1,13 @@
 use super::{Error, Result};
 use crate::types::Key;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use xxhash_rust::xxh3::xxh3_128;
+
+/// Hash the crate's Bloom filters derive their probes from, so a key can be
+/// hashed once and looked up in any number of runs.
+pub fn key_hash(key: &Key) -> u128 {
+    xxh3_128(&key.to_le_bytes())
+}
 
 #[allow(dead_code)]
 pub trait FilterStrategy: Send + Sync {
@@ -9,13 +16,77 @@ pub trait FilterStrategy: Send + Sync {
         Self: Sized;
     fn add(&mut self, key: &Key) -> Result<()>;
     fn may_contain(&self, key: &Key) -> bool;
+    /// [`Self::may_contain`] for a key whose [`key_hash`] is already known.
+    fn may_contain_hash(&self, key: &Key, _hash: u128) -> bool {
+        self.may_contain(key)
+    }
+    /// Starts loading the memory a lookup of `hash` will probe, so lookups
+    /// for a batch of keys overlap their cache misses.
+    fn prefetch(&self, _hash: u128) {}
     fn false_positive_rate(&self) -> f64;
+    /// Keys added so far, duplicates included.
+    fn num_entries(&self) -> usize;
+    /// Bytes of filter data held in memory.
+    fn memory_usage(&self) -> usize;
+    /// Bits or slots a lookup checks.
+    fn num_probes(&self) -> u32;
     fn serialize(&self) -> Result<Vec<u8>>;
     fn deserialize(bytes: &[u8]) -> Result<Self>
     where
         Self: Sized;
 }
 
+/// Outcome of lookups for keys a run doesn't hold, counted as they happen so
+/// the observed false-positive rate can be checked against the filter's
+/// theoretical one.
+#[derive(Debug, Default)]
+pub struct FilterStats {
+    true_negatives: AtomicU64,
+    false_positives: AtomicU64,
+}
+
+impl FilterStats {
+    /// Records a lookup the filter turned away.
+    pub fn record_true_negative(&self) {
+        self.true_negatives.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a lookup that passed the filter but missed in the run.
+    pub fn record_false_positive(&self) {
+        self.false_positives.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn counts(&self) -> FilterCounts {
+        FilterCounts {
+            true_negatives: self.true_negatives.load(Ordering::Relaxed),
+            false_positives: self.false_positives.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Snapshot of [`FilterStats`], which adds up over runs and levels.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FilterCounts {
+    pub true_negatives: u64,
+    pub false_positives: u64,
+}
+
+impl FilterCounts {
+    /// Share of lookups for absent keys that got past the filter, or `None`
+    /// before there have been any.
+    pub fn false_positive_rate(&self) -> Option<f64> {
+        let negatives = self.true_negatives + self.false_positives;
+        (negatives > 0).then(|| self.false_positives as f64 / negatives as f64)
+    }
+}
+
+impl std::ops::AddAssign for FilterCounts {
+    fn add_assign(&mut self, other: Self) {
+        self.true_negatives += other.true_negatives;
+        self.false_positives += other.false_positives;
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct NoopFilter {
     entry_count: AtomicUsize,
@@ -41,6 +112,18 @@ impl FilterStrategy for NoopFilter {
         1.0
     }
 
+    fn num_entries(&self) -> usize {
+        self.entry_count.load(Ordering::SeqCst)
+    }
+
+    fn memory_usage(&self) -> usize {
+        0
+    }
+
+    fn num_probes(&self) -> u32 {
+        0
+    }
+
     fn serialize(&self) -> Result<Vec<u8>> {
         // Get the value and convert it to bytes
         let count = self.entry_count.load(Ordering::SeqCst);
@@ -101,6 +184,26 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_filter_stats() {
+        let stats = FilterStats::default();
+        assert_eq!(stats.counts().false_positive_rate(), None);
+
+        for _ in 0..3 {
+            stats.record_true_negative();
+        }
+        stats.record_false_positive();
+        let mut counts = stats.counts();
+        assert_eq!(counts.false_positive_rate(), Some(0.25));
+
+        counts += FilterCounts {
+            true_negatives: 4,
+            false_positives: 0,
+        };
+        assert_eq!(counts.true_negatives, 7);
+        assert_eq!(counts.false_positive_rate(), Some(0.125));
+    }
+
     #[test]
     fn test_noop_filter_counting() {
         let mut filter = NoopFilter::new(0);
@@ -3,12 +3,31 @@ mod compression;
 mod filter;
 
 use crate::types::{Key, Value};
-use std::io;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use xxhash_rust::xxh3::Xxh3;
 
-use crate::bloom::Bloom;
+use crate::bloom::{BloomParams, FilterKind, RangeFilter};
 pub use block::{Block, BlockConfig};
 pub use compression::{CompressionStrategy, NoopCompression};
-pub use filter::{FilterStrategy, NoopFilter};
+pub use filter::{key_hash, FilterCounts, FilterStats, FilterStrategy};
+
+/// Magic number at the very end of every run file ("LSMRUN01")
+const RUN_MAGIC: u64 = 0x4C534D52554E3031;
+/// Bumped whenever the on-disk layout changes
+const RUN_FORMAT_VERSION: u32 = 5;
+/// index_offset, filter_offset, filter_len, range_filter_len, entry_count,
+/// block_count, filter_kind, checksum, version, magic
+const FOOTER_SIZE: usize = 8 + 8 + 8 + 8 + 8 + 4 + 4 + 8 + 4 + 8;
+/// Offset of the file checksum within the footer; it covers every byte before it
+const FOOTER_CHECKSUM_OFFSET: usize = 8 + 8 + 8 + 8 + 8 + 4 + 4;
+/// offset, compressed_size, uncompressed_size, entry_count, min_key, max_key, checksum
+const INDEX_ENTRY_SIZE: usize = 8 + 4 + 4 + 4 + 8 + 8 + 8;
+/// Keys whose filter probes are prefetched together by [`Run::multi_get`]
+const PREFETCH_BATCH: usize = 16;
 
 #[derive(Debug)]
 #[allow(dead_code)]
@@ -18,131 +37,772 @@ pub enum Error {
     Block(String),
     Filter(String),
     Compression(String),
+    /// Stored data doesn't match its checksum
+    Corruption(String),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Serialization(msg) => write!(f, "Serialization error: {}", msg),
+            Error::Block(msg) => write!(f, "Block error: {}", msg),
+            Error::Filter(msg) => write!(f, "Filter error: {}", msg),
+            Error::Compression(msg) => write!(f, "Compression error: {}", msg),
+            Error::Corruption(msg) => write!(f, "Corruption: {}", msg),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[allow(dead_code)]
 pub struct Run {
-    data: Vec<(Key, Value)>,
-    // Add new fields
     block_config: BlockConfig,
-    blocks: Vec<Block>,
+    /// Blocks held in memory; empty once a persisted run has released them
+    blocks: Vec<Arc<Block>>,
+    /// Backing file and its block index, set once the run has been persisted
+    /// or restored
+    file: Option<RunFile>,
+    /// Smallest key of each block, so lookups binary-search straight to the
+    /// one block that may hold a key
+    fences: Vec<Key>,
     filter: Box<dyn FilterStrategy>,
+    filter_kind: FilterKind,
+    /// How the filter has fared on lookups since the run was built or loaded
+    filter_stats: FilterStats,
+    /// Lets range queries skip the run when no key falls in the range
+    range_filter: Option<RangeFilter>,
     compression: Box<dyn CompressionStrategy>,
+    /// Path of the backing file
+    path: Option<PathBuf>,
 }
 
 impl Run {
-    pub fn new(data: Vec<(Key, Value)>) -> Self {
-        let block_config = BlockConfig::default();
-        let mut blocks = Vec::new();
-
-        // Initialize filter with data size
-        // Using 10 bits per entry and 6 probes as shown in the test cases
-        let total_bits = (data.len() * 10) as u32;
-        let num_probes = 6;
-        let mut filter: Box<dyn FilterStrategy> = Box::new(Bloom::new(total_bits, num_probes));
-
-        // Create initial block and populate filter
-        if !data.is_empty() {
+    pub fn new(data: Vec<(Key, Value)>, bloom: BloomParams) -> Self {
+        Self::with_block_config(data, bloom, BlockConfig::default())
+    }
+
+    /// Builds a run from `data`, split into blocks sized by `block_config`.
+    ///
+    /// Blocks are filled up to `max_fill_ratio` of the target size, with the
+    /// entries spread evenly over them so the last block isn't left as a
+    /// sliver.
+    pub fn with_block_config(
+        mut data: Vec<(Key, Value)>,
+        bloom: BloomParams,
+        block_config: BlockConfig,
+    ) -> Self {
+        data.sort_by_key(|&(key, _)| key);
+        let keys: Vec<Key> = data.iter().map(|&(key, _)| key).collect();
+        let filter = bloom.kind.build(&bloom, &keys);
+
+        // Create blocks
+        let block_count = data.len().div_ceil(block_config.max_entries());
+        let mut blocks = Vec::with_capacity(block_count);
+        for index in 0..block_count {
+            let chunk =
+                &data[index * data.len() / block_count..(index + 1) * data.len() / block_count];
             let mut block = Block::new();
-            for (k, v) in data.iter() {
+            for (k, v) in chunk {
                 block.add_entry(*k, *v).unwrap();
-                filter.add(k).unwrap();
             }
             block.seal().unwrap();
-            blocks.push(block);
+            blocks.push(Arc::new(block));
         }
 
         Run {
-            data,
             block_config,
+            fences: blocks.iter().map(|block| block.header.min_key).collect(),
             blocks,
+            file: None,
             filter,
+            filter_kind: bloom.kind,
+            filter_stats: FilterStats::default(),
+            range_filter: bloom.range.map(|params| RangeFilter::build(&keys, &params)),
             compression: Box::new(NoopCompression),
+            path: None,
         }
     }
 
     pub fn get(&self, key: Key) -> Option<Value> {
-        // First check filter
-        if !self.filter.may_contain(&key) {
+        let may_contain = self.filter.may_contain(&key);
+        self.get_filtered(key, may_contain)
+    }
+
+    /// Looks up every key in `lookups`, storing those the run holds in
+    /// `results` and dropping them from `lookups`.
+    ///
+    /// The filter memory for a batch of keys is prefetched before any of them
+    /// is probed, so their cache misses overlap instead of being paid one
+    /// after another.
+    pub fn multi_get(&self, lookups: &mut Vec<Lookup>, results: &mut [Option<Value>]) {
+        for batch in lookups.chunks(PREFETCH_BATCH) {
+            for lookup in batch {
+                self.filter.prefetch(lookup.hash);
+            }
+            for lookup in batch {
+                let may_contain = self.filter.may_contain_hash(&lookup.key, lookup.hash);
+                results[lookup.index] = self.get_filtered(lookup.key, may_contain);
+            }
+        }
+        lookups.retain(|lookup| results[lookup.index].is_none());
+    }
+
+    /// Finishes a lookup once the filter has had its say.
+    fn get_filtered(&self, key: Key, may_contain: bool) -> Option<Value> {
+        if !may_contain {
+            self.filter_stats.record_true_negative();
             return None;
         }
 
-        // Check blocks
-        for block in &self.blocks {
-            if let Some(value) = block.get(&key) {
-                return Some(value);
-            }
+        // Only the last block starting at or before the key can hold it
+        let block = self.fences.partition_point(|&min_key| min_key <= key);
+        let value = block
+            .checked_sub(1)
+            .and_then(|block| self.block(block).get(&key));
+        if value.is_none() {
+            self.filter_stats.record_false_positive();
         }
+        value
+    }
 
-        None
+    /// Expected false-positive rate of the run's filter.
+    pub fn filter_false_positive_rate(&self) -> f64 {
+        self.filter.false_positive_rate()
     }
 
-    pub fn range(&self, start: Key, end: Key) -> Vec<(Key, Value)> {
-        let mut results = Vec::new();
+    pub fn filter_kind(&self) -> FilterKind {
+        self.filter_kind
+    }
 
-        for block in &self.blocks {
-            if block.header.min_key <= end && block.header.max_key >= start {
-                results.extend(block.range(start, end));
-            }
+    /// Keys added to the run's filter.
+    pub fn filter_entries(&self) -> usize {
+        self.filter.num_entries()
+    }
+
+    /// Bytes of memory the run's filter takes up.
+    pub fn filter_memory_usage(&self) -> usize {
+        self.filter.memory_usage()
+    }
+
+    /// Bits or slots of the run's filter a lookup checks.
+    pub fn filter_probes(&self) -> u32 {
+        self.filter.num_probes()
+    }
+
+    /// Lookups for absent keys the filter stopped or let through so far.
+    pub fn filter_counts(&self) -> FilterCounts {
+        self.filter_stats.counts()
+    }
+
+    /// Streams the entries of `run` in `[start, end)` in key order.
+    ///
+    /// The cursor shares ownership of the run, so it stays valid after the run
+    /// has been merged away. If the run's range filter rules the range out, the
+    /// cursor is empty without touching any block.
+    pub fn cursor(run: Arc<Run>, start: Key, end: Key) -> RunCursor {
+        if !run.may_contain_range(start, end) {
+            return RunCursor {
+                block: run.block_count(),
+                current: None,
+                run,
+                offset: 0,
+                end,
+            };
         }
 
-        results
+        // Start in the block that may hold `start`, skipping those before it
+        let block = run
+            .fences
+            .partition_point(|&min_key| min_key <= start)
+            .saturating_sub(1);
+        let current = (block < run.block_count()).then(|| run.block(block));
+        let offset = current.as_ref().map_or(0, |block| block.seek(start));
+        RunCursor {
+            run,
+            block,
+            current,
+            offset,
+            end,
+        }
+    }
+
+    /// Whether the run may hold keys in `[start, end)`, going by its range
+    /// filter if it has one.
+    pub fn may_contain_range(&self, start: Key, end: Key) -> bool {
+        self.range_filter
+            .as_ref()
+            .map_or(true, |filter| filter.may_contain_range(start, end))
+    }
+
+    /// Number of entries in the run, tombstones included.
+    pub fn len(&self) -> usize {
+        match &self.file {
+            Some(file) => file
+                .index
+                .iter()
+                .map(|handle| handle.entry_count as usize)
+                .sum(),
+            None => self.blocks.iter().map(|block| block.entries.len()).sum(),
+        }
+    }
+
+    /// Smallest and largest key in the run, or `None` if it is empty.
+    pub fn key_range(&self) -> Option<(Key, Key)> {
+        match &self.file {
+            Some(file) => Some((file.index.first()?.min_key, file.index.last()?.max_key)),
+            None => Some((
+                self.blocks.first()?.header.min_key,
+                self.blocks.last()?.header.max_key,
+            )),
+        }
     }
 
-    #[allow(dead_code)]
-    pub fn persist(&mut self) -> Result<()> {
-        // Ensure each block is serialized
+    /// All entries in key order.
+    pub fn entries(&self) -> impl Iterator<Item = (Key, Value)> + '_ {
+        (0..self.block_count()).flat_map(move |index| {
+            let block = self.block(index);
+            (0..block.entries.len()).map(move |entry| block.entries[entry])
+        })
+    }
+
+    fn block_count(&self) -> usize {
+        self.fences.len()
+    }
+
+    /// Block `index`, from memory or read back from the run's file.
+    ///
+    /// The file's blocks were all checked when it was written or restored, so
+    /// one failing to read back later means the file was damaged underneath
+    /// the tree.
+    fn block(&self, index: usize) -> Arc<Block> {
+        if let Some(block) = self.blocks.get(index) {
+            return Arc::clone(block);
+        }
+        let file = self
+            .file
+            .as_ref()
+            .expect("Run has neither blocks nor a file");
+        match file.read_block(index, &*self.compression) {
+            Ok(block) => Arc::new(block),
+            Err(e) => panic!(
+                "Failed to read block {} of {}: {}",
+                index,
+                self.path.as_deref().unwrap_or(Path::new("?")).display(),
+                e
+            ),
+        }
+    }
+
+    /// Drops the in-memory copy of a persisted run's blocks; from then on
+    /// they are read from its file when needed. Runs that were never
+    /// persisted keep theirs.
+    pub fn release_blocks(&mut self) {
+        if self.file.is_some() {
+            self.blocks = Vec::new();
+        }
+    }
+
+    /// Path of the file backing this run, if it has been persisted.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// File id of a persisted run, as recorded in the manifest.
+    pub fn id(&self) -> Option<u64> {
+        crate::filename::parse(self.path()?).and_then(|kind| kind.id())
+    }
+
+    /// Writes the run to `path` as a self-describing run file.
+    ///
+    /// Layout (all integers little-endian):
+    /// ```text
+    /// [block 0] .. [block n-1] [filter] [range filter] [block index] [footer]
+    /// ```
+    /// The block index holds one fixed-size entry per block (offset, sizes, entry
+    /// count, key range, checksum) and the footer locates the index and filters
+    /// and records which kind of filter it is. Runs without a range filter
+    /// store it as zero bytes.
+    /// The footer also carries an xxh3 checksum of everything before it, so any
+    /// damage to the file is caught when it is restored.
+    /// The file is written to a temporary name and renamed into place so a crash
+    /// never leaves a partially written run under its final name.
+    pub fn persist(&mut self, path: &Path) -> Result<()> {
+        if self.blocks.len() != self.block_count() {
+            return Err(Error::Serialization(
+                "Run has released its blocks and can't be written again".to_string(),
+            ));
+        }
+
+        let tmp_path = crate::filename::temp_path(path);
+        let mut writer = ChecksumWriter::new(BufWriter::new(File::create(&tmp_path)?));
+        let mut offset = 0u64;
+        let mut handles = Vec::with_capacity(self.blocks.len());
+        let mut index = Vec::with_capacity(self.blocks.len() * INDEX_ENTRY_SIZE);
+        let mut entry_count = 0u64;
+
         for block in &mut self.blocks {
-            block.serialize(&*self.compression)?;
+            let block = Arc::make_mut(block);
+            let bytes = block.serialize(&*self.compression)?;
+            writer.write_all(&bytes)?;
+
+            let handle = BlockHandle {
+                offset,
+                compressed_size: block.header.compressed_size,
+                uncompressed_size: block.header.uncompressed_size,
+                entry_count: block.header.entry_count,
+                min_key: block.header.min_key,
+                max_key: block.header.max_key,
+                checksum: block.header.checksum,
+            };
+            handle.encode(&mut index);
+            handles.push(handle);
+
+            offset += bytes.len() as u64;
+            entry_count += block.header.entry_count as u64;
+        }
+
+        let filter_offset = offset;
+        let filter_data = self.filter.serialize()?;
+        writer.write_all(&filter_data)?;
+        let range_filter_data = match &self.range_filter {
+            Some(filter) => filter.serialize()?,
+            None => Vec::new(),
+        };
+        writer.write_all(&range_filter_data)?;
+        let index_offset = filter_offset + (filter_data.len() + range_filter_data.len()) as u64;
+        writer.write_all(&index)?;
+
+        writer.write_all(&index_offset.to_le_bytes())?;
+        writer.write_all(&filter_offset.to_le_bytes())?;
+        writer.write_all(&(filter_data.len() as u64).to_le_bytes())?;
+        writer.write_all(&(range_filter_data.len() as u64).to_le_bytes())?;
+        writer.write_all(&entry_count.to_le_bytes())?;
+        writer.write_all(&(self.blocks.len() as u32).to_le_bytes())?;
+        writer.write_all(&self.filter_kind.id().to_le_bytes())?;
+        let checksum = writer.checksum();
+        writer.write_all(&checksum.to_le_bytes())?;
+        writer.write_all(&RUN_FORMAT_VERSION.to_le_bytes())?;
+        writer.write_all(&RUN_MAGIC.to_le_bytes())?;
+
+        let file = writer
+            .inner
+            .into_inner()
+            .map_err(|e| Error::Io(e.into_error()))?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)?;
+
+        self.file = Some(RunFile {
+            file: File::open(path)?,
+            index: handles,
+        });
+        self.path = Some(path.to_path_buf());
+        Ok(())
+    }
+
+    /// Reopens a run previously written with [`Run::persist`].
+    ///
+    /// Only the block index and the filters are kept in memory; blocks are
+    /// read from the file when a lookup or scan needs them. Every byte is
+    /// still checked once here, streaming through the file a block at a time.
+    pub fn restore(path: &Path) -> Result<Self> {
+        let file = File::open(path)?;
+        let file_len = file.metadata()?.len() as usize;
+        if file_len < FOOTER_SIZE {
+            return Err(Error::Serialization(format!(
+                "Run file {} is too small to contain a footer",
+                path.display()
+            )));
+        }
+
+        let mut footer = [0u8; FOOTER_SIZE];
+        file.read_exact_at(&mut footer, (file_len - FOOTER_SIZE) as u64)?;
+        let index_offset = read_u64(&footer, 0) as usize;
+        let filter_offset = read_u64(&footer, 8) as usize;
+        let filter_len = read_u64(&footer, 16) as usize;
+        let range_filter_len = read_u64(&footer, 24) as usize;
+        let entry_count = read_u64(&footer, 32);
+        let block_count = read_u32(&footer, 40) as usize;
+        let filter_kind = read_u32(&footer, 44);
+        let checksum = read_u64(&footer, FOOTER_CHECKSUM_OFFSET);
+        let version = read_u32(&footer, FOOTER_CHECKSUM_OFFSET + 8);
+        let magic = read_u64(&footer, FOOTER_CHECKSUM_OFFSET + 12);
+
+        if magic != RUN_MAGIC {
+            return Err(Error::Serialization(format!(
+                "{} is not a run file",
+                path.display()
+            )));
+        }
+        if version != RUN_FORMAT_VERSION {
+            return Err(Error::Serialization(format!(
+                "Unsupported run format version {}",
+                version
+            )));
+        }
+
+        // Nothing in the file can be trusted if it has been damaged
+        let mut hasher = Xxh3::new();
+        let mut reader = BufReader::new(&file).take((file_len - FOOTER_SIZE) as u64);
+        io::copy(&mut reader, &mut HashWriter(&mut hasher))?;
+        hasher.update(&footer[..FOOTER_CHECKSUM_OFFSET]);
+        let actual = hasher.digest();
+        if actual != checksum {
+            return Err(Error::Corruption(format!(
+                "Run file {} checksum mismatch: expected {:016x}, found {:016x}",
+                path.display(),
+                checksum,
+                actual
+            )));
+        }
+
+        let index_end = index_offset + block_count * INDEX_ENTRY_SIZE;
+        let range_filter_offset = filter_offset + filter_len;
+        if range_filter_offset + range_filter_len != index_offset
+            || index_end != file_len - FOOTER_SIZE
+        {
+            return Err(Error::Serialization(
+                "Run file footer does not match file layout".to_string(),
+            ));
+        }
+
+        let mut index_bytes = vec![0u8; index_end - index_offset];
+        file.read_exact_at(&mut index_bytes, index_offset as u64)?;
+        let index: Vec<BlockHandle> = index_bytes
+            .chunks_exact(INDEX_ENTRY_SIZE)
+            .map(BlockHandle::decode)
+            .collect();
+        for handle in &index {
+            if handle.offset as usize + handle.compressed_size as usize > filter_offset {
+                return Err(Error::Block(format!(
+                    "Block at offset {} extends past the data section",
+                    handle.offset
+                )));
+            }
+        }
+        let run_file = RunFile { file, index };
+
+        // Each block must also match its own checksum and index entry
+        let compression: Box<dyn CompressionStrategy> = Box::new(NoopCompression);
+        for block in 0..run_file.index.len() {
+            run_file.read_block(block, &*compression)?;
         }
 
-        // Also serialize filter (will need this later)
-        let _filter_data = self.filter.serialize()?;
+        let restored_count: u64 = run_file
+            .index
+            .iter()
+            .map(|handle| handle.entry_count as u64)
+            .sum();
+        if restored_count != entry_count {
+            return Err(Error::Serialization(format!(
+                "Run file holds {} entries, footer claims {}",
+                restored_count, entry_count
+            )));
+        }
+
+        let filter_kind = FilterKind::from_id(filter_kind)
+            .ok_or_else(|| Error::Filter(format!("Unknown filter kind {}", filter_kind)))?;
+        let mut filter_bytes = vec![0u8; index_offset - filter_offset];
+        run_file
+            .file
+            .read_exact_at(&mut filter_bytes, filter_offset as u64)?;
+        let filter = filter_kind.deserialize(&filter_bytes[..filter_len])?;
+        let range_filter = match range_filter_len {
+            0 => None,
+            _ => Some(RangeFilter::deserialize(&filter_bytes[filter_len..])?),
+        };
+
+        Ok(Run {
+            block_config: BlockConfig::default(),
+            fences: run_file.index.iter().map(|handle| handle.min_key).collect(),
+            blocks: Vec::new(),
+            file: Some(run_file),
+            filter,
+            filter_kind,
+            filter_stats: FilterStats::default(),
+            range_filter,
+            compression,
+            path: Some(path.to_path_buf()),
+        })
+    }
+}
+
+/// A persisted run's file, with the block index that locates its blocks.
+struct RunFile {
+    file: File,
+    index: Vec<BlockHandle>,
+}
+
+impl RunFile {
+    /// Reads block `index` and checks it against its index entry.
+    fn read_block(&self, index: usize, compression: &dyn CompressionStrategy) -> Result<Block> {
+        let handle = &self.index[index];
+        let mut bytes = vec![0u8; handle.compressed_size as usize];
+        self.file.read_exact_at(&mut bytes, handle.offset)?;
+        let block = Block::deserialize(&bytes, handle.checksum, compression)?;
+        if block.header.entry_count != handle.entry_count
+            || (block.header.entry_count > 0
+                && (block.header.min_key != handle.min_key
+                    || block.header.max_key != handle.max_key))
+        {
+            return Err(Error::Block(format!(
+                "Block at offset {} does not match its index entry",
+                handle.offset
+            )));
+        }
+        Ok(block)
+    }
+}
+
+/// Entry of a run file's block index.
+#[derive(Debug, Clone, Copy)]
+struct BlockHandle {
+    offset: u64,
+    compressed_size: u32,
+    uncompressed_size: u32,
+    entry_count: u32,
+    min_key: Key,
+    max_key: Key,
+    checksum: u64,
+}
+
+impl BlockHandle {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        bytes.extend_from_slice(&self.offset.to_le_bytes());
+        bytes.extend_from_slice(&self.compressed_size.to_le_bytes());
+        bytes.extend_from_slice(&self.uncompressed_size.to_le_bytes());
+        bytes.extend_from_slice(&self.entry_count.to_le_bytes());
+        bytes.extend_from_slice(&self.min_key.to_le_bytes());
+        bytes.extend_from_slice(&self.max_key.to_le_bytes());
+        bytes.extend_from_slice(&self.checksum.to_le_bytes());
+    }
+
+    fn decode(bytes: &[u8]) -> Self {
+        Self {
+            offset: read_u64(bytes, 0),
+            compressed_size: read_u32(bytes, 8),
+            uncompressed_size: read_u32(bytes, 12),
+            entry_count: read_u32(bytes, 16),
+            min_key: read_i64(bytes, 20),
+            max_key: read_i64(bytes, 28),
+            checksum: read_u64(bytes, 36),
+        }
+    }
+}
+
+/// Adapts a hasher to [`io::copy`].
+struct HashWriter<'a>(&'a mut Xxh3);
 
+impl Write for HashWriter<'_> {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.0.update(bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
         Ok(())
     }
+}
+
+/// Writer that keeps a running checksum of everything written through it.
+struct ChecksumWriter<W> {
+    inner: W,
+    hasher: Xxh3,
+}
+
+impl<W: Write> ChecksumWriter<W> {
+    fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Xxh3::new(),
+        }
+    }
+
+    fn write_all(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.hasher.update(bytes);
+        self.inner.write_all(bytes)
+    }
+
+    fn checksum(&self) -> u64 {
+        self.hasher.digest()
+    }
+}
+
+/// A key looked up by [`Run::multi_get`], with its [`key_hash`] and where its
+/// value goes in the results.
+#[derive(Debug, Clone, Copy)]
+pub struct Lookup {
+    pub index: usize,
+    pub key: Key,
+    pub hash: u128,
+}
+
+impl Lookup {
+    pub fn new(index: usize, key: Key) -> Self {
+        Self {
+            index,
+            key,
+            hash: key_hash(&key),
+        }
+    }
+}
 
-    #[allow(dead_code)]
-    pub fn restore(bytes: &[u8]) -> Result<Self> {
-        // TODO: Implement restoration
-        let _filter = NoopFilter::deserialize(bytes)?;
-        todo!()
+/// Cursor returned by [`Run::cursor`].
+pub struct RunCursor {
+    run: Arc<Run>,
+    block: usize,
+    /// The block the cursor is in, loaded when it gets there
+    current: Option<Arc<Block>>,
+    offset: usize,
+    end: Key,
+}
+
+impl Iterator for RunCursor {
+    type Item = (Key, Value);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.block >= self.run.block_count() {
+                return None;
+            }
+            let block = self
+                .current
+                .get_or_insert_with(|| self.run.block(self.block));
+            match block.entries.get(self.offset) {
+                Some(&(key, _)) if key >= self.end => {
+                    self.block = self.run.block_count();
+                    self.current = None;
+                    return None;
+                }
+                Some(&entry) => {
+                    self.offset += 1;
+                    return Some(entry);
+                }
+                None => {
+                    self.block += 1;
+                    self.offset = 0;
+                    self.current = None;
+                }
+            }
+        }
     }
 }
 
+#[inline]
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+#[inline]
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+#[inline]
+fn read_i64(bytes: &[u8], offset: usize) -> i64 {
+    i64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use xxhash_rust::xxh3::xxh3_128;
+    use crate::bloom::{Bloom, RangeFilterParams};
+    use crate::test_helpers::temp_dir;
+    use xxhash_rust::xxh3::{xxh3_128, xxh3_64};
 
     #[test]
     fn test_run_operations() {
         let data = vec![(1, 100), (2, 200), (3, 300)];
-        let run = Run::new(data);
+        let run = Run::new(data, BloomParams::default());
 
         // Test basic operations
         assert_eq!(run.get(2), Some(200));
         assert_eq!(run.get(4), None);
 
-        // Test range query
-        let range = run.range(1, 3);
-        assert_eq!(range, vec![(1, 100), (2, 200)]);
-
         // Verify blocks were created
         assert!(!run.blocks.is_empty());
 
         // Verify filter works
         assert!(run.filter.may_contain(&1));
+
+        // Test range query
+        let range: Vec<_> = Run::cursor(Arc::new(run), 1, 3).collect();
+        assert_eq!(range, vec![(1, 100), (2, 200)]);
+    }
+
+    #[test]
+    fn test_fence_pointers() {
+        let config = BlockConfig::default();
+        let max_entries = config.max_entries();
+        let data: Vec<_> = (0..max_entries as Key * 5 + 3)
+            .map(|i| (i * 2, i))
+            .collect();
+        let run = Run::with_block_config(data.clone(), BloomParams::default(), config);
+
+        // Blocks are evenly filled, in key order, and never over the target
+        assert_eq!(run.blocks.len(), 6);
+        assert_eq!(run.fences.len(), run.blocks.len());
+        for (block, &min_key) in run.blocks.iter().zip(&run.fences) {
+            assert_eq!(block.header.min_key, min_key);
+            assert!(block.entries.len() <= max_entries);
+            assert!(block.entries.len() >= data.len() / 6);
+        }
+        assert!(run
+            .blocks
+            .windows(2)
+            .all(|pair| pair[0].header.max_key < pair[1].header.min_key));
+
+        // Every key is found through its fence, and gaps between keys are not
+        for &(key, value) in &data {
+            assert_eq!(run.get(key), Some(value));
+            assert_eq!(run.get(key + 1), None);
+        }
+        assert_eq!(run.get(-1), None);
+
+        // Scans start inside the right block, including from a gap
+        let start = run.fences[3] - 1;
+        let scanned: Vec<_> = Run::cursor(Arc::new(run), start, start + 7).collect();
+        let expected: Vec<_> = data
+            .iter()
+            .copied()
+            .filter(|&(key, _)| key >= start && key < start + 7)
+            .collect();
+        assert_eq!(scanned, expected);
+    }
+
+    #[test]
+    fn test_filter_follows_error_rate() {
+        let data: Vec<_> = (0..10_000).map(|i| (i, i)).collect();
+        let loose = Run::new(data.clone(), BloomParams::from_error_rate(0.1));
+        let tight = Run::new(data, BloomParams::from_error_rate(0.001));
+
+        let loose_size = loose.filter.serialize().unwrap().len();
+        let tight_size = tight.filter.serialize().unwrap().len();
+        assert!(tight_size > loose_size * 2);
+
+        // Tighter filters let fewer absent keys through
+        let false_positives = |run: &Run| {
+            (10_000..20_000)
+                .filter(|&key| run.filter.may_contain(&key))
+                .count()
+        };
+        assert!(false_positives(&tight) < false_positives(&loose));
     }
 
     #[test]
     fn test_compression() {
-        let mut run = Run::new(vec![(1, 100), (2, 200)]);
+        let dir = temp_dir("run_compression");
+        let mut run = Run::new(vec![(1, 100), (2, 200)], BloomParams::default());
 
         // Test persistence with NoopCompression
-        run.persist().unwrap();
+        run.persist(&dir.join("compression.run")).unwrap();
 
         // With NoopCompression, compressed size should equal uncompressed size
         // and both should be greater than 0
@@ -156,7 +816,7 @@ mod tests {
     #[test]
     fn test_filter_operations() {
         let data = vec![(1i64, 100), (2i64, 200)];
-        let run = Run::new(data);
+        let run = Run::new(data, BloomParams::default());
 
         // Test that filter is properly filtering
         assert!(run.filter.may_contain(&1i64));
@@ -199,11 +859,216 @@ mod tests {
     #[test]
     fn test_bloom_filter() {
         let data = vec![(1, 100), (2, 200)];
-        let run = Run::new(data);
+        let run = Run::new(data, BloomParams::default());
 
         // Test filter behavior
         assert!(run.filter.may_contain(&1));
         assert!(run.filter.may_contain(&2));
         assert!(!run.filter.may_contain(&3));
     }
+
+    #[test]
+    fn test_observed_false_positive_rate() {
+        let data: Vec<(Key, Value)> = (0..1000).map(|i| (i * 2, i)).collect();
+        let run = Run::new(data, BloomParams::from_error_rate(0.05));
+        assert_eq!(run.filter_counts(), FilterCounts::default());
+
+        // Hits are not counted, only lookups for absent keys
+        assert_eq!(run.get(10), Some(5));
+        let lookups = 100_000;
+        for key in 0..lookups {
+            assert_eq!(run.get(key * 2 + 1), None);
+        }
+
+        let counts = run.filter_counts();
+        assert_eq!(
+            counts.true_negatives + counts.false_positives,
+            lookups as u64
+        );
+        let observed = counts.false_positive_rate().unwrap();
+        let expected = run.filter_false_positive_rate();
+        assert!(
+            (observed - expected).abs() < expected * 0.2,
+            "observed {}, expected {}",
+            observed,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_persist_and_restore() {
+        let dir = temp_dir("run_persist_restore");
+        let path = dir.join("000001.run");
+        let data: Vec<(Key, Value)> = (0..100).map(|i| (i * 2, i * 20)).collect();
+        let mut run = Run::new(data.clone(), BloomParams::default());
+
+        run.persist(&path).unwrap();
+        assert_eq!(run.path(), Some(path.as_path()));
+        assert_eq!(run.id(), Some(1));
+        assert!(path.exists());
+        assert!(!crate::filename::temp_path(&path).exists());
+
+        let restored = Run::restore(&path).unwrap();
+        assert_eq!(restored.path(), Some(path.as_path()));
+        assert_eq!(restored.block_count(), run.block_count());
+        assert_eq!(restored.len(), data.len());
+        assert_eq!(restored.key_range(), run.key_range());
+
+        // Only the index stays in memory; blocks are read as they're needed
+        assert!(restored.blocks.is_empty());
+
+        // Every key survives the round trip, and the filter still answers for them
+        for (key, value) in &data {
+            assert_eq!(restored.get(*key), Some(*value));
+        }
+        assert_eq!(restored.get(1), None);
+        let expected: Vec<_> = data
+            .iter()
+            .copied()
+            .filter(|&(key, _)| (10..20).contains(&key))
+            .collect();
+        let range: Vec<_> = Run::cursor(Arc::new(restored), 10, 20).collect();
+        assert_eq!(range, expected);
+
+        // A freshly persisted run can let go of its blocks the same way
+        run.release_blocks();
+        assert!(run.blocks.is_empty());
+        assert_eq!(run.entries().collect::<Vec<_>>(), data);
+        assert!(run.persist(&path).is_err());
+    }
+
+    #[test]
+    fn test_restore_filter_kinds() {
+        let dir = temp_dir("run_restore_filter_kinds");
+        let data: Vec<(Key, Value)> = (0..500).map(|i| (i * 2, i)).collect();
+        for kind in [
+            FilterKind::RocksDbLocal,
+            FilterKind::SpeedDbDynamic,
+            FilterKind::BinaryFuse,
+        ] {
+            let path = dir.join(format!("{}.run", kind));
+            let bloom = BloomParams::default().with_kind(kind);
+            Run::new(data.clone(), bloom).persist(&path).unwrap();
+
+            // The file says which filter to rebuild
+            let restored = Run::restore(&path).unwrap();
+            assert_eq!(restored.filter_kind(), kind);
+            for &(key, value) in &data {
+                assert_eq!(restored.get(key), Some(value));
+            }
+            let passed = (0..1000).filter(|i| restored.filter.may_contain(&(i * 2 + 1)));
+            assert!(passed.count() < 100);
+        }
+    }
+
+    #[test]
+    fn test_multi_get() {
+        let data: Vec<(Key, Value)> = (0..1000).map(|i| (i * 2, i)).collect();
+        for kind in FilterKind::ALL {
+            let run = Run::new(data.clone(), BloomParams::default().with_kind(kind));
+            let keys: Vec<Key> = (0..100).map(|i| i * 37 % 2000).collect();
+            let mut lookups: Vec<Lookup> = keys
+                .iter()
+                .enumerate()
+                .map(|(index, &key)| Lookup::new(index, key))
+                .collect();
+            let mut results = vec![None; keys.len()];
+            run.multi_get(&mut lookups, &mut results);
+
+            // Found keys are dropped from the batch, the rest are left for
+            // older runs
+            let expected: Vec<_> = keys.iter().map(|&key| run.get(key)).collect();
+            assert_eq!(results, expected);
+            assert!(lookups.iter().all(|lookup| lookup.key % 2 == 1));
+            assert_eq!(lookups.len(), 50);
+        }
+    }
+
+    #[test]
+    fn test_range_filter() {
+        let dir = temp_dir("run_range_filter");
+        let path = dir.join("000001.run");
+        let data: Vec<(Key, Value)> = (0..1000).map(|i| (i * 100, i)).collect();
+        let bloom = BloomParams::default().with_range_filter(Some(RangeFilterParams::new(32.0)));
+        let mut run = Run::new(data.clone(), bloom);
+        run.persist(&path).unwrap();
+        let restored = Arc::new(Run::restore(&path).unwrap());
+
+        // Short ranges between keys skip the run; ranges holding keys don't
+        let gaps = (0..999).filter(|i| restored.may_contain_range(i * 100 + 1, i * 100 + 50));
+        assert!(gaps.count() < 100);
+        for i in 0..999 {
+            let cursor = Run::cursor(Arc::clone(&restored), i * 100 - 10, i * 100 + 10);
+            assert_eq!(cursor.collect::<Vec<_>>(), vec![(i * 100, i)]);
+        }
+
+        // Runs without one are always scanned
+        let plain = Run::new(data, BloomParams::default());
+        assert!(plain.range_filter.is_none());
+        assert!(plain.may_contain_range(1, 50));
+    }
+
+    #[test]
+    fn test_restore_empty_run() {
+        let dir = temp_dir("run_restore_empty");
+        let path = dir.join("empty.run");
+        let mut run = Run::new(Vec::new(), BloomParams::default());
+
+        run.persist(&path).unwrap();
+        let restored = Run::restore(&path).unwrap();
+        assert!(restored.blocks.is_empty());
+        assert_eq!(restored.get(1), None);
+    }
+
+    #[test]
+    fn test_restore_detects_corruption() {
+        let dir = temp_dir("run_restore_corrupt");
+        let path = dir.join("000001.run");
+        let data: Vec<(Key, Value)> = (0..100).map(|i| (i, i * 10)).collect();
+        Run::new(data, BloomParams::default())
+            .persist(&path)
+            .unwrap();
+        let mut bytes = fs::read(&path).unwrap();
+
+        // A flipped bit anywhere fails the file checksum
+        bytes[40] ^= 0x01;
+        fs::write(&path, &bytes).unwrap();
+        let err = Run::restore(&path).err().unwrap();
+        assert!(matches!(err, Error::Corruption(_)));
+        assert!(err.to_string().contains("000001.run"));
+
+        // With the file checksum patched up the block's own checksum still
+        // catches it
+        let checksum_at = bytes.len() - FOOTER_SIZE + FOOTER_CHECKSUM_OFFSET;
+        let checksum = xxh3_64(&bytes[..checksum_at]);
+        bytes[checksum_at..checksum_at + 8].copy_from_slice(&checksum.to_le_bytes());
+        fs::write(&path, &bytes).unwrap();
+        let err = Run::restore(&path).err().unwrap();
+        assert!(matches!(err, Error::Corruption(_)));
+        assert!(err.to_string().contains("Block checksum"));
+    }
+
+    #[test]
+    fn test_restore_rejects_invalid_files() {
+        let dir = temp_dir("run_restore_invalid");
+
+        // Too short to hold a footer
+        let short = dir.join("short.run");
+        fs::write(&short, [0u8; 8]).unwrap();
+        assert!(matches!(Run::restore(&short), Err(Error::Serialization(_))));
+
+        // Right size, wrong magic
+        let garbage = dir.join("garbage.run");
+        fs::write(&garbage, vec![0xAB; FOOTER_SIZE * 2]).unwrap();
+        assert!(matches!(
+            Run::restore(&garbage),
+            Err(Error::Serialization(_))
+        ));
+
+        // Missing file
+        assert!(matches!(
+            Run::restore(&dir.join("missing.run")),
+            Err(Error::Io(_))
+        ));
+    }
 }
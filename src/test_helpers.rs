@@ -1,5 +1,6 @@
 // in test_helpers.rs
 use std::net::TcpListener;
+use std::path::PathBuf;
 use std::process::{Child, Command};
 use std::sync::atomic::{AtomicU16, Ordering};
 use std::time::Instant;
@@ -89,6 +90,7 @@ pub async fn start_server() -> (Child, u16) {
                 if !server.try_wait().map(|s| s.is_none()).unwrap_or(false) {
                     println!("Server process exited prematurely");
                     let _ = server.kill();
+                    let _ = server.wait();
                     panic!("Server failed to start: process exited");
                 }
                 sleep(Duration::from_millis(100)).await;
@@ -98,6 +100,7 @@ pub async fn start_server() -> (Child, u16) {
 
     // If we get here, server failed to start
     let _ = server.kill();
+    let _ = server.wait();
     panic!(
         "Server failed to start listening on port {} after {:?}",
         port, timeout
@@ -119,7 +122,7 @@ pub async fn send_command(port: u16, command: &str) -> String {
 
                 loop {
                     match stream.read(&mut buffer).await {
-                        Ok(n) if n == 0 => break,
+                        Ok(0) => break,
                         Ok(n) => {
                             response.push_str(&String::from_utf8_lossy(&buffer[..n]));
                             if response.ends_with(END_OF_MESSAGE) {
@@ -169,3 +172,14 @@ pub async fn shutdown_server(mut server: Child, port: u16) {
     // Verify port is freed
     let _ = wait_for_port_available(port).await;
 }
+
+/// Creates an empty scratch directory under the system temp dir.
+///
+/// The name is combined with the process id so parallel test binaries never
+/// share a directory; any leftovers from a previous run are removed first.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("lsm_tree_{}_{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).expect("Failed to create temp dir");
+    dir
+}
@@ -22,6 +22,12 @@ pub enum Error {
     BufferFull,
     /// Error during compaction
     CompactionError,
+    /// Error reading or writing run files
+    Storage(String),
+    /// Data read back from disk failed its checksum
+    Corruption(String),
+    /// Tree parameters that can't make a workable tree
+    InvalidConfig(String),
 }
 
 impl std::fmt::Display for Error {
@@ -32,6 +38,9 @@ impl std::fmt::Display for Error {
             Error::InvalidRange { start, end } => write!(f, "Invalid range: {} > {}", start, end),
             Error::BufferFull => write!(f, "Buffer is full"),
             Error::CompactionError => write!(f, "Error during compaction"),
+            Error::Storage(msg) => write!(f, "Storage error: {}", msg),
+            Error::Corruption(msg) => write!(f, "Data corruption: {}", msg),
+            Error::InvalidConfig(msg) => write!(f, "Invalid configuration: {}", msg),
         }
     }
 }
@@ -44,6 +53,16 @@ impl From<std::io::Error> for Error {
     }
 }
 
+impl From<crate::run::Error> for Error {
+    fn from(err: crate::run::Error) -> Self {
+        match err {
+            crate::run::Error::Io(e) => Error::Io(e),
+            crate::run::Error::Corruption(msg) => Error::Corruption(msg),
+            other => Error::Storage(other.to_string()),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -66,25 +85,40 @@ mod tests {
         // Test BufferFull error
         let buffer_err = Error::BufferFull;
         assert_eq!(buffer_err.to_string(), "Buffer is full");
+
+        // Test Storage error
+        let storage_err = Error::Storage("bad footer".to_string());
+        assert_eq!(storage_err.to_string(), "Storage error: bad footer");
+
+        // Test InvalidConfig error
+        let config_err = Error::InvalidConfig("Fanout must be at least 2, not 1".to_string());
+        assert_eq!(
+            config_err.to_string(),
+            "Invalid configuration: Fanout must be at least 2, not 1"
+        );
     }
 
     #[test]
     fn test_error_conversion() {
         // Test conversion from io::Error
-        let io_err = io::Error::new(io::ErrorKind::Other, "test error");
+        let io_err = io::Error::other("test error");
         let converted: Error = io_err.into();
         matches!(converted, Error::Io(_));
+
+        // Test that corruption keeps its own variant
+        let run_err = crate::run::Error::Corruption("bad block".to_string());
+        let converted: Error = run_err.into();
+        assert_eq!(converted.to_string(), "Data corruption: bad block");
     }
 
     #[test]
     fn test_result_type() {
         // Test Result with success
         let success: Result<i32> = Ok(42);
-        assert_eq!(success.unwrap(), 42);
+        assert!(matches!(success, Ok(42)));
 
         // Test Result with error
         let failure: Result<i32> = Err(Error::BufferFull);
-        assert!(failure.is_err());
-        matches!(failure.unwrap_err(), Error::BufferFull);
+        assert!(matches!(failure, Err(Error::BufferFull)));
     }
 }
@@ -0,0 +1,141 @@
+use crate::level::Level;
+use crate::manifest::ManifestState;
+use crate::run::{Lookup, Run, RunCursor};
+use crate::types::{Key, Value};
+use std::sync::Arc;
+
+/// An immutable snapshot of the tree's levels.
+///
+/// Flushes and compactions never modify a version in place; they build a new
+/// one and swap it in. Readers holding an older version keep a consistent view
+/// of the level set for as long as they need it, and the runs it shares with
+/// newer versions are only freed once the last reader lets go.
+#[derive(Clone, Default)]
+pub struct Version {
+    levels: Vec<Level>,
+}
+
+impl Version {
+    pub fn new(levels: Vec<Level>) -> Self {
+        Self { levels }
+    }
+
+    /// Levels from newest to oldest data.
+    pub fn levels(&self) -> &[Level] {
+        &self.levels
+    }
+
+    /// Finds the newest value for `key`, tombstones included.
+    pub fn get(&self, key: Key) -> Option<Value> {
+        self.levels.iter().find_map(|level| level.get(key))
+    }
+
+    /// Finds the newest value for every key in `lookups`, tombstones
+    /// included, filling in `results` level by level.
+    pub fn multi_get(&self, mut lookups: Vec<Lookup>, results: &mut [Option<Value>]) {
+        for level in &self.levels {
+            if lookups.is_empty() {
+                return;
+            }
+            level.multi_get(&mut lookups, results);
+        }
+    }
+
+    /// Cursors over the entries of every run in `[start, end)`, newest run
+    /// first.
+    pub fn cursors(&self, start: Key, end: Key) -> impl Iterator<Item = RunCursor> + '_ {
+        self.levels
+            .iter()
+            .flat_map(move |level| level.cursors(start, end))
+    }
+
+    /// A copy of this version with `run` added as the newest run of the first
+    /// level.
+    pub fn with_flushed_run(&self, run: Arc<Run>) -> Self {
+        let mut levels = self.levels.clone();
+        if levels.is_empty() {
+            levels.push(Level::new());
+        }
+        levels[0].add_run(run);
+        Self { levels }
+    }
+
+    /// A copy of this version with `inputs` removed from `level` and `target`
+    /// and `outputs` placed in `target`.
+    pub fn with_compaction(
+        &self,
+        level: usize,
+        target: usize,
+        inputs: &[Arc<Run>],
+        outputs: Vec<Arc<Run>>,
+    ) -> Self {
+        let mut levels = self.levels.clone();
+        if levels.len() <= target {
+            levels.resize_with(target + 1, Level::new);
+        }
+        if level != target {
+            levels[level].replace(inputs, Vec::new());
+        }
+        levels[target].replace(inputs, outputs);
+        Self { levels }
+    }
+
+    /// The manifest's view of this version.
+    pub fn manifest_state(
+        &self,
+        log_number: u64,
+        next_file_id: u64,
+        cursors: Vec<Option<Key>>,
+    ) -> ManifestState {
+        ManifestState {
+            levels: self
+                .levels
+                .iter()
+                .map(|level| level.runs().iter().filter_map(|run| run.id()).collect())
+                .collect(),
+            log_number,
+            next_file_id,
+            cursors,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bloom::BloomParams;
+    use crate::merge::MergingIterator;
+
+    fn range(version: &Version, start: Key, end: Key) -> Vec<(Key, Value)> {
+        MergingIterator::new(version.cursors(start, end).collect()).collect()
+    }
+
+    #[test]
+    fn test_versions_are_immutable() {
+        let first = Version::default()
+            .with_flushed_run(Arc::new(Run::new(vec![(1, 10)], BloomParams::default())));
+        let second = first.with_flushed_run(Arc::new(Run::new(
+            vec![(1, 11), (2, 20)],
+            BloomParams::default(),
+        )));
+
+        // Older versions keep their own view of the levels
+        assert_eq!(first.get(1), Some(10));
+        assert_eq!(first.get(2), None);
+        assert_eq!(second.get(1), Some(11));
+
+        let inputs = second.levels()[0].runs().to_vec();
+        let merged = Arc::new(Run::new(vec![(1, 11), (2, 20)], BloomParams::default()));
+        let third = second.with_compaction(0, 1, &inputs, vec![merged]);
+        assert!(third.levels()[0].runs().is_empty());
+        assert_eq!(third.levels()[1].runs().len(), 1);
+        assert_eq!(range(&third, 0, 10), vec![(1, 11), (2, 20)]);
+        assert_eq!(second.levels()[0].runs().len(), 2);
+
+        // Newer levels shadow older ones
+        let fourth =
+            third.with_flushed_run(Arc::new(Run::new(vec![(2, 21)], BloomParams::default())));
+        assert_eq!(fourth.get(2), Some(21));
+        assert_eq!(range(&fourth, 0, 10), vec![(1, 11), (2, 21)]);
+    }
+}
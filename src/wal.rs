@@ -0,0 +1,286 @@
+use crate::types::{Key, Result, Value, TOMBSTONE};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use xxhash_rust::xxh3::xxh3_64;
+
+const OP_PUT: u8 = 1;
+const OP_DELETE: u8 = 2;
+/// checksum (u64) + op (u8) + key (i64) + value (i64)
+const RECORD_SIZE: usize = 8 + 1 + 8 + 8;
+
+/// How aggressively the write-ahead log is forced to stable storage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncMode {
+    /// fsync after every record; a write is durable once acknowledged
+    Always,
+    /// fsync from a background thread at most once per interval, so writes
+    /// acknowledged within the last interval may be lost on power failure
+    GroupCommit(Duration),
+    /// Never fsync; records survive a process crash but not an OS crash
+    None,
+}
+
+impl Default for SyncMode {
+    fn default() -> Self {
+        SyncMode::GroupCommit(Duration::from_millis(5))
+    }
+}
+
+/// A single logged mutation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalRecord {
+    Put(Key, Value),
+    Delete(Key),
+}
+
+impl WalRecord {
+    /// Builds the record for a memtable write, where deletes are tombstones.
+    pub fn from_write(key: Key, value: Value) -> Self {
+        if value == TOMBSTONE {
+            WalRecord::Delete(key)
+        } else {
+            WalRecord::Put(key, value)
+        }
+    }
+
+    /// The key and memtable value this record replays to.
+    pub fn into_write(self) -> (Key, Value) {
+        match self {
+            WalRecord::Put(key, value) => (key, value),
+            WalRecord::Delete(key) => (key, TOMBSTONE),
+        }
+    }
+
+    fn encode(&self) -> [u8; RECORD_SIZE] {
+        let (op, key, value) = match *self {
+            WalRecord::Put(key, value) => (OP_PUT, key, value),
+            WalRecord::Delete(key) => (OP_DELETE, key, 0),
+        };
+
+        let mut buf = [0u8; RECORD_SIZE];
+        buf[8] = op;
+        buf[9..17].copy_from_slice(&key.to_le_bytes());
+        buf[17..25].copy_from_slice(&value.to_le_bytes());
+        let checksum = xxh3_64(&buf[8..]);
+        buf[0..8].copy_from_slice(&checksum.to_le_bytes());
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        let checksum = u64::from_le_bytes(buf[0..8].try_into().unwrap());
+        if checksum != xxh3_64(&buf[8..RECORD_SIZE]) {
+            return None;
+        }
+
+        let key = Key::from_le_bytes(buf[9..17].try_into().unwrap());
+        let value = Value::from_le_bytes(buf[17..25].try_into().unwrap());
+        match buf[8] {
+            OP_PUT => Some(WalRecord::Put(key, value)),
+            OP_DELETE => Some(WalRecord::Delete(key)),
+            _ => None,
+        }
+    }
+}
+
+/// Append-only log of memtable writes.
+///
+/// Each record is fixed-size and carries its own checksum, so a record torn by
+/// a crash mid-write is detected during replay and everything after it is
+/// ignored. One log file backs one memtable; it is discarded once that
+/// memtable has been flushed to a run.
+pub struct Wal {
+    file: Arc<File>,
+    sync_mode: SyncMode,
+    /// Serializes appends so records are never interleaved
+    write_lock: Mutex<()>,
+    dirty: Arc<AtomicBool>,
+    shutdown: Arc<AtomicBool>,
+    syncer: Option<JoinHandle<()>>,
+}
+
+impl Wal {
+    /// Creates a new, empty log at `path`.
+    pub fn create(path: &Path, sync_mode: SyncMode) -> Result<Self> {
+        let file = Arc::new(
+            OpenOptions::new()
+                .create(true)
+                .truncate(true)
+                .write(true)
+                .open(path)?,
+        );
+        let dirty = Arc::new(AtomicBool::new(false));
+        let shutdown = Arc::new(AtomicBool::new(false));
+
+        let syncer = match sync_mode {
+            SyncMode::GroupCommit(interval) => {
+                let file = Arc::clone(&file);
+                let dirty = Arc::clone(&dirty);
+                let shutdown = Arc::clone(&shutdown);
+                Some(thread::spawn(move || {
+                    while !shutdown.load(Ordering::Acquire) {
+                        thread::park_timeout(interval);
+                        if dirty.swap(false, Ordering::AcqRel) {
+                            if let Err(e) = file.sync_data() {
+                                eprintln!("WAL group commit failed: {}", e);
+                            }
+                        }
+                    }
+                }))
+            }
+            SyncMode::Always | SyncMode::None => None,
+        };
+
+        Ok(Self {
+            file,
+            sync_mode,
+            write_lock: Mutex::new(()),
+            dirty,
+            shutdown,
+            syncer,
+        })
+    }
+
+    /// Appends `record`, syncing according to the log's [`SyncMode`].
+    pub fn append(&self, record: WalRecord) -> Result<()> {
+        self.append_batch(std::slice::from_ref(&record))
+    }
+
+    /// Appends `records` with a single write and at most one sync.
+    pub fn append_batch(&self, records: &[WalRecord]) -> Result<()> {
+        let bytes: Vec<u8> = records.iter().flat_map(|record| record.encode()).collect();
+        let _guard = self.write_lock.lock().unwrap();
+        (&*self.file).write_all(&bytes)?;
+
+        match self.sync_mode {
+            SyncMode::Always => self.file.sync_data()?,
+            SyncMode::GroupCommit(_) => self.dirty.store(true, Ordering::Release),
+            SyncMode::None => {}
+        }
+        Ok(())
+    }
+
+    /// Reads back every intact record in the log at `path`.
+    ///
+    /// Replay stops at the first truncated or corrupt record, which can only
+    /// be the tail of a write that was interrupted by a crash.
+    pub fn replay(path: &Path) -> Result<Vec<WalRecord>> {
+        let bytes = fs::read(path)?;
+        let mut records = Vec::with_capacity(bytes.len() / RECORD_SIZE);
+
+        for chunk in bytes.chunks_exact(RECORD_SIZE) {
+            match WalRecord::decode(chunk) {
+                Some(record) => records.push(record),
+                None => break,
+            }
+        }
+
+        Ok(records)
+    }
+}
+
+impl Drop for Wal {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::Release);
+        if let Some(syncer) = self.syncer.take() {
+            syncer.thread().unpark();
+            let _ = syncer.join();
+        }
+        if self.sync_mode != SyncMode::None {
+            let _ = self.file.sync_data();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::temp_dir;
+
+    #[test]
+    fn test_append_and_replay() {
+        let dir = temp_dir("wal_append_replay");
+        let path = dir.join("000001.wal");
+
+        for mode in [
+            SyncMode::Always,
+            SyncMode::GroupCommit(Duration::from_millis(1)),
+            SyncMode::None,
+        ] {
+            let wal = Wal::create(&path, mode).unwrap();
+            wal.append(WalRecord::Put(1, 100)).unwrap();
+            wal.append_batch(&[WalRecord::Put(-5, i64::MAX), WalRecord::Delete(1)])
+                .unwrap();
+            drop(wal);
+
+            let records = Wal::replay(&path).unwrap();
+            assert_eq!(
+                records,
+                vec![
+                    WalRecord::Put(1, 100),
+                    WalRecord::Put(-5, i64::MAX),
+                    WalRecord::Delete(1),
+                ],
+                "Replay mismatch for {:?}",
+                mode
+            );
+        }
+    }
+
+    #[test]
+    fn test_replay_stops_at_torn_record() {
+        let dir = temp_dir("wal_torn_record");
+        let path = dir.join("000001.wal");
+
+        let wal = Wal::create(&path, SyncMode::Always).unwrap();
+        wal.append(WalRecord::Put(1, 100)).unwrap();
+        wal.append(WalRecord::Put(2, 200)).unwrap();
+        drop(wal);
+
+        // Simulate a crash halfway through the third record
+        let mut bytes = fs::read(&path).unwrap();
+        bytes.extend_from_slice(&WalRecord::Put(3, 300).encode()[..RECORD_SIZE / 2]);
+        fs::write(&path, &bytes).unwrap();
+        assert_eq!(
+            Wal::replay(&path).unwrap(),
+            vec![WalRecord::Put(1, 100), WalRecord::Put(2, 200)]
+        );
+
+        // A flipped bit invalidates that record and everything after it
+        bytes[RECORD_SIZE + 12] ^= 0x01;
+        fs::write(&path, &bytes).unwrap();
+        assert_eq!(Wal::replay(&path).unwrap(), vec![WalRecord::Put(1, 100)]);
+    }
+
+    #[test]
+    fn test_record_write_mapping() {
+        assert_eq!(WalRecord::from_write(1, 10), WalRecord::Put(1, 10));
+        assert_eq!(WalRecord::from_write(1, TOMBSTONE), WalRecord::Delete(1));
+        assert_eq!(WalRecord::Put(1, 10).into_write(), (1, 10));
+        assert_eq!(WalRecord::Delete(1).into_write(), (1, TOMBSTONE));
+    }
+
+    #[test]
+    fn test_group_commit_syncs_in_background() {
+        let dir = temp_dir("wal_group_commit");
+        let path = dir.join("000001.wal");
+
+        let wal = Wal::create(&path, SyncMode::GroupCommit(Duration::from_millis(1))).unwrap();
+        wal.append(WalRecord::Put(7, 70)).unwrap();
+        assert!(wal.dirty.load(Ordering::Acquire));
+
+        // The background thread clears the dirty flag once it has synced
+        for _ in 0..100 {
+            if !wal.dirty.load(Ordering::Acquire) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(!wal.dirty.load(Ordering::Acquire));
+        assert_eq!(Wal::replay(&path).unwrap(), vec![WalRecord::Put(7, 70)]);
+    }
+}
@@ -1,7 +1,7 @@
 use once_cell::sync::Lazy;
 use std::io::Write;
 use std::net::TcpListener;
-use std::process::{Child, Command};
+use std::process::{Child, Command, Stdio};
 use std::sync::Mutex;
 use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
@@ -41,6 +41,10 @@ fn build_server() {
 }
 
 async fn start_server() -> TestServer {
+    start_server_with_args(&[]).await
+}
+
+async fn start_server_with_args(args: &[&str]) -> TestServer {
     // Clear any existing servers first
     let _ = Command::new("pkill")
         .arg("-f")
@@ -49,7 +53,7 @@ async fn start_server() -> TestServer {
 
     tokio::time::sleep(Duration::from_millis(500)).await;
 
-    let mut port = NEXT_PORT.lock().unwrap().clone();
+    let mut port = *NEXT_PORT.lock().unwrap();
 
     // Find an available port
     while TcpListener::bind(format!("127.0.0.1:{}", port)).is_err() {
@@ -60,14 +64,16 @@ async fn start_server() -> TestServer {
     println!("Starting test server on port {}", port);
 
     let mut process = Command::new("./target/release/server")  // Use direct path to binary
-        .env("SERVER_PORT", port.to_string())
+        .arg("-p")
+        .arg(port.to_string())
+        .args(args)
         .spawn()
         .expect("Failed to start server process");
 
     // Wait for server to be ready
     let mut attempts = 50;  // 5 seconds total
     while attempts > 0 {
-        if let Ok(_) = TcpStream::connect(format!("127.0.0.1:{}", port)).await {
+        if TcpStream::connect(format!("127.0.0.1:{}", port)).await.is_ok() {
             tokio::time::sleep(Duration::from_millis(200)).await;
             println!("Successfully connected to test server on port {}", port);
             return TestServer { port, process };
@@ -89,6 +95,7 @@ async fn start_server() -> TestServer {
 
     // If we get here, server failed to start
     let _ = process.kill();
+    let _ = process.wait();
     panic!("Server failed to start after 50 attempts");
 }
 
@@ -130,6 +137,188 @@ async fn test_put_and_get() {
     assert_eq!(response, "", "Get of non-existent value should return empty string");
 }
 
+#[tokio::test]
+async fn test_multi_get() {
+    build_server();  // Ensure server is built before test
+    let server = start_server().await;
+
+    for cmd in ["p 10 42\n", "p 12 43\n", "p 14 44\n"] {
+        let response = send_command(server.port, cmd).await;
+        assert_eq!(response, "OK", "Put failed");
+    }
+
+    // Every key is answered in the order asked for, absent ones without a value
+    let response = send_command(server.port, "g 14 11 10 14\n").await;
+    assert_eq!(response, "14:44 11: 10:42 14:44", "Multi-get returned wrong values");
+
+    let response = send_command(server.port, "g 11 13\n").await;
+    assert_eq!(response, "11: 13:", "Multi-get of non-existent values should name each key");
+}
+
+#[tokio::test]
+async fn test_load() {
+    build_server();  // Ensure server is built before test
+    let server = start_server().await;
+
+    // Packed 32-bit key/value pairs, as the workload generator writes them
+    let path = std::env::temp_dir().join(format!("lsm_load_{}.bin", server.port));
+    let bytes: Vec<u8> = [(5i32, 50i32), (6, 60), (7, 70)]
+        .iter()
+        .flat_map(|&(key, value)| [key.to_le_bytes(), value.to_le_bytes()])
+        .flatten()
+        .collect();
+    std::fs::write(&path, bytes).unwrap();
+
+    let response = send_command(server.port, &format!("l \"{}\"\n", path.display())).await;
+    assert_eq!(response, "Loaded 3 pairs", "Load failed");
+
+    let response = send_command(server.port, "g 6\n").await;
+    assert_eq!(response, "60", "Get of loaded value failed");
+
+    let response = send_command(server.port, "l \"/nonexistent/data.bin\"\n").await;
+    assert!(response.starts_with("Error"), "Load of missing file should fail");
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn test_print_stats() {
+    build_server();  // Ensure server is built before test
+    let server = start_server().await;
+
+    for command in ["p 3 30\n", "p 1 10\n", "p 2 20\n", "d 2\n"] {
+        let response = send_command(server.port, command).await;
+        assert_eq!(response, "OK", "Write failed");
+    }
+
+    let response = send_command(server.port, "s\n").await;
+    assert_eq!(response, "Logical Pairs: 2\nLVL0: 2\n1:10:L0 3:30:L0", "PrintStats failed");
+
+    let response = send_command(server.port, "s summary\n").await;
+    assert_eq!(response, "Logical Pairs: 2\nLVL0: 2", "PrintStats summary failed");
+}
+
+#[tokio::test]
+async fn test_server_options() {
+    build_server();  // Ensure server is built before test
+
+    let help = Command::new("./target/release/server").arg("-h").output().unwrap();
+    assert!(help.status.success(), "Help should exit cleanly");
+    assert!(String::from_utf8_lossy(&help.stdout).starts_with("Usage"));
+
+    for args in [&["-f", "1"][..], &["-n"], &["-l", "sideways"], &["-x"]] {
+        let output = Command::new("./target/release/server").args(args).output().unwrap();
+        assert!(!output.status.success(), "{:?} should be rejected", args);
+        assert!(String::from_utf8_lossy(&output.stderr).contains("Usage"));
+    }
+
+    // A small buffer and tiering, so the load below goes through compactions
+    let server = start_server_with_args(&["-n", "1", "-f", "3", "-l", "tiered"]).await;
+    let path = std::env::temp_dir().join(format!("lsm_options_{}.bin", server.port));
+    let bytes: Vec<u8> = (0..1000i32)
+        .flat_map(|key| [key.to_le_bytes(), (key * 2).to_le_bytes()])
+        .flatten()
+        .collect();
+    std::fs::write(&path, bytes).unwrap();
+    let response = send_command(server.port, &format!("l \"{}\"\n", path.display())).await;
+    assert_eq!(response, "Loaded 1000 pairs", "Load failed");
+    let _ = std::fs::remove_file(&path);
+
+    let response = send_command(server.port, "g 999\n").await;
+    assert_eq!(response, "1998", "Get failed");
+}
+
+#[tokio::test]
+async fn test_client_replay() {
+    build_server();  // Ensure server is built before test
+    let status = Command::new("cargo")
+        .args(["build", "--release", "--bin", "client"])
+        .status()
+        .expect("Failed to build client");
+    assert!(status.success(), "Client build failed");
+    let server = start_server().await;
+
+    let path = std::env::temp_dir().join(format!("lsm_workload_{}.txt", server.port));
+    std::fs::write(&path, "p 1 10\np 2 20\n\ng 2\nd 1\nr 0 5\n").unwrap();
+    let output = Command::new("./target/release/client")
+        .args(["-p", &server.port.to_string(), "-f", path.to_str().unwrap()])
+        .output()
+        .expect("Failed to run client");
+    let _ = std::fs::remove_file(&path);
+    assert!(output.status.success(), "Replay failed");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("g 2 -> 20 ["), "Missing timed response in {}", stdout);
+    assert!(stdout.contains("r 0 5 -> 2:20 ["), "Missing timed response in {}", stdout);
+    assert!(stdout.contains("Sent 5 commands in"), "Missing summary in {}", stdout);
+    assert!(stdout.contains("Latency: mean"), "Missing summary in {}", stdout);
+
+    // Quiet mode drops the prompt and banner but still prints results
+    let mut client = Command::new("./target/release/client")
+        .args(["-q", "-p", &server.port.to_string()])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Failed to run client");
+    client.stdin.take().unwrap().write_all(b"g 2\n").unwrap();
+    let output = client.wait_with_output().expect("Client did not exit");
+    assert!(output.status.success(), "Quiet client failed");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "20\n");
+}
+
+#[tokio::test]
+async fn test_admin_console() {
+    build_server();  // Ensure server is built before test
+    let server = start_server().await;
+    let port = server.port;
+    // Only one server may hold the port; this test drives its own
+    drop(server);
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let mut process = Command::new("./target/release/server")
+        .args(["-p", &port.to_string(), "-n", "1"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Failed to start server process");
+    let mut attempts = 50;
+    while TcpStream::connect(format!("127.0.0.1:{}", port)).await.is_err() {
+        attempts -= 1;
+        assert!(attempts > 0, "Server failed to start");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    let path = std::env::temp_dir().join(format!("lsm_console_{}.bin", port));
+    let bytes: Vec<u8> = (0..300i32)
+        .flat_map(|key| [key.to_le_bytes(), key.to_le_bytes()])
+        .flatten()
+        .collect();
+    std::fs::write(&path, bytes).unwrap();
+    let response = send_command(port, &format!("l \"{}\"\n", path.display())).await;
+    assert_eq!(response, "Loaded 300 pairs", "Load failed");
+    let _ = std::fs::remove_file(&path);
+
+    // A client that stays connected doesn't hold up the shutdown
+    let idle = TcpStream::connect(format!("127.0.0.1:{}", port)).await.unwrap();
+    let mut stdin = process.stdin.take().unwrap();
+    stdin.write_all(b"help\nflush\nbloom\ncompact\nstats\nquit\n").unwrap();
+    let output = process.wait_with_output().expect("Server did not shut down");
+    drop(idle);
+    assert!(output.status.success(), "Server failed on quit");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    for expected in [
+        "Server commands:",
+        "Flushed the memtables",
+        "Level 1: ",
+        "  Run (in memory, bloom): ",
+        "Compacted the tree",
+        "Logical Pairs: 300",
+        "Server shut down.",
+    ] {
+        assert!(stdout.contains(expected), "Missing {:?} in {}", expected, stdout);
+    }
+}
+
 #[tokio::test]
 async fn test_range_query() {
     build_server();  // Ensure server is built before test